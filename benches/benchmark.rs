@@ -0,0 +1,133 @@
+//! Time each stage of the compiler on its own with criterion, with the
+//! Fibonacci example scaled up.  Run with `cargo bench`.
+//!
+//! What each stage allocates is counted as well, exactly, by a
+//! `#[global_allocator]` wrapping the system one, and printed before
+//! its timings.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tinyc_in_rust::codegen::compile;
+use tinyc_in_rust::desugar::desugar;
+use tinyc_in_rust::lexer::Lexer;
+use tinyc_in_rust::optimizer::{algebraic_simplify, eliminate_dead_code, fold_constants};
+use tinyc_in_rust::parser::{parse, NodeArena};
+use tinyc_in_rust::vm::VM;
+
+/// The system allocator, counting the allocations, the bytes
+/// allocated, and the bytes live now and at most
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Count `size` more bytes as live
+fn grow(size: usize) {
+    let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        grow(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        grow(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The Fibonacci example, computing the 30th number
+const FIB: &str = "{ m=n=1;k=30; while (0 < k) { t = m; m = n; n = t + n; k = k - 1; }}";
+
+/// Time `f` with criterion as the benchmark `name`, having printed
+/// what one run of it allocates: the allocations, the bytes, the most
+/// bytes live at once on top of those live before, and the bytes
+/// still live after, ie. leaked
+fn bench(c: &mut Criterion, name: &str, mut f: impl FnMut()) {
+    f();
+    let (allocations, bytes, live) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        BYTES.load(Ordering::Relaxed),
+        LIVE.load(Ordering::Relaxed),
+    );
+    PEAK.store(live, Ordering::Relaxed);
+    f();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let bytes = BYTES.load(Ordering::Relaxed) - bytes;
+    let peak = PEAK.load(Ordering::Relaxed) - live;
+    let leaked = LIVE.load(Ordering::Relaxed).saturating_sub(live);
+    println!("{name}: {allocations} allocations, {bytes} bytes, {peak} peak, {leaked} leaked");
+    c.bench_function(name, |b| b.iter(&mut f));
+}
+
+fn stages(c: &mut Criterion) {
+    // A large program for the front end: the example many times over
+    let src = format!("{{ {} }}", FIB.repeat(1000));
+
+    bench(c, "lex_only", || {
+        for token in Lexer::new(black_box(&src)).into_tokens() {
+            black_box(token.unwrap());
+        }
+    });
+
+    // The tree is allocated in an arena of its own for each run, as
+    // it would be for each program compiled
+    bench(c, "parse_only", || {
+        let arena = NodeArena::new();
+        black_box(parse(&arena, black_box(&src)).unwrap());
+    });
+
+    // A thousand references to two variables, whose names are only
+    // copied into the arena once each
+    let names = "{ total = 0; i = 0; ".to_string() + &"total = total + i; ".repeat(333) + "}";
+    bench(c, "parse_names", || {
+        let arena = NodeArena::new();
+        black_box(parse(&arena, black_box(&names)).unwrap());
+    });
+
+    let arena = NodeArena::new();
+    let ast = parse(&arena, &src).unwrap();
+    bench(c, "optimize_only", || {
+        let arena = NodeArena::new();
+        let ast = desugar(&arena, black_box(ast));
+        let ast = fold_constants(&arena, ast);
+        let ast = algebraic_simplify(&arena, ast);
+        black_box(eliminate_dead_code(&arena, ast));
+    });
+
+    bench(c, "codegen_only", || {
+        black_box(compile(black_box(ast)));
+    });
+
+    let mut vm = VM::new();
+    vm.load(compile(parse(&arena, FIB).unwrap()));
+    bench(c, "vm_only", || {
+        vm.reset();
+        vm.resume().unwrap();
+        assert_eq!(vm.get_global('m'), Some(1_346_269));
+    });
+}
+
+criterion_group!(benches, stages);
+criterion_main!(benches);
@@ -0,0 +1,451 @@
+//! Analyses of the program: the control-flow graph of the compiled
+//! code and how deep its stack gets, and in the tree, the variables
+//! that may be read before they are assigned and those whose last
+//! assignment is never read.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::codegen::Insn;
+use crate::lexer::Span;
+use crate::parser::{AstVisitor, Node, NodeKind};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// A stretch of code that is only entered at its `start` and only
+/// left after its last instruction, at `end`.  The `successors` are
+/// the indices of the blocks control can go to from there.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+}
+
+/// The control-flow graph: the basic blocks of the code, in the order
+/// of their addresses, the first being the entry
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cfg(pub Vec<BasicBlock>);
+
+/// Split `code` into basic blocks and connect them.  A block ends
+/// with a jump, a `Halt`, or a `Ret`, or just before the target of a
+/// jump.  A `Call` is taken to return to the instruction after it, so
+/// it doesn't end a block, but the function called starts one, and a
+/// `Ret` has no successors.
+#[must_use]
+pub fn build_cfg(code: &[Insn]) -> Cfg {
+    // The addresses of the instructions, and those that start a block
+    let mut addresses = Vec::new();
+    let mut leaders = BTreeSet::from([0]);
+    let mut pc = 0;
+    while pc < code.len() {
+        addresses.push(pc);
+        let next = pc + code[pc].size();
+        match (&code[pc], code.get(pc + 1)) {
+            (Insn::Jmp | Insn::Jz | Insn::Jnz, Some(Insn::Address(target))) => {
+                leaders.insert(*target);
+                leaders.insert(next);
+            }
+            (Insn::Call, Some(Insn::Address(target))) => {
+                leaders.insert(*target);
+            }
+            (Insn::Halt | Insn::Ret, _) => {
+                leaders.insert(next);
+            }
+            _ => {}
+        }
+        pc = next;
+    }
+    leaders.retain(|&pc| pc < code.len());
+
+    let starts: Vec<usize> = leaders.into_iter().collect();
+    let block_of = |pc: usize| starts.partition_point(|&start| start <= pc) - 1;
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let limit = starts.get(i + 1).copied().unwrap_or(code.len());
+        let end = addresses[addresses.partition_point(|&pc| pc < limit) - 1];
+        let next = end + code[end].size();
+        let mut successors = Vec::new();
+        let target = match code.get(end + 1) {
+            Some(Insn::Address(target)) if *target < code.len() => Some(block_of(*target)),
+            _ => None,
+        };
+        match code[end] {
+            Insn::Jmp => successors.extend(target),
+            Insn::Jz | Insn::Jnz => {
+                if next < code.len() {
+                    successors.push(block_of(next));
+                }
+                successors.extend(target.filter(|t| !successors.contains(t)));
+            }
+            Insn::Halt | Insn::Ret => {}
+            _ if next < code.len() => successors.push(block_of(next)),
+            _ => {}
+        }
+        blocks.push(BasicBlock {
+            start,
+            end,
+            successors,
+        });
+    }
+    Cfg(blocks)
+}
+
+impl Cfg {
+    /// The indices of the blocks that control can come to `block` from
+    #[must_use]
+    pub fn predecessors(&self, block: usize) -> Vec<usize> {
+        (0..self.0.len())
+            .filter(|&b| self.0[b].successors.contains(&block))
+            .collect()
+    }
+
+    /// Can control get from the block `from` to the block `to`?  A
+    /// block can always reach itself.
+    #[must_use]
+    pub fn is_reachable(&self, from: usize, to: usize) -> bool {
+        let mut seen = vec![false; self.0.len()];
+        let mut work = vec![from];
+        while let Some(b) = work.pop() {
+            if b == to {
+                return true;
+            }
+            if !std::mem::replace(&mut seen[b], true) {
+                work.extend(&self.0[b].successors);
+            }
+        }
+        false
+    }
+}
+
+/// The most values that can be on the stack at once when `code` is
+/// run, found by following the height of the stack through the
+/// control-flow graph until it settles.  A function called starts
+/// with the height at the deepest of its calls.  Returns `None` if
+/// there's no limit, as when a function calls itself with more on
+/// the stack than it was called with.
+#[must_use]
+pub fn max_stack_depth(code: &[Insn]) -> Option<usize> {
+    let cfg = build_cfg(code);
+    let block_at = |pc: usize| cfg.0.iter().position(|b| b.start == pc);
+    // The height on entering each block, once known
+    let mut entry = vec![None; cfg.0.len()];
+    let mut work = Vec::new();
+    let mut deepest = 0;
+    // Enter `block` with `height`, to be looked at again if that's
+    // higher than before.  Without recursion, the height can't be more
+    // than one per instruction.
+    let enter =
+        |entry: &mut Vec<Option<usize>>, block: usize, height: usize, work: &mut Vec<usize>| {
+            if entry[block].is_some_and(|h| h >= height) {
+                return Some(());
+            }
+            if height > code.len() {
+                return None;
+            }
+            entry[block] = Some(height);
+            work.push(block);
+            Some(())
+        };
+    if !code.is_empty() {
+        enter(&mut entry, 0, 0, &mut work)?;
+    }
+    while let Some(b) = work.pop() {
+        let block = &cfg.0[b];
+        let mut height = entry[b].unwrap_or(0);
+        let mut pc = block.start;
+        while pc <= block.end {
+            match code[pc] {
+                Insn::Fetch | Insn::FetchLocal | Insn::Push | Insn::Dup | Insn::Rand => {
+                    height += 1;
+                }
+                Insn::Enter | Insn::Leave => {
+                    if let Some(&Insn::Address(n)) = code.get(pc + 1) {
+                        if code[pc] == Insn::Enter {
+                            height += n;
+                        } else {
+                            height = height.saturating_sub(n);
+                        }
+                    }
+                }
+                Insn::Call => {
+                    if let Some(Insn::Address(target)) = code.get(pc + 1) {
+                        if let Some(callee) = block_at(*target) {
+                            enter(&mut entry, callee, height, &mut work)?;
+                        }
+                    }
+                }
+                Insn::Store
+                | Insn::StoreLocal
+                | Insn::Swap
+                | Insn::Nop
+                | Insn::Read
+                | Insn::BitNot
+                | Insn::Neg
+                | Insn::Abs
+                | Insn::Jmp
+                | Insn::Halt
+                | Insn::Ret
+                | Insn::Integer(_)
+                | Insn::Address(_) => {}
+                _ => height = height.saturating_sub(1),
+            }
+            deepest = deepest.max(height);
+            pc += code[pc].size();
+        }
+        for &s in &block.successors {
+            enter(&mut entry, s, height, &mut work)?;
+        }
+    }
+    Some(deepest)
+}
+
+/// The reads of variables that may happen before the variable is
+/// assigned, with their spans, in the order they appear.  A variable
+/// counts as assigned only if it is on every path to the read, so
+/// an assignment in just one branch of an `if`, in the body of a loop,
+/// or in a function called doesn't count.  A function may be called
+/// before anything is assigned, so its body starts with nothing
+/// assigned.
+#[must_use]
+pub fn uninitialized_reads(ast: &Node) -> Vec<(String, Span)> {
+    let mut reads = Vec::new();
+    assigned_after(ast, HashSet::new(), &mut reads);
+    reads
+}
+
+/// The variables assigned on every path through `node`, given those
+/// `assigned` before it.  The reads of others are added to `reads`.
+#[allow(clippy::too_many_lines)]
+fn assigned_after(
+    node: &Node,
+    mut assigned: HashSet<String>,
+    reads: &mut Vec<(String, Span)>,
+) -> HashSet<String> {
+    // What is assigned on both ways through a choice
+    let both = |a: HashSet<String>, b: HashSet<String>| a.intersection(&b).cloned().collect();
+    match &node.kind {
+        NodeKind::Var(name) => {
+            if !assigned.contains(*name) {
+                reads.push(((*name).to_string(), node.span));
+            }
+            assigned
+        }
+        NodeKind::Add(a, b)
+        | NodeKind::Sub(a, b)
+        | NodeKind::Mul(a, b)
+        | NodeKind::Div(a, b)
+        | NodeKind::Mod(a, b)
+        | NodeKind::Shl(a, b)
+        | NodeKind::Shr(a, b)
+        | NodeKind::Lt(a, b)
+        | NodeKind::Gt(a, b)
+        | NodeKind::Le(a, b)
+        | NodeKind::Ge(a, b)
+        | NodeKind::Eq(a, b)
+        | NodeKind::Ne(a, b)
+        | NodeKind::BitAnd(a, b)
+        | NodeKind::BitOr(a, b)
+        | NodeKind::BitXor(a, b)
+        | NodeKind::Min(a, b)
+        | NodeKind::Max(a, b)
+        | NodeKind::Seq(a, b) => {
+            let assigned = assigned_after(a, assigned, reads);
+            assigned_after(b, assigned, reads)
+        }
+        NodeKind::BitNot(x)
+        | NodeKind::Neg(x)
+        | NodeKind::Abs(x)
+        | NodeKind::Expr(x)
+        | NodeKind::Print(x)
+        | NodeKind::Prog(x) => assigned_after(x, assigned, reads),
+        // The right operand may not be evaluated
+        NodeKind::And(a, b) | NodeKind::Or(a, b) => {
+            let assigned = assigned_after(a, assigned, reads);
+            assigned_after(b, assigned.clone(), reads);
+            assigned
+        }
+        NodeKind::Ternary(test, then, else_)
+        | NodeKind::If2Expr(test, then, else_)
+        | NodeKind::If2(test, then, else_) => {
+            let assigned = assigned_after(test, assigned, reads);
+            let then = assigned_after(then, assigned.clone(), reads);
+            both(then, assigned_after(else_, assigned, reads))
+        }
+        NodeKind::Set(var, val) => {
+            let mut assigned = assigned_after(val, assigned, reads);
+            if let NodeKind::Var(name) = &var.kind {
+                assigned.insert((*name).to_string());
+            }
+            assigned
+        }
+        NodeKind::AddAssign(var, val) => {
+            let assigned = assigned_after(var, assigned, reads);
+            assigned_after(val, assigned, reads)
+        }
+        NodeKind::If1(test, body) | NodeKind::While(test, body) => {
+            let assigned = assigned_after(test, assigned, reads);
+            assigned_after(body, assigned.clone(), reads);
+            assigned
+        }
+        NodeKind::Do(body, test) => {
+            let assigned = assigned_after(body, assigned, reads);
+            assigned_after(test, assigned, reads)
+        }
+        NodeKind::For(init, test, step, body) => {
+            let assigned = assigned_after(init, assigned, reads);
+            let assigned = assigned_after(test, assigned, reads);
+            let in_body = assigned_after(body, assigned.clone(), reads);
+            assigned_after(step, in_body, reads);
+            assigned
+        }
+        NodeKind::Switch(value, cases, default) => {
+            let assigned = assigned_after(value, assigned, reads);
+            let mut after = match default {
+                Some(body) => assigned_after(body, assigned.clone(), reads),
+                None => assigned.clone(),
+            };
+            for (_, body) in cases {
+                after = both(after, assigned_after(body, assigned.clone(), reads));
+            }
+            after
+        }
+        NodeKind::Read(name) | NodeKind::Decl(name) => {
+            assigned.insert((*name).to_string());
+            assigned
+        }
+        NodeKind::Block(stmts) => stmts
+            .iter()
+            .fold(assigned, |assigned, s| assigned_after(s, assigned, reads)),
+        NodeKind::FnDef(_, body) => {
+            assigned_after(body, HashSet::new(), reads);
+            assigned
+        }
+        NodeKind::Cst(_)
+        | NodeKind::Call(_)
+        | NodeKind::Rand
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Empty
+        | NodeKind::Ref(_) => assigned,
+    }
+}
+
+/// The variables whose last assignment is never read after it, in
+/// the order they are first assigned.  A read anywhere in a loop comes
+/// after every assignment in it, since the loop may go round again, and
+/// a read in a function after every assignment at all, since it may be
+/// called at any time.  The values of these variables are still printed
+/// at the end of the run, but an assignment that nothing reads is often
+/// a mistake.
+#[must_use]
+pub fn unused_writes(ast: &Node) -> Vec<String> {
+    let mut uses = Uses::default();
+    uses.visit_node(ast);
+    uses.order
+        .into_iter()
+        .filter(|name| uses.reads.get(name) < uses.writes.get(name))
+        .collect()
+}
+
+/// When each variable was last read, and last written, counting each
+/// read and write as a tick of `clock`, and the variables in the order
+/// of the first write
+#[derive(Default)]
+struct Uses {
+    clock: usize,
+    reads: HashMap<String, usize>,
+    writes: HashMap<String, usize>,
+    order: Vec<String>,
+    in_fn: bool,
+}
+
+impl Uses {
+    /// Note `name` is read now, or in a function, at the very end
+    fn read(&mut self, name: &str) {
+        self.clock += 1;
+        let now = if self.in_fn { usize::MAX } else { self.clock };
+        let last = self.reads.entry(name.to_string()).or_default();
+        *last = now.max(*last);
+    }
+
+    /// Note `name` is written now, or in a function, at the very start,
+    /// unless it's `_`, which is never read
+    fn write(&mut self, name: &str) {
+        if name == "_" {
+            return;
+        }
+        self.clock += 1;
+        let now = if self.in_fn { 0 } else { self.clock };
+        if let Some(last) = self.writes.get_mut(name) {
+            *last = now.max(*last);
+        } else {
+            self.writes.insert(name.to_string(), now);
+            self.order.push(name.to_string());
+        }
+    }
+
+    /// Visit a loop with `visit`, and move the reads in it to its end
+    fn looped(&mut self, visit: impl FnOnce(&mut Self)) {
+        let start = self.clock;
+        visit(self);
+        let end = self.clock;
+        for read in self.reads.values_mut() {
+            if *read > start {
+                *read = end.max(*read);
+            }
+        }
+    }
+}
+
+impl AstVisitor for Uses {
+    fn visit_var(&mut self, name: &str) {
+        self.read(name);
+    }
+
+    fn visit_set(&mut self, var: &Node, val: &Node) {
+        self.visit_node(val);
+        if let NodeKind::Var(name) = &var.kind {
+            self.write(name);
+        }
+    }
+
+    fn visit_add_assign(&mut self, var: &Node, val: &Node) {
+        self.visit_node(val);
+        self.visit_node(var);
+        if let NodeKind::Var(name) = &var.kind {
+            self.write(name);
+        }
+    }
+
+    fn visit_while(&mut self, test: &Node, body: &Node) {
+        self.looped(|uses| {
+            uses.visit_node(test);
+            uses.visit_node(body);
+        });
+    }
+
+    fn visit_do(&mut self, body: &Node, test: &Node) {
+        self.looped(|uses| {
+            uses.visit_node(body);
+            uses.visit_node(test);
+        });
+    }
+
+    fn visit_for(&mut self, init: &Node, test: &Node, step: &Node, body: &Node) {
+        self.visit_node(init);
+        self.looped(|uses| {
+            uses.visit_node(test);
+            uses.visit_node(step);
+            uses.visit_node(body);
+        });
+    }
+
+    fn visit_read(&mut self, name: &str) {
+        self.write(name);
+    }
+
+    fn visit_fn_def(&mut self, _name: &str, body: &Node) {
+        self.in_fn = true;
+        self.visit_node(body);
+        self.in_fn = false;
+    }
+}
@@ -0,0 +1,85 @@
+//! A bump allocator for the nodes of the tree.
+//!
+//! The values are put in chunks, each twice the size of the one
+//! before, and are only freed all at once, when the `Arena` is
+//! dropped.  Allocating is then mostly bumping the length of the last
+//! chunk, and the references handed out live as long as the arena,
+//! so the nodes can refer to each other with plain `&` references.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use std::cell::RefCell;
+
+/// The number of values the first chunk has room for
+const FIRST_CHUNK: usize = 64;
+
+/// The chunk the values are allocated in, and those filled before it.
+/// A chunk is never grown past the capacity it was made with, so the
+/// values in it never move.
+pub struct Arena<T> {
+    current: RefCell<Vec<T>>,
+    full: RefCell<Vec<Vec<T>>>,
+}
+
+impl<T> Arena<T> {
+    #[must_use]
+    pub fn new() -> Self {
+        Arena {
+            current: RefCell::new(Vec::with_capacity(FIRST_CHUNK)),
+            full: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Move `value` into the arena, and return a reference to it that
+    /// lives as long as the arena does
+    ///
+    /// ```
+    /// use tinyc_in_rust::arena::Arena;
+    /// let arena = Arena::new();
+    /// let a = arena.alloc(1);
+    /// let b = arena.alloc(2);
+    /// assert_eq!(*a + *b, 3);
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc(&self, value: T) -> &mut T {
+        let mut current = self.current.borrow_mut();
+        if current.len() == current.capacity() {
+            let next = Vec::with_capacity(current.capacity() * 2);
+            let full = std::mem::replace(&mut *current, next);
+            self.full.borrow_mut().push(full);
+        }
+        current.push(value);
+        // SAFETY: the push was within the capacity, so the values
+        // already in the chunk didn't move, and the new one won't
+        // either, the chunk being moved to `full` only by its handle.
+        // It is only freed with the arena, and this is the only
+        // reference to it handed out.
+        unsafe { &mut *current.as_mut_ptr().add(current.len() - 1) }
+    }
+
+    /// The number of values allocated
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let full: usize = self.full.borrow().iter().map(Vec::len).sum();
+        full + self.current.borrow().len()
+    }
+
+    /// Has nothing been allocated yet?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of chunks, which grows with the logarithm of the
+    /// number of values
+    #[must_use]
+    pub fn chunks(&self) -> usize {
+        self.full.borrow().len() + 1
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,112 @@
+//! An interactive debugger for Tiny-C programs
+//!
+//! The program is read from the file named on the command line and
+//! the debugger commands from standard input:
+//!
+//! - `n`: execute the next instruction
+//! - `g`: go, running until the program halts or hits a breakpoint
+//! - `p a`: print the variable `a`
+//! - `b 5`: set a breakpoint at the instruction at address 5
+//! - `w a`: watch the variable `a`, stopping when it changes
+//! - `q`: quit
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use std::io::{BufRead, Write};
+use tinyc_in_rust::codegen::{compile_with_symbols, disassemble, SourceMap};
+use tinyc_in_rust::parser::{parse, NodeArena};
+use tinyc_in_rust::vm::{StepResult, VmError, VM};
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: debugger <file>");
+        std::process::exit(1);
+    };
+    let src = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        std::process::exit(1);
+    });
+    let mut vm = VM::new();
+    let (code, source_map) = match parse(&NodeArena::new(), &src) {
+        Ok(ast) => compile_with_symbols(ast, &mut vm.symbols),
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    };
+
+    print!("{}", disassemble(&code));
+    vm.load(code);
+
+    prompt();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.unwrap();
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("n"), None) => {
+                // Stepping onto a breakpoint is no reason to stop twice
+                let mut result = vm.step();
+                if matches!(result, Err(VmError::Breakpoint { .. })) {
+                    result = vm.step();
+                }
+                report(result, &vm, &source_map, &src);
+            }
+            (Some("g"), None) => {
+                let mut result = vm.step();
+                while result == Ok(StepResult::Running) {
+                    result = vm.step();
+                }
+                report(result, &vm, &source_map, &src);
+            }
+            (Some("p"), Some(name)) => match vm.symbols.lookup(name) {
+                Some(i) => println!("{name} = {}", vm.globals[i]),
+                None => println!("no variable {name}"),
+            },
+            (Some("w"), Some(name)) => match vm.symbols.lookup(name) {
+                Some(i) => vm.watch_global(i),
+                None => println!("no variable {name}"),
+            },
+            (Some("b"), Some(pc)) => match pc.parse::<usize>() {
+                Ok(pc) => {
+                    if let Err(e) = vm.set_breakpoint(pc) {
+                        println!("{e}");
+                    }
+                }
+                Err(_) => println!("bad address {pc}"),
+            },
+            (Some("q"), None) => break,
+            (None, _) => {}
+            _ => println!("commands: n, g, p <var>, b <address>, w <var>, q"),
+        }
+        prompt();
+    }
+}
+
+fn prompt() {
+    print!("(tdb) ");
+    std::io::stdout().flush().unwrap();
+}
+
+/// Show where the VM stopped after a step, and the line of source
+/// code the instruction there came from
+fn report(result: Result<StepResult, VmError>, vm: &VM, source_map: &SourceMap, src: &str) {
+    match result {
+        Ok(StepResult::Running) => println!("pc = {}, stack = {:?}", vm.pc(), vm.stack()),
+        Ok(StepResult::Halted) => println!("halted at {}", vm.pc()),
+        Err(VmError::Breakpoint { pc }) => {
+            println!("breakpoint at {pc}, stack = {:?}", vm.stack());
+        }
+        Err(VmError::Watchpoint { idx, old, new }) => {
+            let name = vm.symbols.name(idx).unwrap_or("?");
+            println!("{name} changed from {old} to {new}, pc = {}", vm.pc());
+        }
+        Err(e) => println!("runtime error: {e}"),
+    }
+    if let Some(span) = source_map.lookup(vm.pc()) {
+        let line = span.start.line();
+        println!(
+            "{line:4}| {}",
+            src.lines().nth(line - 1).unwrap_or_default()
+        );
+    }
+}
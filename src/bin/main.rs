@@ -8,27 +8,56 @@
 
 // This is a compiler for the Tiny-C language.  Tiny-C is a
 // considerably stripped down version of C and it is meant as a
-// pedagogical tool for learning about compilers.  The integer global
-// variables "a" to "z" are predefined and initialized to zero, and it
-// is not possible to declare new variables.  The compiler reads the
-// program from standard input and prints out the value of the
-// variables that are not zero.  The grammar of Tiny-C in EBNF is:
+// pedagogical tool for learning about compilers.  The variables are
+// integers, initialized to zero.  They are globals, created by naming
+// them, unless a block declares them with `int x;`, making a local
+// that shadows any global of the same name until the end of the
+// block.  The compiler reads the program from standard input and
+// prints out the value of the globals that are not zero.  The grammar
+// of Tiny-C in EBNF is:
 //
-//  <program> ::= <statement>
+//  <program> ::= { <function> } <statement> { <function> }
+//  <function> ::= "fn" <name> "(" ")" <statement>
 //  <statement> ::= "if" <paren_expr> <statement> |
 //                  "if" <paren_expr> <statement> "else" <statement> |
 //                  "while" <paren_expr> <statement> |
 //                  "do" <statement> "while" <paren_expr> ";" |
-//                  "{" { <statement> } "}" |
+//                  "for" "(" <expr> ";" <expr> ";" <expr> ")" <statement> |
+//                  "switch" <paren_expr> "{" { "case" <int> ":" <statement> }
+//                      [ "default" ":" <statement> ] "}" |
+//                  "print" <expr> ";" |
+//                  "read" <id> ";" |
+//                  "break" ";" |
+//                  "continue" ";" |
+//                  "{" { <statement> | <declaration> } "}" |
 //                  <expr> ";" |
 //                  ";"
+//  <declaration> ::= "int" <id> ";"
 //  <paren_expr> ::= "(" <expr> ")"
-//  <expr> ::= <test> | <id> "=" <expr>
-//  <test> ::= <sum> | <sum> "<" <sum>
-//  <sum> ::= <term> | <sum> "+" <term> | <sum> "-" <term>
-//  <term> ::= <id> | <int> | <paren_expr>
-//  <id> ::= "a" | "b" | "c" | "d" | ... | "z"
-//  <int> ::= <an_unsigned_decimal_integer>
+//  <expr> ::= <ternary> | <id> "=" <expr> | <id> "+=" <expr> |
+//             "_" "=" <expr>
+//  <ternary> ::= <or_test> | <or_test> "?" <expr> ":" <ternary>
+//  <or_test> ::= <and_test> | <or_test> "||" <and_test>
+//  <and_test> ::= <bit_or> | <and_test> "&&" <bit_or>
+//  <bit_or> ::= <bit_xor> | <bit_or> "|" <bit_xor>
+//  <bit_xor> ::= <bit_and> | <bit_xor> "^" <bit_and>
+//  <bit_and> ::= <test> | <bit_and> "&" <test>
+//  <test> ::= <shift> | <shift> <relop> <shift>
+//  <relop> ::= "<" | ">" | "<=" | ">=" | "==" | "!="
+//  <shift> ::= <sum> | <shift> "<<" <sum> | <shift> ">>" <sum>
+//  <sum> ::= <product> | <sum> "+" <product> | <sum> "-" <product>
+//  <product> ::= <term> | <product> "*" <term> |
+//                <product> "/" <term> | <product> "%" <term>
+//  <term> ::= "-" <term> | "~" <term> | "++" <id> | "--" <id> | <id> | <int> |
+//             <paren_expr> | "(" "if" <paren_expr> <term> <term> ")" |
+//             <name> "(" ")" | "abs" <paren_expr> |
+//             "min" "(" <expr> "," <expr> ")" | "max" "(" <expr> "," <expr> ")" |
+//             "rand" "(" ")"
+//  <id> ::= <name>
+//  <name> ::= <a_word_of_lowercase_letters_and_underscores>
+//  <int> ::= <an_unsigned_decimal_integer> |
+//            "0x" <an_unsigned_hexadecimal_integer> |
+//            "0" <an_unsigned_octal_integer>
 //
 //
 // Here are a few invocations of the compiler:
@@ -54,14 +83,181 @@
 // highlight the structure of the compiler.
 //
 
-use tinyc_in_rust::{compile_and_run, vm};
+use std::path::Path;
+use tinyc_in_rust::sexp::to_sexp;
+use tinyc_in_rust::{
+    compile_and_run_returning, compile_to_asm, globals_json, interpret_returning, parser,
+    read_source, vm, CompileError,
+};
 
+const USAGE: &str = "\
+usage: main [options] [file ...]
+
+Compile and run each file as a program, or without files each line of
+standard input, and print the variables that aren't zero.
+
+options:
+  --trace        trace the instructions as they are executed
+  --output-asm   print the compiled code rather than run it
+  --dump-ast     print the parsed program as an S-expression rather
+                 than run it
+  --interpret    run the parsed program with the tree-walking
+                 interpreter rather than compile it
+  --json         print the variables that aren't zero as a JSON object
+  --json-all     print all the variables as a JSON object
+  --diff         print the variables the program changed, with their
+                 values before
+  --no-warn-unused
+                 don't warn about variables assigned but never read
+  --max-stack <n>
+                 stop with an error if the stack grows deeper than n
+                 (default 1024)
+  --persist      keep the variables from one program to the next
+  --no-persist   start each program with all variables zero (default)
+  --help         print this help
+  --version      print the version
+";
+
+/// Each file, or line of input if there are no files, is a program of
+/// its own, starting with all the globals zero, unless `--persist` is
+/// given to keep them from one to the next
 fn main() {
     use std::io::BufRead;
 
     let mut vm = vm::VM::new();
+    let mut persist = false;
+    let mut output_asm = false;
+    let mut dump_ast = false;
+    let mut interpret = false;
+    let mut json = None;
+    let mut diff = false;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" => {
+                print!("{USAGE}");
+                return;
+            }
+            "--version" => {
+                println!("tinyc-in-rust {}", env!("CARGO_PKG_VERSION"));
+                return;
+            }
+            "--trace" => vm.trace_on(),
+            "--output-asm" => output_asm = true,
+            "--dump-ast" => dump_ast = true,
+            "--interpret" => interpret = true,
+            "--json" => json = Some(false),
+            "--json-all" => json = Some(true),
+            "--diff" => diff = true,
+            "--no-warn-unused" => vm.disable_unused_warnings(),
+            "--max-stack" => {
+                let Some(n) = args.next().and_then(|n| n.parse().ok()) else {
+                    eprint!("--max-stack needs a number\n{USAGE}");
+                    std::process::exit(2);
+                };
+                vm.set_stack_limit(n);
+            }
+            "--persist" => persist = true,
+            "--no-persist" => persist = false,
+            option if option.starts_with("--") => {
+                eprint!("unknown option {option}\n{USAGE}");
+                std::process::exit(2);
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let programs: Box<dyn Iterator<Item = String>> = if files.is_empty() {
+        Box::new(std::io::stdin().lock().lines().map(|line| {
+            line.unwrap_or_else(|e| {
+                eprintln!("cannot read standard input: {e}");
+                std::process::exit(1);
+            })
+        }))
+    } else {
+        Box::new(files.into_iter().map(|path| {
+            read_source(Path::new(&path)).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            })
+        }))
+    };
+    for src in programs {
+        if output_asm {
+            asm(&src);
+            continue;
+        }
+        if dump_ast {
+            sexp(&src);
+            continue;
+        }
+        if !persist {
+            vm.reset_globals();
+        }
+        run(&mut vm, &src, interpret, json, diff);
+    }
+}
+
+/// Compile `src` and print the code
+fn asm(src: &str) {
+    match compile_to_asm(src) {
+        Ok(listing) => print!("{listing}"),
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse `src` and print the tree as an S-expression
+fn sexp(src: &str) {
+    match parser::parse(&parser::NodeArena::new(), src) {
+        Ok(ast) => println!("{}", to_sexp(ast)),
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
+}
 
-    for line in std::io::stdin().lock().lines() {
-        compile_and_run(&mut vm, &line.unwrap());
+/// Compile and run `src`, or `interpret` it, and print the globals
+/// that aren't zero, or as JSON if `json` is given, all of them if it's
+/// true, or those that changed if `diff`
+fn run(vm: &mut vm::VM, src: &str, interpret: bool, json: Option<bool>, diff: bool) {
+    let before = vm.snapshot();
+    let result = if interpret {
+        interpret_returning(vm, src)
+    } else {
+        compile_and_run_returning(vm, src)
+    };
+    match result {
+        Ok(_) if json.is_some() => println!("{}", globals_json(vm, json == Some(true))),
+        Ok(_) if diff => {
+            for (slot, old, new) in vm::diff_globals(before.globals(), &vm.globals) {
+                let name = vm.symbols.name(slot).unwrap_or("?");
+                println!("{name} = {new} (was {old})");
+            }
+        }
+        Ok(_) => vm.print_nonzero_globals(&mut std::io::stdout()).unwrap(),
+        // Report all the syntax errors, not just the first
+        Err(CompileError::Parse(e)) => {
+            let arena = parser::NodeArena::new();
+            let errors = parser::ErrorRecoveryParser::new(&arena, src).map_or_else(
+                |e| vec![e],
+                |parser| parser.program().err().unwrap_or_default(),
+            );
+            if errors.is_empty() {
+                eprintln!("{e:#}");
+            }
+            for e in errors {
+                eprintln!("{e:#}");
+            }
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
     }
 }
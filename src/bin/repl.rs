@@ -0,0 +1,49 @@
+//! An interactive Tiny-C session
+//!
+//! Each line is compiled and run as a program of its own, but the
+//! globals are kept from one line to the next, so `a=5;` and then
+//! `b=a+1;` sets `b` to 6.  After each line the globals that aren't
+//! zero are printed.  The commands are:
+//!
+//! - `:reset`: set all the globals back to zero
+//! - `:vars`: print all the globals, zero or not
+//! - `:quit`: quit, as does the end of the input
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use std::io::{BufRead, Write};
+use tinyc_in_rust::compile_and_run;
+use tinyc_in_rust::vm::VM;
+
+fn main() {
+    let mut vm = VM::new();
+
+    prompt();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.unwrap();
+        match line.trim() {
+            ":quit" => break,
+            ":reset" => vm.reset_globals(),
+            ":vars" => {
+                for (name, slot) in vm.symbols.names() {
+                    println!("{name} = {}", vm.globals[slot]);
+                }
+            }
+            "" => {}
+            command if command.starts_with(':') => {
+                println!("commands: :reset, :vars, :quit");
+            }
+            src => {
+                if let Err(e) = compile_and_run(&mut vm, src) {
+                    println!("{e:#}");
+                }
+            }
+        }
+        prompt();
+    }
+}
+
+fn prompt() {
+    print!("tinyc> ");
+    std::io::stdout().flush().unwrap();
+}
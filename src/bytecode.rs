@@ -0,0 +1,238 @@
+//! A compact binary format for the compiled code, so it can be saved
+//! and loaded without compiling again.
+//!
+//! The format is a magic header, `TINYC\0`, a version byte, and then
+//! one opcode byte per code slot.  The `Integer` opcode is followed by
+//! its value as a signed LEB128 number and the `Address` opcode by its
+//! value as 3 bytes, big-endian.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::codegen::Insn;
+
+const MAGIC: &[u8] = b"TINYC\0";
+const VERSION: u8 = 1;
+
+/// The ways decoding bytecode can fail.  The `offset` is the position
+/// in the bytes where the problem was found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes don't start with the magic header
+    BadMagic,
+
+    /// The bytecode is of a version we don't know
+    BadVersion(u8),
+
+    /// A byte that isn't an opcode where an opcode was expected
+    BadOpcode { offset: usize, byte: u8 },
+
+    /// An `Integer` that doesn't fit an `isize`
+    Overflow { offset: usize },
+
+    /// The bytes ended in the middle of an instruction
+    Truncated,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not Tiny-C bytecode"),
+            DecodeError::BadVersion(v) => write!(f, "unknown bytecode version {v}"),
+            DecodeError::BadOpcode { offset, byte } => {
+                write!(f, "{offset}: bad opcode {byte:#04x}")
+            }
+            DecodeError::Overflow { offset } => write!(f, "{offset}: integer too large"),
+            DecodeError::Truncated => write!(f, "bytecode ends unexpectedly"),
+        }
+    }
+}
+
+/// Encode `code` in the bytecode format
+///
+/// # Panics
+/// Panics if an `Address` doesn't fit in 3 bytes
+#[must_use]
+pub fn encode(code: &[Insn]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    for insn in code {
+        let opcode = match insn {
+            Insn::Fetch => 0,
+            Insn::Store => 1,
+            Insn::Push => 2,
+            Insn::Pop => 3,
+            Insn::Add => 4,
+            Insn::Sub => 5,
+            Insn::Mul => 6,
+            Insn::Div => 7,
+            Insn::Mod => 8,
+            Insn::Lt => 9,
+            Insn::Gt => 10,
+            Insn::Le => 11,
+            Insn::Ge => 12,
+            Insn::Eq => 13,
+            Insn::Ne => 14,
+            Insn::Jz => 15,
+            Insn::Jnz => 16,
+            Insn::Jmp => 17,
+            Insn::Halt => 18,
+            Insn::Dup => 21,
+            Insn::BitAnd => 22,
+            Insn::BitOr => 23,
+            Insn::BitXor => 24,
+            Insn::BitNot => 25,
+            Insn::Shl => 26,
+            Insn::Shr => 27,
+            Insn::Print => 28,
+            Insn::PrintVar => 29,
+            Insn::Read => 30,
+            Insn::Call => 31,
+            Insn::Ret => 32,
+            Insn::Swap => 33,
+            Insn::Nop => 34,
+            Insn::FetchLocal => 35,
+            Insn::StoreLocal => 36,
+            Insn::Enter => 37,
+            Insn::Leave => 38,
+            Insn::Abs => 39,
+            Insn::Min => 40,
+            Insn::Max => 41,
+            Insn::Rand => 42,
+            Insn::Neg => 43,
+            Insn::Integer(n) => {
+                bytes.push(19);
+                write_leb128(&mut bytes, *n);
+                continue;
+            }
+            Insn::Address(a) => {
+                assert!(*a < 1 << 24, "address {a} doesn't fit in 3 bytes");
+                bytes.push(20);
+                bytes.extend_from_slice(&a.to_be_bytes()[std::mem::size_of::<usize>() - 3..]);
+                continue;
+            }
+        };
+        bytes.push(opcode);
+    }
+    bytes
+}
+
+/// Decode bytecode made by `encode`
+///
+/// # Errors
+/// Returns a `DecodeError` if `bytes` isn't valid bytecode
+pub fn decode(bytes: &[u8]) -> Result<Vec<Insn>, DecodeError> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        return Err(DecodeError::BadMagic);
+    };
+    match rest.first() {
+        Some(&VERSION) => {}
+        Some(&v) => return Err(DecodeError::BadVersion(v)),
+        None => return Err(DecodeError::Truncated),
+    }
+
+    let mut code = Vec::new();
+    let mut offset = MAGIC.len() + 1;
+    while let Some(&byte) = bytes.get(offset) {
+        let start = offset;
+        offset += 1;
+        code.push(match byte {
+            0 => Insn::Fetch,
+            1 => Insn::Store,
+            2 => Insn::Push,
+            3 => Insn::Pop,
+            4 => Insn::Add,
+            5 => Insn::Sub,
+            6 => Insn::Mul,
+            7 => Insn::Div,
+            8 => Insn::Mod,
+            9 => Insn::Lt,
+            10 => Insn::Gt,
+            11 => Insn::Le,
+            12 => Insn::Ge,
+            13 => Insn::Eq,
+            14 => Insn::Ne,
+            15 => Insn::Jz,
+            16 => Insn::Jnz,
+            17 => Insn::Jmp,
+            18 => Insn::Halt,
+            19 => Insn::Integer(read_leb128(bytes, &mut offset, start)?),
+            20 => {
+                let Some(&[a, b, c]) = bytes.get(offset..offset + 3) else {
+                    return Err(DecodeError::Truncated);
+                };
+                offset += 3;
+                Insn::Address(usize::from(a) << 16 | usize::from(b) << 8 | usize::from(c))
+            }
+            21 => Insn::Dup,
+            22 => Insn::BitAnd,
+            23 => Insn::BitOr,
+            24 => Insn::BitXor,
+            25 => Insn::BitNot,
+            26 => Insn::Shl,
+            27 => Insn::Shr,
+            28 => Insn::Print,
+            29 => Insn::PrintVar,
+            30 => Insn::Read,
+            31 => Insn::Call,
+            32 => Insn::Ret,
+            33 => Insn::Swap,
+            34 => Insn::Nop,
+            35 => Insn::FetchLocal,
+            36 => Insn::StoreLocal,
+            37 => Insn::Enter,
+            38 => Insn::Leave,
+            39 => Insn::Abs,
+            40 => Insn::Min,
+            41 => Insn::Max,
+            42 => Insn::Rand,
+            43 => Insn::Neg,
+            _ => {
+                return Err(DecodeError::BadOpcode {
+                    offset: start,
+                    byte,
+                })
+            }
+        });
+    }
+    Ok(code)
+}
+
+/// Append `n` as a signed LEB128 number: 7 bits at a time, least
+/// significant first, with the top bit set on all but the last byte
+fn write_leb128(bytes: &mut Vec<u8>, mut n: isize) {
+    loop {
+        let byte = u8::try_from(n & 0x7f).unwrap();
+        n >>= 7;
+        // Done when the rest is all sign, and the sign bit (0x40) of
+        // this byte agrees
+        if n == 0 && byte & 0x40 == 0 || n == -1 && byte & 0x40 != 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Read a signed LEB128 number at `offset`, moving past it.  The
+/// `start` is the offset of the instruction, for error reporting.
+fn read_leb128(bytes: &[u8], offset: &mut usize, start: usize) -> Result<isize, DecodeError> {
+    let mut n: isize = 0;
+    let mut shift = 0;
+    loop {
+        let Some(&byte) = bytes.get(*offset) else {
+            return Err(DecodeError::Truncated);
+        };
+        *offset += 1;
+        if shift >= isize::BITS {
+            return Err(DecodeError::Overflow { offset: start });
+        }
+        n |= isize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < isize::BITS && byte & 0x40 != 0 {
+                n |= -1 << shift;
+            }
+            return Ok(n);
+        }
+    }
+}
@@ -2,7 +2,11 @@
 
 #![warn(clippy::all, clippy::pedantic)]
 
-use crate::parser::Node;
+use crate::lexer::{SourcePosition, Span};
+use crate::parser::{walk_node, AstVisitor, BNode, Node, NodeKind};
+use crate::symbols::SymbolTable;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 
 /// `Insn` models the instructions of our virtual machine.
 ///
@@ -15,45 +19,782 @@ use crate::parser::Node;
 ///
 /// The targets of `Jmp`, `Jnz`, and `Jz` are absolute addresses.
 /// Conventionally they would be relative addresses.
-#[derive(Debug)]
+///
+/// A function's local variables live on the stack, in a frame that
+/// `Enter n` makes by pushing `n` zeros and `Leave n` drops.
+/// `FetchLocal` and `StoreLocal` address them from the start of the
+/// innermost frame.
+#[derive(Debug, PartialEq, Eq)]
 pub enum Insn {
     Fetch,
     Store,
+    FetchLocal,
+    StoreLocal,
+    Enter,
+    Leave,
     Push,
     Pop,
+    Dup,
+    Swap,
+    Nop,
     Add,
     Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
     Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Neg,
+    Abs,
+    Min,
+    Max,
+    Rand,
     Jz,
     Jnz,
     Jmp,
+    Call,
+    Ret,
+    Print,
+    PrintVar,
+    Read,
     Halt,
     Integer(isize),
     Address(usize),
 }
 
+impl std::fmt::Display for Insn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Insn::Integer(n) => write!(f, "{n}"),
+            Insn::Address(a) => write!(f, "{a}"),
+            _ => write!(f, "{}", format!("{self:?}").to_uppercase()),
+        }
+    }
+}
+
+impl Insn {
+    /// The number of code slots the instruction takes, including its
+    /// operand
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Insn::Fetch
+            | Insn::Store
+            | Insn::FetchLocal
+            | Insn::StoreLocal
+            | Insn::Enter
+            | Insn::Leave
+            | Insn::PrintVar
+            | Insn::Read
+            | Insn::Push
+            | Insn::Jmp
+            | Insn::Jz
+            | Insn::Jnz
+            | Insn::Call => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Produce a numbered listing of `code`, one instruction with its
+/// operand (if any) per line.  Variables are shown by name and jump
+/// targets as `-> address`.
+#[must_use]
+pub fn disassemble(code: &[Insn]) -> String {
+    let mut listing = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let insn = &code[pc];
+        let line = match (insn, code.get(pc + 1)) {
+            (Insn::Fetch | Insn::Store | Insn::PrintVar | Insn::Read, Some(Insn::Address(a)))
+                if *a < 26 =>
+            {
+                format!(
+                    "{insn} {}",
+                    (b'a'..=b'z').map(char::from).nth(*a).unwrap_or('?')
+                )
+            }
+            (Insn::Jmp | Insn::Jz | Insn::Jnz | Insn::Call, Some(target @ Insn::Address(_))) => {
+                format!("{insn} -> {target}")
+            }
+            (
+                Insn::Fetch
+                | Insn::Store
+                | Insn::FetchLocal
+                | Insn::StoreLocal
+                | Insn::Enter
+                | Insn::Leave
+                | Insn::PrintVar
+                | Insn::Read
+                | Insn::Push,
+                Some(operand),
+            ) => {
+                format!("{insn} {operand}")
+            }
+            _ => format!("{insn}"),
+        };
+        writeln!(listing, "{pc:3}: {line}").unwrap();
+        pc += insn.size();
+    }
+    listing
+}
+
+/// An error in assembly source, with the line where it was found
+#[derive(Debug)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "input:{}:{}", self.line, self.message)
+    }
+}
+
+/// Assemble a text listing into code.  Each line has an optional
+/// label, like `L0:`, and an optional instruction, like `PUSH 42`,
+/// `FETCH a`, or `JZ L0`.  A `;` starts a comment.
+///
+/// Jump targets can also be given as absolute addresses, like
+/// `JZ -> 22`, and a label that is a number must be the address of
+/// the line, so the output of `disassemble` assembles back into the
+/// same code.
+///
+/// ```
+/// use tinyc_in_rust::codegen::assemble;
+/// let code = assemble("L0: FETCH i\n JZ L1\n JMP L0\nL1: HALT").unwrap();
+/// assert_eq!(code.len(), 7);
+/// ```
+///
+/// # Errors
+/// Returns an `AssembleError` for unknown instructions, bad or
+/// missing operands, and undefined or duplicate labels
+#[allow(clippy::too_many_lines)]
+pub fn assemble(src: &str) -> Result<Vec<Insn>, AssembleError> {
+    let mut code = Vec::new();
+    let mut labels = HashMap::new();
+
+    // The operand slots to fill with the address of a label
+    let mut holes = Vec::new();
+
+    for (line, text) in (1..).zip(src.lines()) {
+        let error = |message: String| Err(AssembleError { line, message });
+        let mut text = text.split(';').next().unwrap_or_default().trim();
+
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if let Ok(pc) = label.parse::<usize>() {
+                if pc != code.len() {
+                    return error(format!("address {pc} should be {}", code.len()));
+                }
+            } else if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                || label.is_empty()
+            {
+                return error(format!("bad label `{label}'"));
+            } else if labels.insert(label, code.len()).is_some() {
+                return error(format!("label `{label}' defined twice"));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operand) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operand = operand.trim();
+        let insn = match mnemonic.to_uppercase().as_str() {
+            "FETCH" => Insn::Fetch,
+            "STORE" => Insn::Store,
+            "FETCHLOCAL" => Insn::FetchLocal,
+            "STORELOCAL" => Insn::StoreLocal,
+            "ENTER" => Insn::Enter,
+            "LEAVE" => Insn::Leave,
+            "PUSH" => Insn::Push,
+            "POP" => Insn::Pop,
+            "DUP" => Insn::Dup,
+            "SWAP" => Insn::Swap,
+            "NOP" => Insn::Nop,
+            "ADD" => Insn::Add,
+            "SUB" => Insn::Sub,
+            "MUL" => Insn::Mul,
+            "DIV" => Insn::Div,
+            "MOD" => Insn::Mod,
+            "SHL" => Insn::Shl,
+            "SHR" => Insn::Shr,
+            "LT" => Insn::Lt,
+            "GT" => Insn::Gt,
+            "LE" => Insn::Le,
+            "GE" => Insn::Ge,
+            "EQ" => Insn::Eq,
+            "NE" => Insn::Ne,
+            "BITAND" => Insn::BitAnd,
+            "BITOR" => Insn::BitOr,
+            "BITXOR" => Insn::BitXor,
+            "BITNOT" => Insn::BitNot,
+            "NEG" => Insn::Neg,
+            "ABS" => Insn::Abs,
+            "MIN" => Insn::Min,
+            "MAX" => Insn::Max,
+            "RAND" => Insn::Rand,
+            "JZ" => Insn::Jz,
+            "JNZ" => Insn::Jnz,
+            "JMP" => Insn::Jmp,
+            "CALL" => Insn::Call,
+            "RET" => Insn::Ret,
+            "PRINT" => Insn::Print,
+            "PRINTVAR" => Insn::PrintVar,
+            "READ" => Insn::Read,
+            "HALT" => Insn::Halt,
+            _ => return error(format!("unknown instruction `{mnemonic}'")),
+        };
+
+        let operand = match (&insn, operand) {
+            (
+                Insn::Fetch
+                | Insn::Store
+                | Insn::FetchLocal
+                | Insn::StoreLocal
+                | Insn::Enter
+                | Insn::Leave
+                | Insn::PrintVar
+                | Insn::Read
+                | Insn::Push
+                | Insn::Jz
+                | Insn::Jnz
+                | Insn::Jmp
+                | Insn::Call,
+                "",
+            ) => {
+                return error(format!("`{mnemonic}' needs an operand"));
+            }
+            (_, "") => None,
+            (Insn::Fetch | Insn::Store | Insn::PrintVar | Insn::Read, var) => {
+                match (var.as_bytes(), var.parse()) {
+                    ([c @ b'a'..=b'z'], _) => Some(Insn::Address(usize::from(c - b'a'))),
+                    (_, Ok(a)) => Some(Insn::Address(a)),
+                    _ => return error(format!("bad variable `{var}'")),
+                }
+            }
+            (Insn::FetchLocal | Insn::StoreLocal | Insn::Enter | Insn::Leave, n) => {
+                match n.parse() {
+                    Ok(n) => Some(Insn::Address(n)),
+                    Err(_) => return error(format!("bad count `{n}'")),
+                }
+            }
+            (Insn::Push, n) => match n.parse() {
+                Ok(n) => Some(Insn::Integer(n)),
+                Err(_) => return error(format!("bad integer `{n}'")),
+            },
+            (Insn::Jz | Insn::Jnz | Insn::Jmp | Insn::Call, target) => {
+                let target = target.strip_prefix("->").unwrap_or(target).trim();
+                if let Ok(a) = target.parse() {
+                    Some(Insn::Address(a))
+                } else {
+                    holes.push((code.len() + 1, target, line));
+                    Some(Insn::Address(0))
+                }
+            }
+            _ => return error(format!("`{mnemonic}' takes no operand")),
+        };
+        code.push(insn);
+        code.extend(operand);
+    }
+
+    for (hole, label, line) in holes {
+        let Some(&target) = labels.get(label) else {
+            return Err(AssembleError {
+                line,
+                message: format!("undefined label `{label}'"),
+            });
+        };
+        code[hole] = Insn::Address(target);
+    }
+    Ok(code)
+}
+
+/// Improve `code` by rewriting short instruction sequences into
+/// shorter ones with the same effect:
+///
+/// - `Push 0; Add` and `Push 0; Sub` are removed,
+/// - `Push 0; Push N; Sub` only needs to `Push N; Neg`, and likewise
+///   for `Fetch` and `FetchLocal` in place of `Push`,
+/// - `Fetch A; Fetch A` only needs to `Fetch A; Dup`,
+/// - a `Jmp` to the next instruction is removed,
+/// - a `Jz` or `Jnz` to the next instruction only needs to `Pop`.
+///
+/// The slots removed are first overwritten with `Nop`s, which `compact`
+/// then removes, adjusting the jump targets to the new addresses.  A
+/// sequence that is the target of a jump in its middle is left alone.
+/// As each round may expose new opportunities, we repeat until
+/// nothing changes.
+#[must_use]
+pub fn peephole(mut code: Vec<Insn>) -> Vec<Insn> {
+    loop {
+        let mut targets = vec![false; code.len() + 1];
+        let mut pc = 0;
+        while pc < code.len() {
+            if let (Insn::Jmp | Insn::Jz | Insn::Jnz | Insn::Call, Some(Insn::Address(t))) =
+                (&code[pc], code.get(pc + 1))
+            {
+                if *t < targets.len() {
+                    targets[*t] = true;
+                }
+            }
+            pc += code[pc].size();
+        }
+
+        let mut changed = false;
+        let mut pc = 0;
+        while pc < code.len() {
+            match (&code[pc], code.get(pc + 1), code.get(pc + 2)) {
+                (Insn::Push, Some(Insn::Integer(0)), Some(Insn::Add | Insn::Sub))
+                    if !targets[pc + 1] && !targets[pc + 2] =>
+                {
+                    code[pc..pc + 3].fill_with(|| Insn::Nop);
+                    changed = true;
+                    pc += 3;
+                    continue;
+                }
+                (
+                    Insn::Push,
+                    Some(Insn::Integer(0)),
+                    Some(Insn::Push | Insn::Fetch | Insn::FetchLocal),
+                ) if code.get(pc + 4) == Some(&Insn::Sub)
+                    && !targets[pc + 1]
+                    && !targets[pc + 2]
+                    && !targets[pc + 4] =>
+                {
+                    code[pc..pc + 2].fill_with(|| Insn::Nop);
+                    code[pc + 4] = Insn::Neg;
+                    changed = true;
+                    pc += 5;
+                    continue;
+                }
+                (Insn::Fetch, Some(&Insn::Address(a)), Some(Insn::Fetch))
+                    if code.get(pc + 3) == Some(&Insn::Address(a)) && !targets[pc + 2] =>
+                {
+                    code[pc + 2] = Insn::Dup;
+                    code[pc + 3] = Insn::Nop;
+                    changed = true;
+                }
+                (Insn::Jmp, Some(Insn::Address(t)), _) if *t == pc + 2 => {
+                    code[pc..pc + 2].fill_with(|| Insn::Nop);
+                    changed = true;
+                }
+                (Insn::Jz | Insn::Jnz, Some(Insn::Address(t)), _) if *t == pc + 2 => {
+                    code[pc] = Insn::Pop;
+                    code[pc + 1] = Insn::Nop;
+                    changed = true;
+                }
+                _ => {}
+            }
+            pc += code[pc].size();
+        }
+        if !changed {
+            return code;
+        }
+        code = compact(code);
+    }
+}
+
+/// Remove the `Nop`s from `code`, adjusting the jump targets to the
+/// new addresses.  A jump to a `Nop` goes to the next instruction.
+#[must_use]
+pub fn compact(code: Vec<Insn>) -> Vec<Insn> {
+    // The address each old address moves to
+    let mut new_pc = Vec::with_capacity(code.len() + 1);
+    let mut here = 0;
+    for insn in &code {
+        new_pc.push(here);
+        here += usize::from(*insn != Insn::Nop);
+    }
+    new_pc.push(here);
+
+    let mut code: Vec<Insn> = code.into_iter().filter(|insn| *insn != Insn::Nop).collect();
+    let mut pc = 0;
+    while pc < code.len() {
+        if let Insn::Jmp | Insn::Jz | Insn::Jnz | Insn::Call = code[pc] {
+            if let Some(Insn::Address(t)) = code.get_mut(pc + 1) {
+                if let Some(&n) = new_pc.get(*t) {
+                    *t = n;
+                }
+            }
+        }
+        pc += code[pc].size();
+    }
+    code
+}
+
 /// Take the top-level program Node and compile it to instructions.
 #[must_use]
-pub fn compile(ast: Node) -> Vec<Insn> {
-    let mut cg = Codegen::default();
+pub fn compile(ast: &Node) -> Vec<Insn> {
+    compile_with_source_map(ast).0
+}
+
+/// Compile a single expression, such as the `3 + 4` in `a = 3 + 4;`,
+/// followed by `Halt`, for evaluating it on its own.  Unlike for a
+/// `Prog(Expr(..))`, the value isn't popped but left on the top of the
+/// stack when the VM halts.  There being no functions, a call
+/// panics, as for `compile`.
+///
+/// ```
+/// use tinyc_in_rust::codegen::compile_expr;
+/// use tinyc_in_rust::parser::NodeArena;
+/// use tinyc_in_rust::sexp::from_sexp;
+/// use tinyc_in_rust::vm::VM;
+/// let arena = NodeArena::new();
+/// let mut vm = VM::new();
+/// vm.run(compile_expr(from_sexp(&arena, "(Mul 6 7)").unwrap())).unwrap();
+/// assert_eq!(vm.stack(), [42]);
+/// ```
+#[must_use]
+pub fn compile_expr(expr: BNode) -> Vec<Insn> {
+    compile(&Node {
+        kind: NodeKind::Prog(expr),
+        span: expr.span,
+    })
+}
+
+/// Like `compile`, but for a tree from `memoize_subexpressions`, with
+/// the `Ref`s looked up in `refs`.  Each is compiled where it's used,
+/// so the code is the same as for the tree before it was shared.
+#[must_use]
+#[allow(clippy::implicit_hasher)]
+pub fn compile_with_refs<'a>(ast: &Node<'a>, refs: HashMap<u64, BNode<'a>>) -> Vec<Insn> {
+    let mut cg = Codegen {
+        refs,
+        ..Codegen::default()
+    };
     cg.compile(ast);
     cg.code
 }
 
+/// An error that stops a program from being compiled, though it
+/// parsed, with the position where it was detected
+#[derive(Debug)]
+pub struct CodegenError {
+    pub pos: SourcePosition,
+    pub message: String,
+}
+
+impl std::fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "input:{}:{}", self.pos, self.message)
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+/// Check that `ast` can be compiled: that every function called is
+/// defined, every `break` and `continue` is in a loop (or a `switch`,
+/// for `break`), every assignment is to a variable, no `read` is into
+/// a local, and `_` is only assigned to.  These are the errors
+/// `compile` panics on.
+///
+/// # Errors
+/// Returns a `CodegenError` for the first of these found
+pub fn check(ast: &Node) -> Result<(), CodegenError> {
+    let mut checks = Checks::default();
+    checks.visit_node(ast);
+    if let Some(e) = checks.error {
+        return Err(e);
+    }
+    match checks
+        .called
+        .into_iter()
+        .find(|(name, _)| !checks.defined.contains(name))
+    {
+        Some((name, span)) => Err(CodegenError {
+            pos: span.start,
+            message: format!("undefined function `{name}'"),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Compiling with `try_from` is the same as with `compile`, except
+/// what `check` finds is an error rather than a panic
+///
+/// ```
+/// use tinyc_in_rust::codegen::Insn;
+/// use tinyc_in_rust::parser::{parse, NodeArena};
+/// let arena = NodeArena::new();
+/// let ast = parse(&arena, "a = 42;").unwrap();
+/// let code = Vec::<Insn>::try_from(ast).unwrap();
+/// assert_eq!(code[..2], [Insn::Push, Insn::Integer(42)]);
+/// let ast = parse(&arena, "a = f();").unwrap();
+/// assert!(Vec::<Insn>::try_from(ast).is_err());
+/// ```
+impl TryFrom<&Node<'_>> for Vec<Insn> {
+    type Error = CodegenError;
+
+    fn try_from(ast: &Node) -> Result<Self, CodegenError> {
+        check(ast)?;
+        Ok(compile(ast))
+    }
+}
+
+/// The functions defined, and those called with where, the loops and
+/// `switch`es we're in, the locals in scope, and the first misplaced
+/// `break` or `continue`, assignment to something other than a
+/// variable, `read` into a local, or read of `_`
+#[derive(Default)]
+struct Checks {
+    defined: HashSet<String>,
+    called: Vec<(String, Span)>,
+    loops: usize,
+    switches: usize,
+    locals: Vec<HashSet<String>>,
+    error: Option<CodegenError>,
+}
+
+impl Checks {
+    fn misplaced(&mut self, span: Span, what: &str) {
+        self.error.get_or_insert(CodegenError {
+            pos: span.start,
+            message: format!("`{what}' outside of a loop"),
+        });
+    }
+}
+
+impl AstVisitor for Checks {
+    fn visit_node(&mut self, node: &Node) {
+        match &node.kind {
+            NodeKind::FnDef(name, _) => {
+                self.defined.insert((*name).to_string());
+                let outer = std::mem::take(&mut self.locals);
+                walk_node(self, node);
+                self.locals = outer;
+                return;
+            }
+            NodeKind::Call(name) => self.called.push(((*name).to_string(), node.span)),
+            NodeKind::Break if self.loops + self.switches == 0 => {
+                self.misplaced(node.span, "break");
+            }
+            NodeKind::Continue if self.loops == 0 => self.misplaced(node.span, "continue"),
+            NodeKind::Read(name) if self.locals.iter().any(|scope| scope.contains(*name)) => {
+                self.error.get_or_insert(CodegenError {
+                    pos: node.span.start,
+                    message: format!("cannot read into local `{name}'"),
+                });
+            }
+            NodeKind::Set(var, _) | NodeKind::AddAssign(var, _)
+                if !matches!(var.kind, NodeKind::Var(_)) =>
+            {
+                self.error.get_or_insert(CodegenError {
+                    pos: var.span.start,
+                    message: "variable expected".to_string(),
+                });
+            }
+            NodeKind::Set(var, val) if matches!(&var.kind, NodeKind::Var(v) if *v == "_") => {
+                self.visit_node(val);
+                return;
+            }
+            NodeKind::Var(name) if *name == "_" => {
+                self.error.get_or_insert(CodegenError {
+                    pos: node.span.start,
+                    message: "cannot read `_'".to_string(),
+                });
+            }
+            NodeKind::Decl(name) => {
+                if let Some(scope) = self.locals.last_mut() {
+                    scope.insert((*name).to_string());
+                }
+            }
+            NodeKind::Block(..) => {
+                self.locals.push(HashSet::new());
+                walk_node(self, node);
+                self.locals.pop();
+                return;
+            }
+            NodeKind::While(..) | NodeKind::Do(..) | NodeKind::For(..) => {
+                self.loops += 1;
+                walk_node(self, node);
+                self.loops -= 1;
+                return;
+            }
+            NodeKind::Switch(..) => {
+                self.switches += 1;
+                walk_node(self, node);
+                self.switches -= 1;
+                return;
+            }
+            _ => {}
+        }
+        walk_node(self, node);
+    }
+}
+
+/// Counts the locals declared outside of any function, which is the
+/// size of the frame they need
+#[derive(Default)]
+struct Decls(usize);
+
+impl AstVisitor for Decls {
+    fn visit_decl(&mut self, _name: &str) {
+        self.0 += 1;
+    }
+
+    fn visit_fn_def(&mut self, _name: &str, _body: &Node) {}
+}
+
+/// Maps each slot of the code to the span of the innermost `Node` it
+/// was generated for
+#[derive(Debug, Default)]
+pub struct SourceMap(Vec<Option<Span>>);
+
+impl SourceMap {
+    /// The span of source code that the code at `pc` came from
+    #[must_use]
+    pub fn lookup(&self, pc: usize) -> Option<Span> {
+        self.0.get(pc).copied().flatten()
+    }
+}
+
+/// Like `compile`, but also mapping the code back to the source
+#[must_use]
+pub fn compile_with_source_map(ast: &Node) -> (Vec<Insn>, SourceMap) {
+    compile_with_symbols(ast, &mut SymbolTable::new())
+}
+
+/// Like `compile_with_source_map`, but the variables get their slots
+/// from `symbols`, and the new ones are added to it
+#[must_use]
+pub fn compile_with_symbols(ast: &Node, symbols: &mut SymbolTable) -> (Vec<Insn>, SourceMap) {
+    let mut cg = Codegen {
+        symbols: std::mem::take(symbols),
+        ..Codegen::default()
+    };
+    cg.compile(ast);
+    *symbols = cg.symbols;
+    (cg.code, SourceMap(cg.spans))
+}
+
 /// The Generator traverses the parsed source code and generates
 /// `code` in the process.
 #[derive(Default)]
-struct Codegen {
+struct Codegen<'a> {
     code: Vec<Insn>,
+
+    /// The holes left by `break` statements, one frame per enclosing
+    /// loop.  They are fixed up to point past the loop when it ends.
+    break_holes: Vec<Vec<usize>>,
+
+    /// The holes left by `continue` statements, likewise one frame
+    /// per enclosing loop.  They are fixed up once the loop has
+    /// emitted the code that starts the next iteration.
+    continue_holes: Vec<Vec<usize>>,
+
+    /// The address of each label emitted so far, and the holes left
+    /// for labels, with the name and the span of the node that left
+    /// them.  They are fixed up by `finalize` once the whole program
+    /// is compiled.  A function's label is its name, and the labels
+    /// of the compiler's own have a `.` in them, so they can't clash.
+    labels: HashMap<String, usize>,
+    label_holes: Vec<(usize, String, Span)>,
+
+    /// The slots of the variables
+    symbols: SymbolTable,
+
+    /// The locals in scope, one map per enclosing block from their
+    /// names to their offsets in the current frame, and the number
+    /// of locals given an offset in the frame so far
+    scopes: Vec<HashMap<String, usize>>,
+    frame_size: usize,
+
+    /// The subtrees shared by the `Ref`s, see `memoize_subexpressions`
+    refs: HashMap<u64, BNode<'a>>,
+
+    /// The span of the node being compiled, and for each slot of
+    /// `code` the span of the node that was being compiled then
+    span: Option<Span>,
+    spans: Vec<Option<Span>>,
 }
 
-impl Codegen {
-    #[allow(clippy::unused_self)]
-    fn global(&self, v: &str) -> usize {
-        match v {
-            v if v.len() == 1 => v.chars().next().unwrap() as usize - 97,
-            _ => panic!("{v} isn't a variable we can compile right now"),
+/// Where a variable lives
+enum VarKind {
+    /// At this offset in the current frame
+    Local(usize),
+    /// In this global slot
+    Global(usize),
+    /// Nowhere, as it's `_`, whose values are thrown away
+    Discard,
+}
+
+impl Codegen<'_> {
+    /// The innermost local named `v`, or else the global
+    fn lookup(&mut self, v: &str) -> VarKind {
+        if v == "_" {
+            return VarKind::Discard;
+        }
+        match self.scopes.iter().rev().find_map(|scope| scope.get(v)) {
+            Some(&offset) => VarKind::Local(offset),
+            None => VarKind::Global(self.symbols.intern(v)),
+        }
+    }
+
+    /// Push the value of the variable `v`
+    fn fetch(&mut self, v: &str) {
+        match self.lookup(v) {
+            VarKind::Local(offset) => {
+                self.code.push(Insn::FetchLocal);
+                self.code.push(Insn::Address(offset));
+            }
+            VarKind::Global(a) => {
+                self.code.push(Insn::Fetch);
+                self.code.push(Insn::Address(a));
+            }
+            VarKind::Discard => panic!("`_' can't be read"),
+        }
+    }
+
+    /// Store the top of the stack in the variable `v`, leaving it there
+    fn store(&mut self, v: &str) {
+        match self.lookup(v) {
+            VarKind::Local(offset) => {
+                self.code.push(Insn::StoreLocal);
+                self.code.push(Insn::Address(offset));
+            }
+            VarKind::Global(a) => {
+                self.code.push(Insn::Store);
+                self.code.push(Insn::Address(a));
+            }
+            // The value stays on the stack as that of the assignment,
+            // usually for the `Pop` ending the statement to drop
+            VarKind::Discard => {}
+        }
+    }
+
+    /// Compile `body` in a frame of its own, with room for the locals
+    /// it declares outside of any function.  The `Leave` goes before
+    /// the instruction `exit` which ends it.
+    fn compile_frame(&mut self, body: &Node, exit: Insn) {
+        let mut decls = Decls::default();
+        decls.visit_node(body);
+        let outer_scopes = std::mem::replace(&mut self.scopes, vec![HashMap::new()]);
+        let outer_size = std::mem::replace(&mut self.frame_size, 0);
+        if decls.0 > 0 {
+            self.code.push(Insn::Enter);
+            self.code.push(Insn::Address(decls.0));
+        }
+        self.compile(body);
+        if decls.0 > 0 {
+            self.code.push(Insn::Leave);
+            self.code.push(Insn::Address(decls.0));
         }
+        self.code.push(exit);
+        self.scopes = outer_scopes;
+        self.frame_size = outer_size;
     }
 
     fn here(&self) -> usize {
@@ -70,99 +811,384 @@ impl Codegen {
         self.code[hole] = Insn::Address(target);
     }
 
-    fn compile(&mut self, n: Node) {
-        match n {
-            Node::Add(a, b) => {
-                self.compile(*a);
-                self.compile(*b);
-                self.code.push(Insn::Add);
+    /// Put the label `name` here
+    fn emit_label(&mut self, name: &str) {
+        self.labels.insert(name.to_string(), self.here());
+    }
+
+    /// Leave a hole for the address of the label `name`, which may not
+    /// have been emitted yet
+    fn label_hole(&mut self, name: &str) {
+        let hole = self.hole();
+        let span = self.span.unwrap_or_default();
+        self.label_holes.push((hole, name.to_string(), span));
+    }
+
+    /// Jump to the label `name`
+    fn emit_jump_to_label(&mut self, name: &str) {
+        self.code.push(Insn::Jmp);
+        self.label_hole(name);
+    }
+
+    /// Fix the holes left for labels.  Only a function can be missing,
+    /// as the compiler emits all of its own labels.
+    fn finalize(&mut self) {
+        for (hole, name, span) in std::mem::take(&mut self.label_holes) {
+            let Some(&target) = self.labels.get(&name) else {
+                panic!("input:{}:undefined function `{name}'", span.start);
+            };
+            self.fix(hole, target);
+        }
+    }
+
+    /// Start compiling a loop which `break` can leave and `continue`
+    /// can restart
+    fn enter_loop(&mut self) {
+        self.break_holes.push(Vec::new());
+        self.continue_holes.push(Vec::new());
+    }
+
+    /// Fix the innermost loop's `continue`s to jump to `target`
+    fn fix_continues(&mut self, target: usize) {
+        for hole in self.continue_holes.pop().unwrap() {
+            self.fix(hole, target);
+        }
+    }
+
+    /// Finish the innermost loop, fixing its `break`s to jump here
+    fn leave_loop(&mut self) {
+        for hole in self.break_holes.pop().unwrap() {
+            self.fix(hole, self.here());
+        }
+    }
+
+    /// Compile the operands of a binary operator followed by `insn`
+    fn binary(&mut self, a: &Node, b: &Node, insn: Insn) {
+        self.compile(a);
+        self.compile(b);
+        self.code.push(insn);
+    }
+
+    /// Compile `&&` (`or` false) or `||` (`or` true).  The right
+    /// operand is skipped if the left one decides the result, and
+    /// either way the result is normalized to 0 or 1.
+    fn short_circuit(&mut self, a: &Node, b: &Node, or: bool) {
+        let branch = || if or { Insn::Jnz } else { Insn::Jz };
+
+        self.compile(a);
+        self.code.push(branch());
+        let j1 = self.hole();
+
+        self.compile(b);
+        self.code.push(branch());
+        let j2 = self.hole();
+
+        self.code.push(Insn::Push);
+        self.code.push(Insn::Integer(isize::from(!or)));
+        self.code.push(Insn::Jmp);
+        let jmp = self.hole();
+
+        self.fix(j1, self.here());
+        self.fix(j2, self.here());
+        self.code.push(Insn::Push);
+        self.code.push(Insn::Integer(isize::from(or)));
+
+        self.fix(jmp, self.here());
+    }
+
+    /// The code generated so far belongs to the current node
+    fn sync_spans(&mut self) {
+        self.spans.resize(self.code.len(), self.span);
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn compile(&mut self, n: &Node) {
+        let Node { kind, span } = n;
+        let span = *span;
+        self.sync_spans();
+        let outer = self.span.replace(span);
+        match kind {
+            NodeKind::Add(a, b) => self.binary(a, b, Insn::Add),
+            NodeKind::Sub(a, b) => self.binary(a, b, Insn::Sub),
+            NodeKind::Mul(a, b) => self.binary(a, b, Insn::Mul),
+            NodeKind::Div(a, b) => self.binary(a, b, Insn::Div),
+            NodeKind::Mod(a, b) => self.binary(a, b, Insn::Mod),
+            NodeKind::Shl(a, b) => self.binary(a, b, Insn::Shl),
+            NodeKind::Shr(a, b) => self.binary(a, b, Insn::Shr),
+            NodeKind::BitAnd(a, b) => self.binary(a, b, Insn::BitAnd),
+            NodeKind::BitOr(a, b) => self.binary(a, b, Insn::BitOr),
+            NodeKind::BitXor(a, b) => self.binary(a, b, Insn::BitXor),
+            NodeKind::Min(a, b) => self.binary(a, b, Insn::Min),
+            NodeKind::Max(a, b) => self.binary(a, b, Insn::Max),
+            NodeKind::BitNot(x) => {
+                self.compile(x);
+                self.code.push(Insn::BitNot);
             }
-            Node::Sub(a, b) => {
-                self.compile(*a);
-                self.compile(*b);
-                self.code.push(Insn::Sub);
+            NodeKind::Neg(x) => {
+                self.compile(x);
+                self.code.push(Insn::Neg);
             }
-            Node::If1(test, then) => {
-                self.compile(*test);
+            NodeKind::Abs(x) => {
+                self.compile(x);
+                self.code.push(Insn::Abs);
+            }
+            NodeKind::And(a, b) => self.short_circuit(a, b, false),
+            NodeKind::Or(a, b) => self.short_circuit(a, b, true),
+            NodeKind::If1(test, then) => {
+                self.compile(test);
                 self.code.push(Insn::Jz);
                 let jz = self.hole();
 
-                self.compile(*then);
+                self.compile(then);
                 self.fix(jz, self.here());
             }
-            Node::If2(test, then, else_) => {
-                self.compile(*test);
+            NodeKind::If2(test, then, else_) => {
+                self.compile(test);
+                self.code.push(Insn::Jz);
+                let jz = self.hole();
+
+                self.compile(then);
+                self.code.push(Insn::Jmp);
+                let jmp = self.hole();
+
+                self.fix(jz, self.here());
+                self.compile(else_);
+
+                self.fix(jmp, self.here());
+            }
+            NodeKind::Ternary(test, then, else_) | NodeKind::If2Expr(test, then, else_) => {
+                // Like `If2`, but each branch leaves its value on the stack
+                self.compile(test);
                 self.code.push(Insn::Jz);
                 let jz = self.hole();
 
-                self.compile(*then);
+                self.compile(then);
                 self.code.push(Insn::Jmp);
                 let jmp = self.hole();
 
                 self.fix(jz, self.here());
-                self.compile(*else_);
+                self.compile(else_);
 
                 self.fix(jmp, self.here());
             }
-            Node::While(test, body) => {
+            NodeKind::While(test, body) => {
+                self.enter_loop();
+                let l_restart = self.here();
+
+                self.compile(test);
+
+                self.code.push(Insn::Jz);
+                let jz = self.hole();
+
+                self.compile(body);
+                self.code.push(Insn::Jmp);
+                let jmp = self.hole();
+
+                self.fix(jmp, l_restart);
+                self.fix(jz, self.here());
+                self.fix_continues(l_restart);
+                self.leave_loop();
+            }
+            NodeKind::For(init, test, step, body) => {
+                self.compile(init);
+                self.code.push(Insn::Pop);
+
+                self.enter_loop();
                 let l_restart = self.here();
 
-                self.compile(*test);
+                self.compile(test);
 
                 self.code.push(Insn::Jz);
                 let jz = self.hole();
 
-                self.compile(*body);
+                self.compile(body);
+                self.fix_continues(self.here());
+                self.compile(step);
+                self.code.push(Insn::Pop);
                 self.code.push(Insn::Jmp);
                 let jmp = self.hole();
 
                 self.fix(jmp, l_restart);
                 self.fix(jz, self.here());
+                self.leave_loop();
+            }
+            NodeKind::Switch(value, cases, default) => {
+                // The value stays on the stack while it is compared
+                // with each case, and is popped once one is chosen
+                self.compile(value);
+                let mut jumps = Vec::new();
+                for (n, _) in cases {
+                    self.code.push(Insn::Dup);
+                    self.code.push(Insn::Push);
+                    self.code.push(Insn::Integer(*n));
+                    self.code.push(Insn::Eq);
+                    self.code.push(Insn::Jnz);
+                    jumps.push(self.hole());
+                }
+
+                // A `break` leaves the `switch`, just like the end of
+                // each case does, but `continue` is for the loop
+                self.break_holes.push(Vec::new());
+                self.code.push(Insn::Pop);
+                if let Some(default) = *default {
+                    self.compile(default);
+                }
+                for ((_, body), jump) in cases.iter().zip(jumps) {
+                    self.code.push(Insn::Jmp);
+                    let jmp = self.hole();
+                    self.break_holes.last_mut().unwrap().push(jmp);
+
+                    self.fix(jump, self.here());
+                    self.code.push(Insn::Pop);
+                    self.compile(body);
+                }
+                self.leave_loop();
             }
-            Node::Do(body, test) => {
+            NodeKind::Do(body, test) => {
+                self.enter_loop();
                 let l_restart = self.here();
 
-                self.compile(*body);
-                self.compile(*test);
+                self.compile(body);
+                self.fix_continues(self.here());
+                self.compile(test);
 
                 self.code.push(Insn::Jnz);
                 let jnz = self.hole();
                 self.fix(jnz, l_restart);
+                self.leave_loop();
+            }
+            NodeKind::Prog(body) => {
+                self.compile_frame(body, Insn::Halt);
+                self.finalize();
             }
-            Node::Prog(body) => {
-                self.compile(*body);
-                self.code.push(Insn::Halt);
+            NodeKind::FnDef(name, body) => {
+                // The body is only run when called
+                let end = format!("{name}.end");
+                self.emit_jump_to_label(&end);
+
+                self.emit_label(name);
+                self.compile_frame(body, Insn::Ret);
+
+                self.emit_label(&end);
             }
-            Node::Expr(body) => {
-                self.compile(*body);
+            NodeKind::Call(name) => {
+                self.code.push(Insn::Call);
+                self.label_hole(name);
+                self.code.push(Insn::Push);
+                self.code.push(Insn::Integer(0));
+            }
+            NodeKind::Expr(body) => {
+                self.compile(body);
                 self.code.push(Insn::Pop);
             }
-            Node::Set(var, expr) => {
-                self.compile(*expr);
-                self.code.push(Insn::Store);
-                let Node::Var(v) = *var else {
-                    panic!("We expected a Var, not {:?}", *var);
+            NodeKind::Print(x) => {
+                // A variable is printed with its name, like the globals
+                // at the end of the run, unless it's a local
+                let global = match &x.kind {
+                    NodeKind::Var(v) => match self.lookup(v) {
+                        VarKind::Global(a) => Some(a),
+                        VarKind::Local(_) | VarKind::Discard => None,
+                    },
+                    _ => None,
                 };
-                self.code.push(Insn::Address(self.global(&v)));
+                self.compile(x);
+                if let Some(a) = global {
+                    self.code.push(Insn::PrintVar);
+                    self.code.push(Insn::Address(a));
+                } else {
+                    self.code.push(Insn::Print);
+                }
             }
-            Node::Cst(val) => {
+            NodeKind::Read(v) => {
+                let VarKind::Global(a) = self.lookup(v) else {
+                    panic!("input:{}:cannot read into local `{v}'", span.start);
+                };
+                self.code.push(Insn::Read);
+                self.code.push(Insn::Address(a));
+            }
+            NodeKind::Decl(v) => {
+                let offset = self.frame_size;
+                self.frame_size += 1;
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert((*v).to_string(), offset);
+                // The slot may have been used before, eg. in a loop
                 self.code.push(Insn::Push);
-                self.code.push(Insn::Integer(val));
+                self.code.push(Insn::Integer(0));
+                self.code.push(Insn::StoreLocal);
+                self.code.push(Insn::Address(offset));
+                self.code.push(Insn::Pop);
             }
-            Node::Var(v) => {
-                self.code.push(Insn::Fetch);
-                self.code.push(Insn::Address(self.global(&v)));
+            NodeKind::Set(var, expr) => {
+                self.compile(expr);
+                let NodeKind::Var(v) = &var.kind else {
+                    panic!("input:{}:variable expected", var.span.start);
+                };
+                self.store(v);
+            }
+            // As for its desugaring `var = var + expr`
+            NodeKind::AddAssign(var, expr) => {
+                let NodeKind::Var(v) = &var.kind else {
+                    panic!("input:{}:variable expected", var.span.start);
+                };
+                self.compile(var);
+                self.compile(expr);
+                self.code.push(Insn::Add);
+                self.store(v);
+            }
+            NodeKind::Cst(val) => {
+                self.code.push(Insn::Push);
+                self.code.push(Insn::Integer(*val));
+            }
+            NodeKind::Var(v) => self.fetch(v),
+            NodeKind::Lt(a, b) => self.binary(a, b, Insn::Lt),
+            NodeKind::Gt(a, b) => self.binary(a, b, Insn::Gt),
+            NodeKind::Le(a, b) => self.binary(a, b, Insn::Le),
+            NodeKind::Ge(a, b) => self.binary(a, b, Insn::Ge),
+            NodeKind::Eq(a, b) => self.binary(a, b, Insn::Eq),
+            NodeKind::Ne(a, b) => self.binary(a, b, Insn::Ne),
+            NodeKind::Seq(a, b) => {
+                self.compile(a);
+                self.compile(b);
             }
-            Node::Lt(a, b) => {
-                self.compile(*a);
-                self.compile(*b);
-                self.code.push(Insn::Lt);
+            NodeKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for s in stmts {
+                    self.compile(s);
+                }
+                self.scopes.pop();
             }
-            Node::Seq(a, b) => {
-                self.compile(*a);
-                self.compile(*b);
+            NodeKind::Break => {
+                assert!(
+                    !self.break_holes.is_empty(),
+                    "input:{}:`break' outside of a loop",
+                    span.start
+                );
+                self.code.push(Insn::Jmp);
+                let jmp = self.hole();
+                self.break_holes.last_mut().unwrap().push(jmp);
+            }
+            NodeKind::Continue => {
+                assert!(
+                    !self.continue_holes.is_empty(),
+                    "input:{}:`continue' outside of a loop",
+                    span.start
+                );
+                self.code.push(Insn::Jmp);
+                let jmp = self.hole();
+                self.continue_holes.last_mut().unwrap().push(jmp);
+            }
+            NodeKind::Empty => {}
+            NodeKind::Rand => self.code.push(Insn::Rand),
+            NodeKind::Ref(key) => {
+                let Some(&shared) = self.refs.get(key) else {
+                    panic!("no shared subtree {key:x}");
+                };
+                self.compile(shared);
             }
-            Node::Empty => {}
         }
+        self.sync_spans();
+        self.span = outer;
     }
 }
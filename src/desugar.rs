@@ -0,0 +1,123 @@
+//! Desugaring rewrites the conveniences of the language into the
+//! plainer constructs they are short for, so later passes and the
+//! code generator have less to deal with.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::parser::{BNode, Node, NodeArena, NodeKind};
+
+/// Rewrite
+///
+/// - `for (init; test; step) body` into
+///   `{ init; while (test) { body; step; } }`, and
+/// - `var += val` into `var = var + val`.
+///
+/// A `for` whose body has a `continue` for it is kept, as the
+/// `continue` would skip the `step` in the `while`.  So is a `+=` to
+/// something other than a variable, eg. `a + 1 += 2`, which the parser
+/// accepts, for `codegen::check` to report.
+#[must_use]
+pub fn desugar<'a>(arena: &'a NodeArena<'a>, node: BNode<'a>) -> BNode<'a> {
+    let span = node.span;
+    let node_of = |kind| arena.alloc(Node { kind, span });
+    let sugarless = |node| desugar(arena, node);
+    let kind = match node.kind {
+        NodeKind::For(init, test, step, body) if !body.continues() => NodeKind::Block(vec![
+            node_of(NodeKind::Expr(sugarless(init))),
+            node_of(NodeKind::While(
+                sugarless(test),
+                node_of(NodeKind::Block(vec![
+                    sugarless(body),
+                    node_of(NodeKind::Expr(sugarless(step))),
+                ])),
+            )),
+        ]),
+        NodeKind::AddAssign(var, val) if matches!(var.kind, NodeKind::Var(_)) => {
+            NodeKind::Set(var, node_of(NodeKind::Add(var, sugarless(val))))
+        }
+        NodeKind::AddAssign(var, val) => NodeKind::AddAssign(sugarless(var), sugarless(val)),
+        NodeKind::Add(a, b) => NodeKind::Add(sugarless(a), sugarless(b)),
+        NodeKind::Sub(a, b) => NodeKind::Sub(sugarless(a), sugarless(b)),
+        NodeKind::Mul(a, b) => NodeKind::Mul(sugarless(a), sugarless(b)),
+        NodeKind::Div(a, b) => NodeKind::Div(sugarless(a), sugarless(b)),
+        NodeKind::Mod(a, b) => NodeKind::Mod(sugarless(a), sugarless(b)),
+        NodeKind::Shl(a, b) => NodeKind::Shl(sugarless(a), sugarless(b)),
+        NodeKind::Shr(a, b) => NodeKind::Shr(sugarless(a), sugarless(b)),
+        NodeKind::Lt(a, b) => NodeKind::Lt(sugarless(a), sugarless(b)),
+        NodeKind::Gt(a, b) => NodeKind::Gt(sugarless(a), sugarless(b)),
+        NodeKind::Le(a, b) => NodeKind::Le(sugarless(a), sugarless(b)),
+        NodeKind::Ge(a, b) => NodeKind::Ge(sugarless(a), sugarless(b)),
+        NodeKind::Eq(a, b) => NodeKind::Eq(sugarless(a), sugarless(b)),
+        NodeKind::Ne(a, b) => NodeKind::Ne(sugarless(a), sugarless(b)),
+        NodeKind::BitAnd(a, b) => NodeKind::BitAnd(sugarless(a), sugarless(b)),
+        NodeKind::BitOr(a, b) => NodeKind::BitOr(sugarless(a), sugarless(b)),
+        NodeKind::BitXor(a, b) => NodeKind::BitXor(sugarless(a), sugarless(b)),
+        NodeKind::Min(a, b) => NodeKind::Min(sugarless(a), sugarless(b)),
+        NodeKind::Max(a, b) => NodeKind::Max(sugarless(a), sugarless(b)),
+        NodeKind::BitNot(x) => NodeKind::BitNot(sugarless(x)),
+        NodeKind::Neg(x) => NodeKind::Neg(sugarless(x)),
+        NodeKind::Abs(x) => NodeKind::Abs(sugarless(x)),
+        NodeKind::And(a, b) => NodeKind::And(sugarless(a), sugarless(b)),
+        NodeKind::Or(a, b) => NodeKind::Or(sugarless(a), sugarless(b)),
+        NodeKind::Ternary(a, b, c) => NodeKind::Ternary(sugarless(a), sugarless(b), sugarless(c)),
+        NodeKind::If2Expr(a, b, c) => NodeKind::If2Expr(sugarless(a), sugarless(b), sugarless(c)),
+        NodeKind::Set(var, val) => NodeKind::Set(var, sugarless(val)),
+        NodeKind::If1(test, then) => NodeKind::If1(sugarless(test), sugarless(then)),
+        NodeKind::If2(test, then, else_) => {
+            NodeKind::If2(sugarless(test), sugarless(then), sugarless(else_))
+        }
+        NodeKind::While(test, body) => NodeKind::While(sugarless(test), sugarless(body)),
+        NodeKind::Do(body, test) => NodeKind::Do(sugarless(body), sugarless(test)),
+        NodeKind::For(init, test, step, body) => NodeKind::For(
+            sugarless(init),
+            sugarless(test),
+            sugarless(step),
+            sugarless(body),
+        ),
+        NodeKind::Switch(value, ref cases, default) => NodeKind::Switch(
+            sugarless(value),
+            cases
+                .iter()
+                .map(|&(n, body)| (n, sugarless(body)))
+                .collect(),
+            default.map(sugarless),
+        ),
+        NodeKind::Seq(a, b) => NodeKind::Seq(sugarless(a), sugarless(b)),
+        NodeKind::Block(ref stmts) => {
+            NodeKind::Block(stmts.iter().map(|&s| sugarless(s)).collect())
+        }
+        NodeKind::Expr(x) => NodeKind::Expr(sugarless(x)),
+        NodeKind::Print(x) => NodeKind::Print(sugarless(x)),
+        NodeKind::FnDef(name, body) => NodeKind::FnDef(name, sugarless(body)),
+        NodeKind::Prog(body) => NodeKind::Prog(sugarless(body)),
+        NodeKind::Var(_)
+        | NodeKind::Cst(_)
+        | NodeKind::Read(_)
+        | NodeKind::Decl(_)
+        | NodeKind::Call(_)
+        | NodeKind::Rand
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Empty
+        | NodeKind::Ref(_) => return node,
+    };
+    node_of(kind)
+}
+
+impl Node<'_> {
+    /// Is there a `continue` for the loop with this body?  Those in
+    /// nested loops are for the nested loops.
+    fn continues(&self) -> bool {
+        match &self.kind {
+            NodeKind::Continue => true,
+            NodeKind::If1(_, a) => a.continues(),
+            NodeKind::If2(_, a, b) | NodeKind::Seq(a, b) => a.continues() || b.continues(),
+            NodeKind::Block(stmts) => stmts.iter().any(|s| s.continues()),
+            NodeKind::Switch(_, cases, default) => {
+                cases.iter().any(|(_, body)| body.continues())
+                    || default.as_ref().is_some_and(|body| body.continues())
+            }
+            _ => false,
+        }
+    }
+}
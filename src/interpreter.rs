@@ -0,0 +1,445 @@
+//! Run a program by walking its tree, without compiling it.  This is
+//! slower than the VM, but simple enough to serve as the reference
+//! for what a program should do.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::lexer::SourcePosition;
+use crate::parser::{Node, NodeKind};
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+
+/// An error that stops the program, with the position of the node
+/// where it happened
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub pos: SourcePosition,
+    pub message: String,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "input:{}:{}", self.pos, self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The deepest the calls can nest, as for the VM's default stack limit
+const MAX_DEPTH: usize = crate::vm::DEFAULT_STACK_LIMIT;
+
+/// The size of the stack the interpreter runs on.  Each call recurses
+/// on it, through several frames of `exec` and `eval`, so it needs
+/// much more than the usual stack.
+const STACK_SIZE: usize = 64 << 20;
+
+/// Run the program `node` with the globals in `globals`, at the slots
+/// `symbols` gives them as for the VM, and leave them with their
+/// values at the end.  A global that has no slot yet gets the next
+/// one, and `globals` grows to make room for it.  What it prints goes
+/// to standard output, and what it reads comes from standard input.
+///
+/// ```
+/// use tinyc_in_rust::interpreter::interpret;
+/// use tinyc_in_rust::parser::{parse, NodeArena};
+/// use tinyc_in_rust::symbols::SymbolTable;
+/// let mut globals = vec![0; 26];
+/// globals[8] = 125;
+/// globals[9] = 100;
+/// let arena = NodeArena::new();
+/// let gcd = parse(&arena, "{ while (i - j) if (i < j) j = j - i; else i = i - j; gcd = i; }");
+/// let mut symbols = SymbolTable::new();
+/// interpret(gcd.unwrap(), &mut globals, &mut symbols).unwrap();
+/// assert_eq!(globals[8..10], [25, 25]);
+/// assert_eq!(globals[symbols.lookup("gcd").unwrap()], 25);
+/// ```
+///
+/// # Errors
+/// Returns a `RuntimeError` if the program fails, eg. divides by zero,
+/// or can't be run, eg. calls a function that isn't defined
+///
+/// # Panics
+/// Panics if the thread the program runs on can't be started
+pub fn interpret(
+    node: &Node,
+    globals: &mut Vec<isize>,
+    symbols: &mut SymbolTable,
+) -> Result<(), RuntimeError> {
+    let mut functions = Functions::default();
+    functions.visit_node(node);
+    let mut interpreter = Interpreter {
+        globals,
+        symbols,
+        functions: functions.0,
+        scopes: vec![HashMap::new()],
+        loops: 0,
+        switches: 0,
+        depth: 0,
+        rand_state: 0,
+    };
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, || interpreter.exec(node).map(|_| ()))
+            .expect("cannot start the interpreter thread")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// The functions defined in the program, by name
+#[derive(Default)]
+struct Functions<'a>(HashMap<&'a str, &'a Node<'a>>);
+
+impl<'a> Functions<'a> {
+    fn visit_node(&mut self, node: &'a Node<'a>) {
+        match &node.kind {
+            NodeKind::FnDef(name, body) => {
+                self.0.insert(name, body);
+            }
+            NodeKind::Prog(body) | NodeKind::Expr(body) => self.visit_node(body),
+            NodeKind::Seq(a, b) => {
+                self.visit_node(a);
+                self.visit_node(b);
+            }
+            NodeKind::Block(stmts) => {
+                for s in stmts {
+                    self.visit_node(s);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How control leaves a statement
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+}
+
+struct Interpreter<'a> {
+    globals: &'a mut Vec<isize>,
+    symbols: &'a mut SymbolTable,
+    functions: HashMap<&'a str, &'a Node<'a>>,
+
+    /// The locals of the function being run, one map per enclosing
+    /// block
+    scopes: Vec<HashMap<String, isize>>,
+
+    /// The loops and `switch`es of the function being run that we're
+    /// in, and how deep the calls are
+    loops: usize,
+    switches: usize,
+    depth: usize,
+
+    /// The state of the generator `rand()` draws from, the same as the
+    /// VM's but always starting from zero
+    rand_state: u64,
+}
+
+fn error<T>(node: &Node, message: &str) -> Result<T, RuntimeError> {
+    Err(RuntimeError {
+        pos: node.span.start,
+        message: message.to_string(),
+    })
+}
+
+impl Interpreter<'_> {
+    /// The slot of the global `name`, making room for it if it's new
+    fn global(&mut self, name: &str) -> usize {
+        let slot = self.symbols.intern(name);
+        if self.globals.len() <= slot {
+            self.globals.resize(slot + 1, 0);
+        }
+        slot
+    }
+
+    fn fetch(&mut self, node: &Node, name: &str) -> Result<isize, RuntimeError> {
+        if name == "_" {
+            return error(node, "cannot read `_'");
+        }
+        if let Some(&value) = self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            return Ok(value);
+        }
+        let slot = self.global(name);
+        Ok(self.globals[slot])
+    }
+
+    /// Set the variable `name`, or throw `value` away if it's `_`
+    fn store(&mut self, name: &str, value: isize) {
+        if name == "_" {
+            return;
+        }
+        if let Some(local) = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+        {
+            *local = value;
+            return;
+        }
+        let slot = self.global(name);
+        self.globals[slot] = value;
+    }
+
+    /// The name of the variable `var` that is assigned to
+    fn target<'n>(var: &'n Node) -> Result<&'n str, RuntimeError> {
+        match &var.kind {
+            NodeKind::Var(name) => Ok(name),
+            _ => error(var, "variable expected"),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        a: &Node,
+        b: &Node,
+        op: impl FnOnce(isize, isize) -> isize,
+    ) -> Result<isize, RuntimeError> {
+        let a = self.eval(a)?;
+        let b = self.eval(b)?;
+        Ok(op(a, b))
+    }
+
+    /// Like `binary`, but failing if the divisor is zero
+    fn divide(
+        &mut self,
+        node: &Node,
+        a: &Node,
+        b: &Node,
+        op: impl FnOnce(isize, isize) -> isize,
+    ) -> Result<isize, RuntimeError> {
+        let a = self.eval(a)?;
+        let b = self.eval(b)?;
+        if b == 0 {
+            return error(node, "division by zero");
+        }
+        Ok(op(a, b))
+    }
+
+    fn test(&mut self, node: &Node) -> Result<bool, RuntimeError> {
+        Ok(self.eval(node)? != 0)
+    }
+
+    /// Run the body of a loop, and say whether to leave it
+    fn loop_body(&mut self, body: &Node) -> Result<bool, RuntimeError> {
+        self.loops += 1;
+        let flow = self.exec(body);
+        self.loops -= 1;
+        Ok(flow? == Flow::Break)
+    }
+
+    /// Call the function `name`, which has a frame of locals of its own
+    fn call(&mut self, node: &Node, name: &str) -> Result<isize, RuntimeError> {
+        let Some(&body) = self.functions.get(name) else {
+            return error(node, &format!("undefined function `{name}'"));
+        };
+        if self.depth == MAX_DEPTH {
+            return error(node, "stack overflow");
+        }
+        let scopes = std::mem::replace(&mut self.scopes, vec![HashMap::new()]);
+        let loops = std::mem::take(&mut self.loops);
+        let switches = std::mem::take(&mut self.switches);
+        self.depth += 1;
+        let flow = self.exec(body);
+        self.depth -= 1;
+        self.scopes = scopes;
+        self.loops = loops;
+        self.switches = switches;
+        flow?;
+        Ok(0)
+    }
+
+    /// Run the statement `node`
+    #[allow(clippy::too_many_lines)]
+    fn exec(&mut self, node: &Node) -> Result<Flow, RuntimeError> {
+        match &node.kind {
+            NodeKind::Prog(body) => return self.exec(body),
+            NodeKind::If1(test, then) => {
+                if self.test(test)? {
+                    return self.exec(then);
+                }
+            }
+            NodeKind::If2(test, then, else_) => {
+                return if self.test(test)? {
+                    self.exec(then)
+                } else {
+                    self.exec(else_)
+                };
+            }
+            NodeKind::While(test, body) => {
+                while self.test(test)? {
+                    if self.loop_body(body)? {
+                        break;
+                    }
+                }
+            }
+            NodeKind::Do(body, test) => loop {
+                if self.loop_body(body)? || !self.test(test)? {
+                    break;
+                }
+            },
+            NodeKind::For(init, test, step, body) => {
+                self.eval(init)?;
+                while self.test(test)? {
+                    if self.loop_body(body)? {
+                        break;
+                    }
+                    self.eval(step)?;
+                }
+            }
+            NodeKind::Switch(value, cases, default) => {
+                let value = self.eval(value)?;
+                let body = cases
+                    .iter()
+                    .find(|(n, _)| *n == value)
+                    .map(|(_, body)| body)
+                    .or(default.as_ref());
+                if let Some(body) = body {
+                    self.switches += 1;
+                    let flow = self.exec(body);
+                    self.switches -= 1;
+                    // Only a `continue` goes on past the `switch`
+                    if flow? == Flow::Continue {
+                        return Ok(Flow::Continue);
+                    }
+                }
+            }
+            NodeKind::Print(x) => {
+                let value = self.eval(x)?;
+                match &x.kind {
+                    NodeKind::Var(name) if !self.scopes.iter().any(|s| s.contains_key(*name)) => {
+                        println!("{name} = {value}");
+                    }
+                    _ => println!("{value}"),
+                }
+            }
+            NodeKind::Read(name) => {
+                let mut line = String::new();
+                let value = match std::io::stdin().read_line(&mut line) {
+                    Ok(n) if n > 0 => line.trim().parse().ok(),
+                    _ => None,
+                };
+                let Some(value) = value else {
+                    return error(node, "integer expected in the input");
+                };
+                self.store(name, value);
+            }
+            NodeKind::Decl(name) => {
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .insert((*name).to_string(), 0);
+            }
+            NodeKind::Break if self.loops + self.switches == 0 => {
+                return error(node, "`break' outside of a loop");
+            }
+            NodeKind::Break => return Ok(Flow::Break),
+            NodeKind::Continue if self.loops == 0 => {
+                return error(node, "`continue' outside of a loop");
+            }
+            NodeKind::Continue => return Ok(Flow::Continue),
+            NodeKind::Empty | NodeKind::FnDef(..) => {}
+            NodeKind::Seq(a, b) => {
+                let flow = self.exec(a)?;
+                if flow != Flow::Normal {
+                    return Ok(flow);
+                }
+                return self.exec(b);
+            }
+            NodeKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                let mut flow = Flow::Normal;
+                for s in stmts {
+                    flow = self.exec(s)?;
+                    if flow != Flow::Normal {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                return Ok(flow);
+            }
+            NodeKind::Expr(x) => {
+                self.eval(x)?;
+            }
+            _ => {
+                self.eval(node)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// The value of the expression `node`
+    fn eval(&mut self, node: &Node) -> Result<isize, RuntimeError> {
+        match &node.kind {
+            NodeKind::Var(name) => self.fetch(node, name),
+            NodeKind::Cst(value) => Ok(*value),
+            NodeKind::Add(a, b) => self.binary(a, b, isize::wrapping_add),
+            NodeKind::Sub(a, b) => self.binary(a, b, isize::wrapping_sub),
+            NodeKind::Mul(a, b) => self.binary(a, b, isize::wrapping_mul),
+            NodeKind::Div(a, b) => self.divide(node, a, b, isize::wrapping_div),
+            NodeKind::Mod(a, b) => self.divide(node, a, b, isize::wrapping_rem),
+            // As in the VM, shifting by the width or more (or by a
+            // negative amount) shifts out all the bits
+            NodeKind::Shl(a, b) => self.binary(a, b, |a, b| {
+                u32::try_from(b)
+                    .ok()
+                    .and_then(|b| a.checked_shl(b))
+                    .unwrap_or(0)
+            }),
+            NodeKind::Shr(a, b) => self.binary(a, b, |a, b| {
+                a >> u32::try_from(b).map_or(isize::BITS - 1, |b| b.min(isize::BITS - 1))
+            }),
+            NodeKind::Lt(a, b) => self.binary(a, b, |a, b| isize::from(a < b)),
+            NodeKind::Gt(a, b) => self.binary(a, b, |a, b| isize::from(a > b)),
+            NodeKind::Le(a, b) => self.binary(a, b, |a, b| isize::from(a <= b)),
+            NodeKind::Ge(a, b) => self.binary(a, b, |a, b| isize::from(a >= b)),
+            NodeKind::Eq(a, b) => self.binary(a, b, |a, b| isize::from(a == b)),
+            NodeKind::Ne(a, b) => self.binary(a, b, |a, b| isize::from(a != b)),
+            NodeKind::BitAnd(a, b) => self.binary(a, b, |a, b| a & b),
+            NodeKind::BitOr(a, b) => self.binary(a, b, |a, b| a | b),
+            NodeKind::BitXor(a, b) => self.binary(a, b, |a, b| a ^ b),
+            NodeKind::BitNot(x) => Ok(!self.eval(x)?),
+            NodeKind::Neg(x) => Ok(self.eval(x)?.wrapping_neg()),
+            NodeKind::Abs(x) => Ok(self.eval(x)?.saturating_abs()),
+            NodeKind::Min(a, b) => self.binary(a, b, std::cmp::min),
+            NodeKind::Max(a, b) => self.binary(a, b, std::cmp::max),
+            NodeKind::Rand => {
+                self.rand_state = self
+                    .rand_state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                let x = self.rand_state ^ (self.rand_state >> 31);
+                Ok(isize::try_from(x >> 1).unwrap_or(isize::MAX))
+            }
+            NodeKind::And(a, b) => Ok(isize::from(self.test(a)? && self.test(b)?)),
+            NodeKind::Or(a, b) => Ok(isize::from(self.test(a)? || self.test(b)?)),
+            NodeKind::Ternary(test, then, else_) | NodeKind::If2Expr(test, then, else_) => {
+                if self.test(test)? {
+                    self.eval(then)
+                } else {
+                    self.eval(else_)
+                }
+            }
+            NodeKind::Set(var, val) => {
+                let value = self.eval(val)?;
+                self.store(Self::target(var)?, value);
+                Ok(value)
+            }
+            NodeKind::AddAssign(var, val) => {
+                let name = Self::target(var)?;
+                let old = self.fetch(node, name)?;
+                let value = old.wrapping_add(self.eval(val)?);
+                self.store(name, value);
+                Ok(value)
+            }
+            NodeKind::Call(name) => self.call(node, name),
+            NodeKind::Ref(_) => error(node, "shared subtrees can't be interpreted"),
+            _ => error(node, "expression expected"),
+        }
+    }
+}
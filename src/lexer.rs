@@ -6,77 +6,409 @@
 
 #![warn(clippy::all, clippy::pedantic)]
 
+use std::collections::HashMap;
+
 /// The tokens are keywards, special characters, integer constants,
 /// and identifiers.  Strong types are really helpful here.  Note, in
 /// contrast to typical C implementations, the integer value and the
 /// identifier string is strongly tied to the corresponding token.
 #[derive(Debug, Default)]
 pub enum Token {
+    AbsSym,
+    BreakSym,
+    CaseSym,
+    ContinueSym,
+    DefaultSym,
     DoSym,
     ElseSym,
+    FnSym,
+    ForSym,
     IfSym,
+    IntSym,
+    MaxSym,
+    MinSym,
+    PrintSym,
+    RandSym,
+    ReadSym,
+    SwitchSym,
     WhileSym,
     Lbra,
     Rbra,
     Lpar,
     Rpar,
     Plus,
+    PlusPlus,
+    PlusEq,
     Minus,
+    MinusMinus,
+    Star,
+    Slash,
+    Percent,
     Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Shl,
+    Shr,
     Semi,
+    Question,
+    Colon,
+    Comma,
     Equal,
+    EqEq,
+    NotEq,
+    AmpAmp,
+    PipePipe,
+    Amp,
+    Pipe,
+    Caret,
+    Tilde,
+    Underscore,
     Int(isize),
-    Id(String),
+    Id(InternedStr),
     #[default]
     Eoi,
 }
 
+impl Token {
+    /// The name of the token for error messages: the text of a
+    /// keyword or a special character, quoted like `` `this' ``, or what
+    /// kind of token it is
+    #[must_use]
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Token::AbsSym => "`abs'",
+            Token::BreakSym => "`break'",
+            Token::CaseSym => "`case'",
+            Token::ContinueSym => "`continue'",
+            Token::DefaultSym => "`default'",
+            Token::DoSym => "`do'",
+            Token::ElseSym => "`else'",
+            Token::FnSym => "`fn'",
+            Token::ForSym => "`for'",
+            Token::IfSym => "`if'",
+            Token::IntSym => "`int'",
+            Token::MaxSym => "`max'",
+            Token::MinSym => "`min'",
+            Token::PrintSym => "`print'",
+            Token::RandSym => "`rand'",
+            Token::ReadSym => "`read'",
+            Token::SwitchSym => "`switch'",
+            Token::WhileSym => "`while'",
+            Token::Lbra => "`{'",
+            Token::Rbra => "`}'",
+            Token::Lpar => "`('",
+            Token::Rpar => "`)'",
+            Token::Plus => "`+'",
+            Token::PlusPlus => "`++'",
+            Token::PlusEq => "`+='",
+            Token::Minus => "`-'",
+            Token::MinusMinus => "`--'",
+            Token::Star => "`*'",
+            Token::Slash => "`/'",
+            Token::Percent => "`%'",
+            Token::Less => "`<'",
+            Token::LessEq => "`<='",
+            Token::Greater => "`>'",
+            Token::GreaterEq => "`>='",
+            Token::Shl => "`<<'",
+            Token::Shr => "`>>'",
+            Token::Semi => "`;'",
+            Token::Question => "`?'",
+            Token::Colon => "`:'",
+            Token::Comma => "`,'",
+            Token::Equal => "`='",
+            Token::EqEq => "`=='",
+            Token::NotEq => "`!='",
+            Token::AmpAmp => "`&&'",
+            Token::PipePipe => "`||'",
+            Token::Amp => "`&'",
+            Token::Pipe => "`|'",
+            Token::Caret => "`^'",
+            Token::Tilde => "`~'",
+            Token::Underscore => "`_'",
+            Token::Int(_) => "integer literal",
+            Token::Id(_) => "identifier",
+            Token::Eoi => "end of input",
+        }
+    }
+}
+
+/// An identifier as kept by an `Interner`.  It's just an index, so
+/// it's cheap to copy and compare, and `Interner::resolve` gives the
+/// name back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InternedStr(usize);
+
+/// Keeps a single copy of each identifier, so a name that's used many
+/// times is only allocated the first time
+#[derive(Clone, Debug, Default)]
+pub struct Interner {
+    arena: Vec<String>,
+    map: HashMap<String, usize>,
+}
+
+impl Interner {
+    #[must_use]
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    /// The `InternedStr` for `s`, adding it if it's new
+    pub fn intern(&mut self, s: &str) -> InternedStr {
+        if let Some(&i) = self.map.get(s) {
+            return InternedStr(i);
+        }
+        let i = self.arena.len();
+        self.arena.push(s.to_string());
+        self.map.insert(s.to_string(), i);
+        InternedStr(i)
+    }
+
+    /// The name that `id` stands for
+    ///
+    /// # Panics
+    /// Panics if `id` is from another `Interner`
+    #[must_use]
+    pub fn resolve(&self, id: InternedStr) -> &str {
+        &self.arena[id.0]
+    }
+
+    /// The number of different names interned
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.arena.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.arena.is_empty()
+    }
+}
+
 /// Source code position for syntax error reporting.  Both are 1-based
-/// (ie. the starting position is (1,1).  Note, the implementation
-/// below only accepts spaces and tabs as whitespace.  No tabs nor
-/// comments.
+/// (ie. the starting position is (1,1).  Tabs advance the column to
+/// the next tab stop and a carriage return doesn't move it at all.
 #[derive(Clone, Copy, Default, Debug)]
 pub struct SourcePosition {
     line: usize,
     col: usize,
 }
 
+impl SourcePosition {
+    #[must_use]
+    pub fn new(line: usize, col: usize) -> Self {
+        SourcePosition { line, col }
+    }
+
+    #[must_use]
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    #[must_use]
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    /// The position after `ch`, with tab stops every
+    /// `DEFAULT_TAB_WIDTH` columns
+    #[must_use]
+    pub fn advance(self, ch: char) -> Self {
+        self.advance_with_tab_width(ch, DEFAULT_TAB_WIDTH)
+    }
+
+    /// The position after `ch`, with tab stops every `tab_width`
+    /// columns.  A newline starts the next line, and a carriage
+    /// return doesn't move at all, being part of a "\r\n" line
+    /// ending where the '\n' does the work.
+    ///
+    /// # Panics
+    /// Panics if `tab_width` is 0
+    #[must_use]
+    pub fn advance_with_tab_width(self, ch: char, tab_width: usize) -> Self {
+        assert!(tab_width > 0, "a tab width of 0");
+        let SourcePosition { line, col } = self;
+        match ch {
+            '\n' => SourcePosition {
+                line: line + 1,
+                col: 1,
+            },
+            '\t' => SourcePosition {
+                line,
+                col: (col - 1) / tab_width * tab_width + tab_width + 1,
+            },
+            '\r' => self,
+            _ => SourcePosition { line, col: col + 1 },
+        }
+    }
+}
+
+/// The distance between tab stops unless told otherwise
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// A position is shown as `line:col`, as in error messages
+impl std::fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// The stretch of source code a construct was parsed from, from the
+/// `start` of its first token to the `end` of its last (exclusive)
+#[derive(Clone, Copy, Default)]
+pub struct Span {
+    pub start: SourcePosition,
+    pub end: SourcePosition,
+}
+
+impl std::fmt::Debug for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// A lexical error with the position where it was detected, and the
+/// line of the source it is on
+#[derive(Debug)]
+pub struct LexError {
+    pub pos: SourcePosition,
+    pub message: String,
+    pub source_line: String,
+}
+
+/// An error is shown as `input:line:col:message`, and with the `#`
+/// flag, followed by its line of the source with a `^` under the
+/// column
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "input:{}:{}", self.pos, self.message)?;
+        if f.alternate() {
+            highlight(f, self.pos, &self.source_line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write `line` of the source, and a `^` under the column of `pos`,
+/// each on a line of its own, indented.  The column counts tabs to
+/// the next tab stop, so a line with tabs only lines up if they're
+/// shown the same way.  Nothing is written without the line.
+pub(crate) fn highlight(
+    f: &mut std::fmt::Formatter<'_>,
+    pos: SourcePosition,
+    line: &str,
+) -> std::fmt::Result {
+    if line.is_empty() {
+        return Ok(());
+    }
+    write!(f, "\n  {line}\n  {:>1$}", "^", pos.col)
+}
+
+impl std::error::Error for LexError {}
+
 /// The `Lexer` is initialized with the source code string and
 /// tokenizes it `get_token()`.
+#[derive(Clone)]
 pub struct Lexer<'a> {
+    /// The source code, for the lines of the errors
+    src: &'a str,
+
     /// The peekable iterator that gives us chars from the source
     itr: std::iter::Peekable<std::str::Chars<'a>>,
 
     /// The source code position of the peekable character
     pos: SourcePosition,
+
+    /// The distance between tab stops, for column accounting
+    tab_width: usize,
+
+    /// The identifiers seen, and the one being read
+    interner: Interner,
+    id_name: String,
 }
 
 impl<'a> Lexer<'a> {
     #[must_use]
     pub fn new(src: &'a str) -> Lexer<'a> {
+        Self::with_tab_width(src, DEFAULT_TAB_WIDTH)
+    }
+
+    /// Like `new`, but with tab stops every `tab_width` columns
+    ///
+    /// # Panics
+    /// Panics if `tab_width` is 0
+    #[must_use]
+    pub fn with_tab_width(src: &'a str, tab_width: usize) -> Lexer<'a> {
+        assert!(tab_width > 0, "a tab width of 0");
         Self {
+            src,
             itr: src.chars().peekable(),
             pos: SourcePosition { line: 1, col: 1 },
+            tab_width,
+            interner: Interner::new(),
+            id_name: String::new(),
         }
     }
 
-    /// Report a error message in the context of the current lexer
-    /// position and terminate
-    pub fn syntax_error(&mut self, pos: SourcePosition, msg: &str) -> ! {
-        eprintln!("input:{}:{}:{msg}", pos.line, pos.col);
-        // Proper error handling is out of scope for now.
-        std::process::exit(1);
+    /// Turn the lexer into an iterator over the remaining tokens,
+    /// which ends before `Token::Eoi`
+    ///
+    /// ```
+    /// use tinyc_in_rust::lexer::Lexer;
+    /// let tokens = Lexer::new("a = 42;").into_tokens().collect::<Result<Vec<_>, _>>();
+    /// assert_eq!(tokens.unwrap().len(), 4);
+    /// ```
+    #[must_use]
+    pub fn into_tokens(self) -> Tokens<'a> {
+        Tokens {
+            lex: self,
+            done: false,
+        }
+    }
+
+    /// The identifiers of the `Token::Id`s returned so far
+    #[must_use]
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+
+    /// The position of the next character to be read, that is, just
+    /// past the last token returned by `get_token()`
+    #[must_use]
+    pub fn position(&self) -> SourcePosition {
+        self.pos
+    }
+
+    /// Report a error message in the context of the given source
+    /// position.  This always fails, but the `Result` type lets the
+    /// caller simply `return` it.
+    ///
+    /// # Errors
+    /// Always returns the `LexError`
+    pub fn syntax_error<T>(&self, pos: SourcePosition, msg: &str) -> Result<T, LexError> {
+        Err(LexError {
+            pos,
+            message: msg.to_string(),
+            source_line: self.source_line(pos.line).to_string(),
+        })
+    }
+
+    /// The `line`th line of the source, counting from 1, without its
+    /// line ending, or "" if there's no such line
+    #[must_use]
+    pub fn source_line(&self, line: usize) -> &'a str {
+        self.src
+            .lines()
+            .nth(line.wrapping_sub(1))
+            .unwrap_or_default()
     }
 
     /// Consumes the current character and advances to the next,
     /// updating the current position in the process
     fn next_ch(&mut self) {
+        self.pos = self.pos.advance_with_tab_width(self.ch(), self.tab_width);
         self.itr.next();
-        self.pos.col += 1;
-        if self.ch() == '\n' {
-            self.pos.line += 1;
-            self.pos.col = 1;
-        }
     }
 
     /// Convenient access to the current position.  We turn
@@ -88,12 +420,51 @@ impl<'a> Lexer<'a> {
         *self.itr.peek().unwrap_or(&'\0')
     }
 
+    /// Skips the whitespace and comments up to the next token.  This
+    /// is a loop rather than a recursive call per comment, so that any
+    /// number of them in a row doesn't run out of stack.
+    fn skip_space(&mut self) -> Result<(), LexError> {
+        loop {
+            let pos = self.pos;
+            match (self.ch(), self.itr.clone().nth(1)) {
+                (' ' | '\t' | '\r' | '\n', _) => self.next_ch(),
+                ('/', Some('/')) => {
+                    // A `//` comment runs to the end of the line
+                    while self.ch() != '\n' && self.ch() != '\0' {
+                        self.next_ch();
+                    }
+                }
+                ('/', Some('*')) => {
+                    // A `/* */` comment, which, as in C, doesn't nest
+                    self.next_ch();
+                    self.next_ch();
+                    loop {
+                        match self.ch() {
+                            '\0' => return self.syntax_error(pos, "unterminated comment"),
+                            '*' => {
+                                self.next_ch();
+                                if self.ch() == '/' {
+                                    self.next_ch();
+                                    break;
+                                }
+                            }
+                            _ => self.next_ch(),
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
     /// Parses the next `Token` and populates `self.sym` with it.
     /// `Token::Eoi` is represents the end of the source code.
-    pub fn get_token(&mut self) -> (SourcePosition, Token) {
-        while self.ch() == ' ' || self.ch() == '\n' {
-            self.next_ch();
-        }
+    ///
+    /// # Errors
+    /// Returns a `LexError` on illegal input
+    #[allow(clippy::too_many_lines)]
+    pub fn get_token(&mut self) -> Result<(SourcePosition, Token), LexError> {
+        self.skip_space()?;
 
         let pos: SourcePosition = self.pos;
         let token = match self.ch() {
@@ -102,51 +473,191 @@ impl<'a> Lexer<'a> {
             '}' => Token::Rbra,
             '(' => Token::Lpar,
             ')' => Token::Rpar,
-            '+' => Token::Plus,
-            '-' => Token::Minus,
-            '<' => Token::Less,
+            '+' => {
+                self.next_ch();
+                match self.ch() {
+                    '+' => Token::PlusPlus,
+                    '=' => Token::PlusEq,
+                    _ => return Ok((pos, Token::Plus)),
+                }
+            }
+            '-' => {
+                self.next_ch();
+                if self.ch() != '-' {
+                    return Ok((pos, Token::Minus));
+                }
+                Token::MinusMinus
+            }
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '<' => {
+                self.next_ch();
+                match self.ch() {
+                    '=' => Token::LessEq,
+                    '<' => Token::Shl,
+                    _ => return Ok((pos, Token::Less)),
+                }
+            }
+            '>' => {
+                self.next_ch();
+                match self.ch() {
+                    '=' => Token::GreaterEq,
+                    '>' => Token::Shr,
+                    _ => return Ok((pos, Token::Greater)),
+                }
+            }
             ';' => Token::Semi,
-            '=' => Token::Equal,
+            '?' => Token::Question,
+            ':' => Token::Colon,
+            ',' => Token::Comma,
+            '=' => {
+                self.next_ch();
+                if self.ch() != '=' {
+                    return Ok((pos, Token::Equal));
+                }
+                Token::EqEq
+            }
+            '&' => {
+                self.next_ch();
+                if self.ch() != '&' {
+                    return Ok((pos, Token::Amp));
+                }
+                Token::AmpAmp
+            }
+            '|' => {
+                self.next_ch();
+                if self.ch() != '|' {
+                    return Ok((pos, Token::Pipe));
+                }
+                Token::PipePipe
+            }
+            '^' => Token::Caret,
+            '~' => Token::Tilde,
+            '_' => Token::Underscore,
+            '!' => {
+                self.next_ch();
+                if self.ch() != '=' {
+                    return self.syntax_error(pos, "`!=' expected");
+                }
+                Token::NotEq
+            }
 
             '0'..='9' => {
-                let mut int_val = 0;
+                let mut int_val: isize = 0;
+                if self.ch() == '0' {
+                    self.next_ch();
+                    if matches!(self.ch(), 'x' | 'X') {
+                        self.next_ch();
+                        let mut digits = String::new();
+                        while self.ch().is_ascii_hexdigit() {
+                            digits.push(self.ch());
+                            self.next_ch();
+                        }
+                        let Ok(int_val) = isize::from_str_radix(&digits, 16) else {
+                            return self.syntax_error(pos, "Illegal hexadecimal constant");
+                        };
+                        return Ok((pos, Token::Int(int_val)));
+                    }
+                    if self.ch().is_ascii_digit() {
+                        // As in C, a leading zero means octal
+                        let mut digits = String::new();
+                        while self.ch().is_ascii_digit() {
+                            digits.push(self.ch());
+                            self.next_ch();
+                        }
+                        let Ok(int_val) = isize::from_str_radix(&digits, 8) else {
+                            return self.syntax_error(pos, "Illegal octal constant");
+                        };
+                        return Ok((pos, Token::Int(int_val)));
+                    }
+                }
                 while '0' <= self.ch() && self.ch() <= '9' {
-                    int_val = int_val * 10 + self.ch() as isize - '0' as isize;
+                    let digit = self.ch() as isize - '0' as isize;
+                    let Some(val) = int_val.checked_mul(10).and_then(|v| v.checked_add(digit))
+                    else {
+                        return self.syntax_error(pos, "Illegal decimal constant");
+                    };
+                    int_val = val;
                     self.next_ch();
                 }
 
                 // As we have already advanced past the current we
                 // return to skip the next_ch() below.
-                return (pos, Token::Int(int_val));
+                return Ok((pos, Token::Int(int_val)));
             }
 
             'a'..='z' => {
-                let mut id_name = String::new();
+                self.id_name.clear();
                 while 'a' <= self.ch() && self.ch() <= 'z' || self.ch() == '_' {
-                    id_name.push(self.ch());
+                    let ch = self.ch();
+                    self.id_name.push(ch);
                     self.next_ch();
                 }
 
                 // Note, a more conventional approach would use a hash
                 // table for the symbol table and store the keywords
                 // there along with source code symbols.
-                return (
+                return Ok((
                     pos,
-                    match id_name.as_str() {
+                    match self.id_name.as_str() {
+                        "abs" => Token::AbsSym,
+                        "break" => Token::BreakSym,
+                        "case" => Token::CaseSym,
+                        "continue" => Token::ContinueSym,
+                        "default" => Token::DefaultSym,
                         "do" => Token::DoSym,
                         "else" => Token::ElseSym,
+                        "fn" => Token::FnSym,
+                        "for" => Token::ForSym,
                         "if" => Token::IfSym,
+                        "int" => Token::IntSym,
+                        "max" => Token::MaxSym,
+                        "min" => Token::MinSym,
+                        "print" => Token::PrintSym,
+                        "rand" => Token::RandSym,
+                        "read" => Token::ReadSym,
+                        "switch" => Token::SwitchSym,
                         "while" => Token::WhileSym,
-                        _ => Token::Id(id_name),
+                        _ => Token::Id(self.interner.intern(&self.id_name)),
                     },
-                );
+                ));
             }
 
-            _ => self.syntax_error(pos, "Illegal token"),
+            _ => return self.syntax_error(pos, "Illegal token"),
         };
 
         self.next_ch();
 
-        (pos, token)
+        Ok((pos, token))
+    }
+}
+
+/// The iterator returned by `Lexer::into_tokens()`.  It yields each
+/// token with its position, and ends at the end of the input or after
+/// the first `LexError`.
+pub struct Tokens<'a> {
+    lex: Lexer<'a>,
+    done: bool,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<(SourcePosition, Token), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.lex.get_token() {
+            Ok((_, Token::Eoi)) => {
+                self.done = true;
+                None
+            }
+            Ok(token) => Some(Ok(token)),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
     }
 }
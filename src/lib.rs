@@ -3,32 +3,71 @@
 //! This is a Rust translation from Marc Feeley's C version.  I've
 //! tried to thread a careful balance between keeping it close to the
 //! original while also make it reasonably idomatic clean Rust code.
+//!
+//! The simplest way to run a program is `compile_and_run_with_globals`,
+//! eg. Euclid's algorithm on 125 and 100:
+//!
+//! ```
+//! use tinyc_in_rust::compile_and_run_with_globals;
+//! let gcd = "while (i - j) if (i < j) j = j - i; else i = i - j;";
+//! let globals = compile_and_run_with_globals(gcd, &[("i", 125), ("j", 100)]).unwrap();
+//! assert_eq!(globals, [("i".to_string(), 25), ("j".to_string(), 25)]);
+//! ```
 
 #![warn(clippy::all, clippy::pedantic)]
 
 // This is a compiler for the Tiny-C language.  Tiny-C is a
 // considerably stripped down version of C and it is meant as a
-// pedagogical tool for learning about compilers.  The integer global
-// variables "a" to "z" are predefined and initialized to zero, and it
-// is not possible to declare new variables.  The compiler reads the
-// program from standard input and prints out the value of the
-// variables that are not zero.  The grammar of Tiny-C in EBNF is:
+// pedagogical tool for learning about compilers.  The variables are
+// integers, initialized to zero.  They are globals, created by naming
+// them, unless a block declares them with `int x;`, making a local
+// that shadows any global of the same name until the end of the
+// block.  The compiler reads the program from standard input and
+// prints out the value of the globals that are not zero.  The grammar
+// of Tiny-C in EBNF is:
 //
-//  <program> ::= <statement>
+//  <program> ::= { <function> } <statement> { <function> }
+//  <function> ::= "fn" <name> "(" ")" <statement>
 //  <statement> ::= "if" <paren_expr> <statement> |
 //                  "if" <paren_expr> <statement> "else" <statement> |
 //                  "while" <paren_expr> <statement> |
 //                  "do" <statement> "while" <paren_expr> ";" |
-//                  "{" { <statement> } "}" |
+//                  "for" "(" <expr> ";" <expr> ";" <expr> ")" <statement> |
+//                  "switch" <paren_expr> "{" { "case" <int> ":" <statement> }
+//                      [ "default" ":" <statement> ] "}" |
+//                  "print" <expr> ";" |
+//                  "read" <id> ";" |
+//                  "break" ";" |
+//                  "continue" ";" |
+//                  "{" { <statement> | <declaration> } "}" |
 //                  <expr> ";" |
 //                  ";"
+//  <declaration> ::= "int" <id> ";"
 //  <paren_expr> ::= "(" <expr> ")"
-//  <expr> ::= <test> | <id> "=" <expr>
-//  <test> ::= <sum> | <sum> "<" <sum>
-//  <sum> ::= <term> | <sum> "+" <term> | <sum> "-" <term>
-//  <term> ::= <id> | <int> | <paren_expr>
-//  <id> ::= "a" | "b" | "c" | "d" | ... | "z"
-//  <int> ::= <an_unsigned_decimal_integer>
+//  <expr> ::= <ternary> | <id> "=" <expr> | <id> "+=" <expr> |
+//             "_" "=" <expr>
+//  <ternary> ::= <or_test> | <or_test> "?" <expr> ":" <ternary>
+//  <or_test> ::= <and_test> | <or_test> "||" <and_test>
+//  <and_test> ::= <bit_or> | <and_test> "&&" <bit_or>
+//  <bit_or> ::= <bit_xor> | <bit_or> "|" <bit_xor>
+//  <bit_xor> ::= <bit_and> | <bit_xor> "^" <bit_and>
+//  <bit_and> ::= <test> | <bit_and> "&" <test>
+//  <test> ::= <shift> | <shift> <relop> <shift>
+//  <relop> ::= "<" | ">" | "<=" | ">=" | "==" | "!="
+//  <shift> ::= <sum> | <shift> "<<" <sum> | <shift> ">>" <sum>
+//  <sum> ::= <product> | <sum> "+" <product> | <sum> "-" <product>
+//  <product> ::= <term> | <product> "*" <term> |
+//                <product> "/" <term> | <product> "%" <term>
+//  <term> ::= "-" <term> | "~" <term> | "++" <id> | "--" <id> | <id> | <int> |
+//             <paren_expr> | "(" "if" <paren_expr> <term> <term> ")" |
+//             <name> "(" ")" | "abs" <paren_expr> |
+//             "min" "(" <expr> "," <expr> ")" | "max" "(" <expr> "," <expr> ")" |
+//             "rand" "(" ")"
+//  <id> ::= <name>
+//  <name> ::= <a_word_of_lowercase_letters_and_underscores>
+//  <int> ::= <an_unsigned_decimal_integer> |
+//            "0x" <an_unsigned_hexadecimal_integer> |
+//            "0" <an_unsigned_octal_integer>
 //
 //
 // Here are a few invocations of the compiler:
@@ -54,20 +93,326 @@
 // highlight the structure of the compiler.
 //
 
+pub mod analysis;
+pub mod arena;
+pub mod bytecode;
 pub mod codegen;
+pub mod desugar;
+pub mod interpreter;
 pub mod lexer;
+pub mod optimizer;
 pub mod parser;
+mod pretty;
+pub mod sexp;
+pub mod symbols;
+pub mod visualize;
 pub mod vm;
 
 #[cfg(test)]
 mod tests;
 
-pub fn compile_and_run(vm: &mut vm::VM, src: &str) {
-    vm.run(codegen::compile(parser::parse(src)));
+use std::path::{Path, PathBuf};
+
+/// The ways compiling and running a program can fail, in the order
+/// of the stages that find them
+#[derive(Debug)]
+pub enum CompileError {
+    /// The source has something that isn't a token
+    Lex(lexer::LexError),
+    /// The tokens don't make a program
+    Parse(parser::ParseError),
+    /// The program parsed but can't be compiled, as it calls a
+    /// function that isn't defined, or has a `break` or `continue`
+    /// outside of a loop
+    Codegen(codegen::CodegenError),
+    /// The program failed while it ran
+    Runtime(vm::VmError),
+    /// The program failed while the interpreter ran it
+    Interpret(interpreter::RuntimeError),
+
+    /// The source file couldn't be read
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+impl From<lexer::LexError> for CompileError {
+    fn from(e: lexer::LexError) -> Self {
+        CompileError::Lex(e)
+    }
+}
+
+/// A syntax error that is really a lexical one is reported as such
+impl From<parser::ParseError> for CompileError {
+    fn from(mut e: parser::ParseError) -> Self {
+        match e.cause.take() {
+            Some(cause) => CompileError::Lex(*cause),
+            None => CompileError::Parse(e),
+        }
+    }
+}
+
+impl From<codegen::CodegenError> for CompileError {
+    fn from(e: codegen::CodegenError) -> Self {
+        CompileError::Codegen(e)
+    }
+}
 
-    for i in 0u8..26 {
-        if vm.globals[i as usize] != 0 {
-            println!("{} = {}", (i + 97) as char, vm.globals[i as usize]);
+impl From<interpreter::RuntimeError> for CompileError {
+    fn from(e: interpreter::RuntimeError) -> Self {
+        CompileError::Interpret(e)
+    }
+}
+
+impl From<vm::VmError> for CompileError {
+    fn from(e: vm::VmError) -> Self {
+        CompileError::Runtime(e)
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // With the `#` flag passed on for the line of the source
+            CompileError::Lex(e) => e.fmt(f),
+            CompileError::Parse(e) => e.fmt(f),
+            CompileError::Codegen(e) => write!(f, "{e}"),
+            CompileError::Runtime(e) => write!(f, "runtime error: {e}"),
+            CompileError::Interpret(e) => write!(f, "{e}"),
+            CompileError::Io { path, error } => {
+                write!(f, "cannot read {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Lex(e) => Some(e),
+            CompileError::Parse(e) => Some(e),
+            CompileError::Codegen(e) => Some(e),
+            CompileError::Runtime(e) => Some(e),
+            CompileError::Interpret(e) => Some(e),
+            CompileError::Io { error, .. } => Some(error),
         }
     }
 }
+
+/// Compile `src`, run it on `vm`, and print the globals that aren't
+/// zero, as `name = value`, in the order of their slots.  The
+/// variables assigned but never read are warned about on standard
+/// error, unless `vm.disable_unused_warnings()` was called.  This is
+/// the main entry point, as used by the REPL.
+///
+/// The globals start as `vm` has them, so a program run after another
+/// sees what the first left behind.  An empty program, `;`, prints
+/// nothing unless earlier runs left something.
+///
+/// ```
+/// use tinyc_in_rust::{compile_and_run, vm::VM, CompileError};
+/// let mut vm = VM::new();
+/// compile_and_run(&mut vm, "{ i=1; while (i<100) i=i+i; }").unwrap(); // prints i = 128
+/// compile_and_run(&mut vm, "j = i / 2;").unwrap(); // prints i = 128 and j = 64
+///
+/// let error = |src| compile_and_run(&mut VM::new(), src).unwrap_err();
+/// assert!(matches!(error("a = 1 $ 2;"), CompileError::Lex(_)));
+/// assert!(matches!(error("a = ;"), CompileError::Parse(_)));
+/// assert!(matches!(error("a = f();"), CompileError::Codegen(_)));
+/// assert!(matches!(error("a = 1 / b;"), CompileError::Runtime(_)));
+/// assert_eq!(error("a = f();").to_string(), "input:1:5:undefined function `f'");
+/// ```
+///
+/// # Errors
+/// Returns a `CompileError` from the first stage that fails: `Lex`
+/// or `Parse` if `src` isn't a valid program, `Codegen` if it can't be
+/// compiled, or `Runtime` if it fails when run.  Nothing is printed
+/// then, but a program that fails at runtime may have changed the
+/// globals of `vm` before it did.
+pub fn compile_and_run(vm: &mut vm::VM, src: &str) -> Result<(), CompileError> {
+    let arena = parser::NodeArena::new();
+    let ast = parser::parse(&arena, src)?;
+    if vm.warns_unused() {
+        warn_unused(ast);
+    }
+    let code = compile_optimized(&arena, ast, &mut vm.symbols)?;
+    vm.run(code)?;
+    let _ = vm.print_nonzero_globals(&mut std::io::stdout());
+    Ok(())
+}
+
+/// Warn on standard error about each variable `ast` assigns but never
+/// reads after its last assignment.  Use `analysis::unused_writes` to get their names instead.
+pub fn warn_unused(ast: &parser::Node) {
+    for name in analysis::unused_writes(ast) {
+        eprintln!("warning: `{name}' is assigned but never read");
+    }
+}
+
+/// Compile `src`, run it on `vm`, and return the globals that aren't
+/// zero as a JSON object, eg. `{"a":42}`
+///
+/// # Errors
+/// Returns a `CompileError` if the program doesn't parse or fails at
+/// runtime
+pub fn compile_and_run_json(vm: &mut vm::VM, src: &str) -> Result<String, CompileError> {
+    compile_and_run_returning(vm, src)?;
+    Ok(globals_json(vm, false))
+}
+
+/// The globals of `vm` as a JSON object, in the order of their slots,
+/// with `all` of them or only those that aren't zero.  The names need
+/// no escaping, being letters and underscores.
+#[must_use]
+pub fn globals_json(vm: &vm::VM, all: bool) -> String {
+    let members: Vec<String> = vm
+        .symbols
+        .names()
+        .into_iter()
+        .filter(|&(_, slot)| all || vm.globals[slot] != 0)
+        .map(|(name, slot)| format!("\"{name}\":{}", vm.globals[slot]))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// Read the Tiny-C program in the file `path`
+///
+/// # Errors
+/// Returns `CompileError::Io` if the file can't be read
+pub fn read_source(path: &Path) -> Result<String, CompileError> {
+    std::fs::read_to_string(path).map_err(|error| CompileError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+/// Compile the program in the file `path`, run it on `vm`, and print
+/// the globals that aren't zero, like `compile_and_run`
+///
+/// # Errors
+/// Returns a `CompileError` if the file can't be read, or the program
+/// doesn't parse or fails at runtime
+pub fn compile_file(path: &Path, vm: &mut vm::VM) -> Result<(), CompileError> {
+    compile_and_run(vm, &read_source(path)?)
+}
+
+/// Compile `src`, run it on `vm`, and return the globals afterwards,
+/// indexed by the slots in `vm.symbols`.  It warns about variables
+/// assigned but never read as `compile_and_run` does.
+///
+/// ```
+/// use tinyc_in_rust::{compile_and_run_returning, vm::VM};
+/// let mut vm = VM::new();
+/// let globals = compile_and_run_returning(&mut vm, "{ i=1; while (i<100) i=i+i; }").unwrap();
+/// assert_eq!(globals[8], 128);
+/// let globals = compile_and_run_returning(&mut vm, "count = 3;").unwrap();
+/// assert_eq!(globals[vm.symbols.lookup("count").unwrap()], 3);
+/// ```
+///
+/// # Errors
+/// Returns a `CompileError` if the program doesn't parse or fails at
+/// runtime
+pub fn compile_and_run_returning(vm: &mut vm::VM, src: &str) -> Result<Vec<isize>, CompileError> {
+    let arena = parser::NodeArena::new();
+    let ast = parser::parse(&arena, src)?;
+    if vm.warns_unused() {
+        warn_unused(ast);
+    }
+    let code = compile_optimized(&arena, ast, &mut vm.symbols)?;
+    vm.run(code)?;
+    Ok(vm.globals.clone())
+}
+
+/// Like `compile_and_run_returning`, but running `src` with the
+/// tree-walking interpreter rather than compiling it.  The globals
+/// get their slots from `vm.symbols`, as they would when compiled.
+///
+/// ```
+/// use tinyc_in_rust::{interpret_returning, vm::VM};
+/// let mut vm = VM::new();
+/// let globals = interpret_returning(&mut vm, "{ i=1; while (i<100) i=i+i; }").unwrap();
+/// assert_eq!(globals[8], 128);
+/// let globals = interpret_returning(&mut vm, "count = i / 2;").unwrap();
+/// assert_eq!(globals[vm.symbols.lookup("count").unwrap()], 64);
+/// ```
+///
+/// # Errors
+/// Returns a `CompileError` if `src` doesn't parse, or fails when it
+/// is run
+pub fn interpret_returning(vm: &mut vm::VM, src: &str) -> Result<Vec<isize>, CompileError> {
+    let arena = parser::NodeArena::new();
+    let ast = parser::parse(&arena, src)?;
+    if vm.warns_unused() {
+        warn_unused(ast);
+    }
+    // What the program did up to an error is kept, as on the VM
+    interpreter::interpret(ast, &mut vm.globals, &mut vm.symbols)?;
+    Ok(vm.globals.clone())
+}
+
+/// Compile and run `src` on a VM of its own, with the globals in `init`
+/// set first and the others zero, and return the names and values of
+/// the globals that aren't zero afterwards, in the order of their slots
+///
+/// ```
+/// use tinyc_in_rust::compile_and_run_with_globals;
+/// let fib = "{ m = n = 1; while (0 < k) { t = m; m = n; n = t + n; k = k - 1; } }";
+/// let globals = compile_and_run_with_globals(fib, &[("k", 10)]).unwrap();
+/// assert_eq!(
+///     globals,
+///     [("m".to_string(), 89), ("n".to_string(), 144), ("t".to_string(), 55)]
+/// );
+/// ```
+///
+/// # Errors
+/// Returns a `CompileError` if the program doesn't parse or fails at
+/// runtime
+///
+/// # Panics
+/// Panics if a name in `init` isn't a variable name
+pub fn compile_and_run_with_globals(
+    src: &str,
+    init: &[(&str, isize)],
+) -> Result<Vec<(String, isize)>, CompileError> {
+    let mut vm = vm::VM::from_globals(init);
+    compile_and_run_returning(&mut vm, src)?;
+    Ok(vm
+        .nonzero_globals()
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect())
+}
+
+/// Compile `src` and return the listing of the code, as made by
+/// `codegen::disassemble`, rather than run it
+///
+/// ```
+/// let asm = tinyc_in_rust::compile_to_asm("a = 6 * 7;").unwrap();
+/// assert_eq!(asm, "  0: PUSH 42\n  2: STORE a\n  4: POP\n  5: HALT\n");
+/// ```
+///
+/// # Errors
+/// Returns a `CompileError` if the program doesn't parse
+pub fn compile_to_asm(src: &str) -> Result<String, CompileError> {
+    let arena = parser::NodeArena::new();
+    let ast = parser::parse(&arena, src)?;
+    let code = compile_optimized(&arena, ast, &mut symbols::SymbolTable::new())?;
+    Ok(codegen::disassemble(&code))
+}
+
+/// Compile `ast` with all the optimizations, the rewritten trees going
+/// in `arena` and the variables getting their slots from `symbols`
+fn compile_optimized<'a>(
+    arena: &'a parser::NodeArena<'a>,
+    ast: parser::BNode<'a>,
+    symbols: &mut symbols::SymbolTable,
+) -> Result<Vec<codegen::Insn>, CompileError> {
+    codegen::check(ast)?;
+    let ast = desugar::desugar(arena, ast);
+    let ast = optimizer::fold_constants(arena, ast);
+    let ast = optimizer::algebraic_simplify(arena, ast);
+    let ast = optimizer::eliminate_dead_code(arena, ast);
+    let (code, _) = codegen::compile_with_symbols(ast, symbols);
+    Ok(codegen::peephole(code))
+}
@@ -0,0 +1,546 @@
+//! Optimizations on the abstract syntax tree, done after parsing and
+//! before code generation.
+//!
+//! Each pass returns the rewritten tree, with the new nodes allocated
+//! in the arena, and the subtrees left as they are shared with the
+//! tree it was given.  A rewritten node keeps the span of the node it
+//! replaces.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::parser::{AstVisitor, BNode, Node, NodeArena, NodeKind};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Evaluate the operators whose operands are all constants at compile
+/// time, and resolve the `if`s and conditional expressions whose test
+/// is constant.  Operations that would fail at runtime (division by
+/// zero, overflow) are left for the runtime to report.
+#[must_use]
+#[allow(clippy::too_many_lines)]
+pub fn fold_constants<'a>(arena: &'a NodeArena<'a>, node: BNode<'a>) -> BNode<'a> {
+    let fold = |node| fold_constants(arena, node);
+    let kind = match node.kind {
+        NodeKind::Add(a, b) => fold_binary(arena, NodeKind::Add, a, b, isize::checked_add),
+        NodeKind::Sub(a, b) => fold_binary(arena, NodeKind::Sub, a, b, isize::checked_sub),
+        NodeKind::Mul(a, b) => fold_binary(arena, NodeKind::Mul, a, b, isize::checked_mul),
+        NodeKind::Div(a, b) => fold_binary(arena, NodeKind::Div, a, b, isize::checked_div),
+        NodeKind::Mod(a, b) => fold_binary(arena, NodeKind::Mod, a, b, isize::checked_rem),
+        NodeKind::Shl(a, b) => fold_binary(arena, NodeKind::Shl, a, b, |a, b| {
+            a.checked_shl(u32::try_from(b).ok()?)
+        }),
+        NodeKind::Shr(a, b) => fold_binary(arena, NodeKind::Shr, a, b, |a, b| {
+            a.checked_shr(u32::try_from(b).ok()?)
+        }),
+        NodeKind::Lt(a, b) => {
+            fold_binary(arena, NodeKind::Lt, a, b, |a, b| Some(isize::from(a < b)))
+        }
+        NodeKind::Gt(a, b) => {
+            fold_binary(arena, NodeKind::Gt, a, b, |a, b| Some(isize::from(a > b)))
+        }
+        NodeKind::Le(a, b) => {
+            fold_binary(arena, NodeKind::Le, a, b, |a, b| Some(isize::from(a <= b)))
+        }
+        NodeKind::Ge(a, b) => {
+            fold_binary(arena, NodeKind::Ge, a, b, |a, b| Some(isize::from(a >= b)))
+        }
+        NodeKind::Eq(a, b) => {
+            fold_binary(arena, NodeKind::Eq, a, b, |a, b| Some(isize::from(a == b)))
+        }
+        NodeKind::Ne(a, b) => {
+            fold_binary(arena, NodeKind::Ne, a, b, |a, b| Some(isize::from(a != b)))
+        }
+        NodeKind::BitAnd(a, b) => fold_binary(arena, NodeKind::BitAnd, a, b, |a, b| Some(a & b)),
+        NodeKind::BitOr(a, b) => fold_binary(arena, NodeKind::BitOr, a, b, |a, b| Some(a | b)),
+        NodeKind::BitXor(a, b) => fold_binary(arena, NodeKind::BitXor, a, b, |a, b| Some(a ^ b)),
+        NodeKind::Min(a, b) => fold_binary(arena, NodeKind::Min, a, b, |a, b| Some(a.min(b))),
+        NodeKind::Max(a, b) => fold_binary(arena, NodeKind::Max, a, b, |a, b| Some(a.max(b))),
+        NodeKind::BitNot(x) => {
+            let x = fold(x);
+            match x.kind {
+                NodeKind::Cst(n) => NodeKind::Cst(!n),
+                _ => NodeKind::BitNot(x),
+            }
+        }
+        // Negating or taking `abs` of the most negative number
+        // overflows, so it's left for the VM to deal with
+        NodeKind::Neg(x) => {
+            let x = fold(x);
+            match x.kind {
+                NodeKind::Cst(n) if n != isize::MIN => NodeKind::Cst(-n),
+                _ => NodeKind::Neg(x),
+            }
+        }
+        NodeKind::Abs(x) => {
+            let x = fold(x);
+            // `abs(isize::MIN)` is `isize::MAX` or an overflow,
+            // depending on the VM, so it's left for the VM to decide
+            match x.kind {
+                NodeKind::Cst(n) if n != isize::MIN => NodeKind::Cst(n.abs()),
+                _ => NodeKind::Abs(x),
+            }
+        }
+        NodeKind::And(a, b) => fold_binary(arena, NodeKind::And, a, b, |a, b| {
+            Some(isize::from(a != 0 && b != 0))
+        }),
+        NodeKind::Or(a, b) => fold_binary(arena, NodeKind::Or, a, b, |a, b| {
+            Some(isize::from(a != 0 || b != 0))
+        }),
+        NodeKind::Ternary(test, then, else_) => {
+            let test = fold(test);
+            match test.kind {
+                NodeKind::Cst(n) => return fold(if n != 0 { then } else { else_ }),
+                _ => NodeKind::Ternary(test, fold(then), fold(else_)),
+            }
+        }
+        NodeKind::If2Expr(test, then, else_) => {
+            let test = fold(test);
+            match test.kind {
+                NodeKind::Cst(n) => return fold(if n != 0 { then } else { else_ }),
+                _ => NodeKind::If2Expr(test, fold(then), fold(else_)),
+            }
+        }
+        NodeKind::If1(test, then) => {
+            let test = fold(test);
+            match test.kind {
+                NodeKind::Cst(0) => NodeKind::Empty,
+                NodeKind::Cst(_) => return fold(then),
+                _ => NodeKind::If1(test, fold(then)),
+            }
+        }
+        NodeKind::If2(test, then, else_) => {
+            let test = fold(test);
+            match test.kind {
+                NodeKind::Cst(n) => return fold(if n != 0 { then } else { else_ }),
+                _ => NodeKind::If2(test, fold(then), fold(else_)),
+            }
+        }
+        NodeKind::Set(var, val) => NodeKind::Set(var, fold(val)),
+        NodeKind::AddAssign(var, val) => NodeKind::AddAssign(var, fold(val)),
+        NodeKind::While(test, body) => NodeKind::While(fold(test), fold(body)),
+        NodeKind::Do(body, test) => NodeKind::Do(fold(body), fold(test)),
+        NodeKind::For(init, test, step, body) => {
+            NodeKind::For(fold(init), fold(test), fold(step), fold(body))
+        }
+        NodeKind::Switch(value, ref cases, default) => NodeKind::Switch(
+            fold(value),
+            cases.iter().map(|&(n, body)| (n, fold(body))).collect(),
+            default.map(fold),
+        ),
+        NodeKind::Seq(a, b) => NodeKind::Seq(fold(a), fold(b)),
+        NodeKind::Block(ref stmts) => NodeKind::Block(stmts.iter().map(|&s| fold(s)).collect()),
+        NodeKind::Expr(x) => NodeKind::Expr(fold(x)),
+        NodeKind::Print(x) => NodeKind::Print(fold(x)),
+        NodeKind::FnDef(name, body) => NodeKind::FnDef(name, fold(body)),
+        NodeKind::Prog(body) => NodeKind::Prog(fold(body)),
+        NodeKind::Var(_)
+        | NodeKind::Cst(_)
+        | NodeKind::Read(_)
+        | NodeKind::Decl(_)
+        | NodeKind::Call(_)
+        | NodeKind::Rand
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Empty
+        | NodeKind::Ref(_) => return node,
+    };
+    arena.alloc(Node {
+        kind,
+        span: node.span,
+    })
+}
+
+/// Fold the operands of a binary operator, and the operator itself
+/// too if both operands are constants and `eval` has a value for them
+fn fold_binary<'a>(
+    arena: &'a NodeArena<'a>,
+    op: fn(BNode<'a>, BNode<'a>) -> NodeKind<'a>,
+    a: BNode<'a>,
+    b: BNode<'a>,
+    eval: fn(isize, isize) -> Option<isize>,
+) -> NodeKind<'a> {
+    let (a, b) = (fold_constants(arena, a), fold_constants(arena, b));
+    if let (NodeKind::Cst(x), NodeKind::Cst(y)) = (&a.kind, &b.kind) {
+        if let Some(v) = eval(*x, *y) {
+            return NodeKind::Cst(v);
+        }
+    }
+    op(a, b)
+}
+
+/// Rewrite the arithmetic with an identity element: `x + 0`, `0 + x`,
+/// `x - 0`, `x * 1`, and `x / 1` into `x`, and `x * 0` into `0`.  The
+/// last only if `x` has no side effects and can't fail.  Overflowing
+/// doesn't count as failing, as the arithmetic wraps around unless the
+/// VM is told to check for it with `enable_overflow_check`, and then
+/// the `x` that would have overflowed isn't computed at all.
+#[must_use]
+pub fn algebraic_simplify<'a>(arena: &'a NodeArena<'a>, node: BNode<'a>) -> BNode<'a> {
+    let simplify = |node| algebraic_simplify(arena, node);
+    let kind = match node.kind {
+        NodeKind::Add(a, b) => match (simplify(a), simplify(b)) {
+            (x, zero) | (zero, x) if matches!(zero.kind, NodeKind::Cst(0)) => x.kind.clone(),
+            (a, b) => NodeKind::Add(a, b),
+        },
+        NodeKind::Sub(a, b) => match (simplify(a), simplify(b)) {
+            (x, zero) if matches!(zero.kind, NodeKind::Cst(0)) => x.kind.clone(),
+            (a, b) => NodeKind::Sub(a, b),
+        },
+        NodeKind::Mul(a, b) => match (simplify(a), simplify(b)) {
+            (x, one) if matches!(one.kind, NodeKind::Cst(1)) => x.kind.clone(),
+            (x, zero) if matches!(zero.kind, NodeKind::Cst(0)) && x.is_pure() => NodeKind::Cst(0),
+            (a, b) => NodeKind::Mul(a, b),
+        },
+        NodeKind::Div(a, b) => match (simplify(a), simplify(b)) {
+            (x, one) if matches!(one.kind, NodeKind::Cst(1)) => x.kind.clone(),
+            (a, b) => NodeKind::Div(a, b),
+        },
+        NodeKind::Mod(a, b) => NodeKind::Mod(simplify(a), simplify(b)),
+        NodeKind::Shl(a, b) => NodeKind::Shl(simplify(a), simplify(b)),
+        NodeKind::Shr(a, b) => NodeKind::Shr(simplify(a), simplify(b)),
+        NodeKind::Lt(a, b) => NodeKind::Lt(simplify(a), simplify(b)),
+        NodeKind::Gt(a, b) => NodeKind::Gt(simplify(a), simplify(b)),
+        NodeKind::Le(a, b) => NodeKind::Le(simplify(a), simplify(b)),
+        NodeKind::Ge(a, b) => NodeKind::Ge(simplify(a), simplify(b)),
+        NodeKind::Eq(a, b) => NodeKind::Eq(simplify(a), simplify(b)),
+        NodeKind::Ne(a, b) => NodeKind::Ne(simplify(a), simplify(b)),
+        NodeKind::BitAnd(a, b) => NodeKind::BitAnd(simplify(a), simplify(b)),
+        NodeKind::BitOr(a, b) => NodeKind::BitOr(simplify(a), simplify(b)),
+        NodeKind::BitXor(a, b) => NodeKind::BitXor(simplify(a), simplify(b)),
+        NodeKind::Min(a, b) => NodeKind::Min(simplify(a), simplify(b)),
+        NodeKind::Max(a, b) => NodeKind::Max(simplify(a), simplify(b)),
+        NodeKind::BitNot(x) => NodeKind::BitNot(simplify(x)),
+        NodeKind::Neg(x) => NodeKind::Neg(simplify(x)),
+        NodeKind::Abs(x) => NodeKind::Abs(simplify(x)),
+        NodeKind::And(a, b) => NodeKind::And(simplify(a), simplify(b)),
+        NodeKind::Or(a, b) => NodeKind::Or(simplify(a), simplify(b)),
+        NodeKind::Ternary(a, b, c) => NodeKind::Ternary(simplify(a), simplify(b), simplify(c)),
+        NodeKind::If2Expr(a, b, c) => NodeKind::If2Expr(simplify(a), simplify(b), simplify(c)),
+        NodeKind::Set(var, val) => NodeKind::Set(var, simplify(val)),
+        NodeKind::AddAssign(var, val) => NodeKind::AddAssign(var, simplify(val)),
+        NodeKind::If1(test, then) => NodeKind::If1(simplify(test), simplify(then)),
+        NodeKind::If2(test, then, else_) => {
+            NodeKind::If2(simplify(test), simplify(then), simplify(else_))
+        }
+        NodeKind::While(test, body) => NodeKind::While(simplify(test), simplify(body)),
+        NodeKind::Do(body, test) => NodeKind::Do(simplify(body), simplify(test)),
+        NodeKind::For(init, test, step, body) => NodeKind::For(
+            simplify(init),
+            simplify(test),
+            simplify(step),
+            simplify(body),
+        ),
+        NodeKind::Switch(value, ref cases, default) => NodeKind::Switch(
+            simplify(value),
+            cases.iter().map(|&(n, body)| (n, simplify(body))).collect(),
+            default.map(simplify),
+        ),
+        NodeKind::Seq(a, b) => NodeKind::Seq(simplify(a), simplify(b)),
+        NodeKind::Block(ref stmts) => NodeKind::Block(stmts.iter().map(|&s| simplify(s)).collect()),
+        NodeKind::Expr(x) => NodeKind::Expr(simplify(x)),
+        NodeKind::Print(x) => NodeKind::Print(simplify(x)),
+        NodeKind::FnDef(name, body) => NodeKind::FnDef(name, simplify(body)),
+        NodeKind::Prog(body) => NodeKind::Prog(simplify(body)),
+        NodeKind::Var(_)
+        | NodeKind::Cst(_)
+        | NodeKind::Read(_)
+        | NodeKind::Decl(_)
+        | NodeKind::Call(_)
+        | NodeKind::Rand
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Empty
+        | NodeKind::Ref(_) => return node,
+    };
+    arena.alloc(Node {
+        kind,
+        span: node.span,
+    })
+}
+
+/// Remove the code that can't have any effect: empty statements in
+/// sequences, `if`s and `while`s whose test is constant false,
+/// expression statements that only fetch a variable or a constant,
+/// and the statements in a block after a `break` or `continue`
+#[must_use]
+pub fn eliminate_dead_code<'a>(arena: &'a NodeArena<'a>, node: BNode<'a>) -> BNode<'a> {
+    let eliminate = |node| eliminate_dead_code(arena, node);
+    let kind = match node.kind {
+        NodeKind::Seq(a, b) => {
+            let (a, b) = (eliminate(a), eliminate(b));
+            if matches!(b.kind, NodeKind::Empty) || a.jumps_away() {
+                a.kind.clone()
+            } else if matches!(a.kind, NodeKind::Empty) {
+                b.kind.clone()
+            } else {
+                NodeKind::Seq(a, b)
+            }
+        }
+        NodeKind::Block(ref stmts) => {
+            let mut live = Vec::with_capacity(stmts.len());
+            for &s in stmts {
+                let s = eliminate(s);
+                if matches!(s.kind, NodeKind::Empty) {
+                    continue;
+                }
+                let jumps_away = s.jumps_away();
+                live.push(s);
+                if jumps_away {
+                    break;
+                }
+            }
+            // A declaration left alone is kept in its block, as it is
+            // local to it
+            if live.len() > 1
+                || live
+                    .first()
+                    .is_some_and(|s| matches!(s.kind, NodeKind::Decl(_)))
+            {
+                NodeKind::Block(live)
+            } else {
+                live.pop().map_or(NodeKind::Empty, |s| s.kind.clone())
+            }
+        }
+        NodeKind::If1(test, _) | NodeKind::While(test, _)
+            if matches!(test.kind, NodeKind::Cst(0)) =>
+        {
+            NodeKind::Empty
+        }
+        NodeKind::Expr(x) if matches!(x.kind, NodeKind::Var(_) | NodeKind::Cst(_)) => {
+            NodeKind::Empty
+        }
+        NodeKind::If1(test, then) => NodeKind::If1(test, eliminate(then)),
+        NodeKind::If2(test, then, else_) => NodeKind::If2(test, eliminate(then), eliminate(else_)),
+        NodeKind::While(test, body) => NodeKind::While(test, eliminate(body)),
+        NodeKind::Do(body, test) => NodeKind::Do(eliminate(body), test),
+        NodeKind::For(init, test, step, body) => NodeKind::For(init, test, step, eliminate(body)),
+        NodeKind::Switch(value, ref cases, default) => NodeKind::Switch(
+            value,
+            cases
+                .iter()
+                .map(|&(n, body)| (n, eliminate(body)))
+                .collect(),
+            default.map(eliminate),
+        ),
+        NodeKind::FnDef(name, body) => NodeKind::FnDef(name, eliminate(body)),
+        NodeKind::Prog(body) => NodeKind::Prog(eliminate(body)),
+        _ => return node,
+    };
+    arena.alloc(Node {
+        kind,
+        span: node.span,
+    })
+}
+
+/// Share the subexpressions that appear more than once: each is kept
+/// once in the returned table, under its hash, and replaced by a
+/// `Ref` to it wherever it appears.  The subexpressions shared can
+/// themselves have `Ref`s in them.  Variables and constants are too
+/// small to be worth sharing.  Use `compile_with_refs` to compile the
+/// result.
+#[must_use]
+pub fn memoize_subexpressions<'a>(
+    arena: &'a NodeArena<'a>,
+    ast: BNode<'a>,
+) -> (BNode<'a>, HashMap<u64, BNode<'a>>) {
+    let mut counter = SubexpressionCounter::default();
+    counter.visit_node(ast);
+    let mut refs = HashMap::new();
+    let ast = share(arena, ast, &counter.0, &mut refs);
+    (ast, refs)
+}
+
+fn hash_of(node: &Node) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// How many times the subexpressions with each hash appear
+#[derive(Default)]
+struct SubexpressionCounter(HashMap<u64, usize>);
+
+impl AstVisitor for SubexpressionCounter {
+    fn visit_node(&mut self, node: &Node) {
+        if node.is_shareable() {
+            *self.0.entry(hash_of(node)).or_default() += 1;
+        }
+        crate::parser::walk_node(self, node);
+    }
+}
+
+/// Replace the subexpressions of `node` that appear more than once,
+/// going by `counts`, with `Ref`s into `refs`, the innermost first
+fn share<'a>(
+    arena: &'a NodeArena<'a>,
+    node: BNode<'a>,
+    counts: &HashMap<u64, usize>,
+    refs: &mut HashMap<u64, BNode<'a>>,
+) -> BNode<'a> {
+    let key = node.is_shareable().then(|| hash_of(node));
+    let kind = node.map_children(|child| share(arena, child, counts, refs));
+    let node = arena.alloc(Node {
+        kind,
+        span: node.span,
+    });
+    let Some(key) = key.filter(|key| counts[key] > 1) else {
+        return node;
+    };
+    let shared = arena.alloc(Node {
+        kind: NodeKind::Ref(key),
+        span: node.span,
+    });
+    match refs.entry(key) {
+        Entry::Vacant(entry) => {
+            entry.insert(node);
+            shared
+        }
+        // Unless the hashes of different trees collide
+        Entry::Occupied(entry) if **entry.get() == *node => shared,
+        Entry::Occupied(_) => node,
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Is this an operator expression, worth sharing if repeated?
+    fn is_shareable(&self) -> bool {
+        matches!(
+            self.kind,
+            NodeKind::Add(..)
+                | NodeKind::Sub(..)
+                | NodeKind::Mul(..)
+                | NodeKind::Div(..)
+                | NodeKind::Mod(..)
+                | NodeKind::Shl(..)
+                | NodeKind::Shr(..)
+                | NodeKind::Lt(..)
+                | NodeKind::Gt(..)
+                | NodeKind::Le(..)
+                | NodeKind::Ge(..)
+                | NodeKind::Eq(..)
+                | NodeKind::Ne(..)
+                | NodeKind::BitAnd(..)
+                | NodeKind::BitOr(..)
+                | NodeKind::BitXor(..)
+                | NodeKind::Min(..)
+                | NodeKind::Max(..)
+                | NodeKind::BitNot(..)
+                | NodeKind::Neg(..)
+                | NodeKind::Abs(..)
+                | NodeKind::And(..)
+                | NodeKind::Or(..)
+                | NodeKind::Ternary(..)
+                | NodeKind::If2Expr(..)
+        )
+    }
+
+    /// The kind of this node with `rewrite` applied to the subtrees
+    /// directly below it
+    pub(crate) fn map_children(&self, mut rewrite: impl FnMut(BNode<'a>) -> BNode<'a>) -> NodeKind<'a> {
+        match self.kind {
+            NodeKind::Add(a, b) => NodeKind::Add(rewrite(a), rewrite(b)),
+            NodeKind::Sub(a, b) => NodeKind::Sub(rewrite(a), rewrite(b)),
+            NodeKind::Mul(a, b) => NodeKind::Mul(rewrite(a), rewrite(b)),
+            NodeKind::Div(a, b) => NodeKind::Div(rewrite(a), rewrite(b)),
+            NodeKind::Mod(a, b) => NodeKind::Mod(rewrite(a), rewrite(b)),
+            NodeKind::Shl(a, b) => NodeKind::Shl(rewrite(a), rewrite(b)),
+            NodeKind::Shr(a, b) => NodeKind::Shr(rewrite(a), rewrite(b)),
+            NodeKind::Lt(a, b) => NodeKind::Lt(rewrite(a), rewrite(b)),
+            NodeKind::Gt(a, b) => NodeKind::Gt(rewrite(a), rewrite(b)),
+            NodeKind::Le(a, b) => NodeKind::Le(rewrite(a), rewrite(b)),
+            NodeKind::Ge(a, b) => NodeKind::Ge(rewrite(a), rewrite(b)),
+            NodeKind::Eq(a, b) => NodeKind::Eq(rewrite(a), rewrite(b)),
+            NodeKind::Ne(a, b) => NodeKind::Ne(rewrite(a), rewrite(b)),
+            NodeKind::BitAnd(a, b) => NodeKind::BitAnd(rewrite(a), rewrite(b)),
+            NodeKind::BitOr(a, b) => NodeKind::BitOr(rewrite(a), rewrite(b)),
+            NodeKind::BitXor(a, b) => NodeKind::BitXor(rewrite(a), rewrite(b)),
+            NodeKind::Min(a, b) => NodeKind::Min(rewrite(a), rewrite(b)),
+            NodeKind::Max(a, b) => NodeKind::Max(rewrite(a), rewrite(b)),
+            NodeKind::And(a, b) => NodeKind::And(rewrite(a), rewrite(b)),
+            NodeKind::Or(a, b) => NodeKind::Or(rewrite(a), rewrite(b)),
+            NodeKind::Set(a, b) => NodeKind::Set(rewrite(a), rewrite(b)),
+            NodeKind::AddAssign(a, b) => NodeKind::AddAssign(rewrite(a), rewrite(b)),
+            NodeKind::If1(a, b) => NodeKind::If1(rewrite(a), rewrite(b)),
+            NodeKind::While(a, b) => NodeKind::While(rewrite(a), rewrite(b)),
+            NodeKind::Do(a, b) => NodeKind::Do(rewrite(a), rewrite(b)),
+            NodeKind::Seq(a, b) => NodeKind::Seq(rewrite(a), rewrite(b)),
+            NodeKind::Ternary(a, b, c) => NodeKind::Ternary(rewrite(a), rewrite(b), rewrite(c)),
+            NodeKind::If2Expr(a, b, c) => NodeKind::If2Expr(rewrite(a), rewrite(b), rewrite(c)),
+            NodeKind::If2(a, b, c) => NodeKind::If2(rewrite(a), rewrite(b), rewrite(c)),
+            NodeKind::For(a, b, c, d) => {
+                NodeKind::For(rewrite(a), rewrite(b), rewrite(c), rewrite(d))
+            }
+            NodeKind::Switch(value, ref cases, default) => NodeKind::Switch(
+                rewrite(value),
+                cases.iter().map(|&(n, body)| (n, rewrite(body))).collect(),
+                default.map(rewrite),
+            ),
+            NodeKind::Block(ref stmts) => {
+                NodeKind::Block(stmts.iter().map(|&s| rewrite(s)).collect())
+            }
+            NodeKind::BitNot(x) => NodeKind::BitNot(rewrite(x)),
+            NodeKind::Neg(x) => NodeKind::Neg(rewrite(x)),
+            NodeKind::Abs(x) => NodeKind::Abs(rewrite(x)),
+            NodeKind::Print(x) => NodeKind::Print(rewrite(x)),
+            NodeKind::Expr(x) => NodeKind::Expr(rewrite(x)),
+            NodeKind::FnDef(name, x) => NodeKind::FnDef(name, rewrite(x)),
+            NodeKind::Prog(x) => NodeKind::Prog(rewrite(x)),
+            NodeKind::Var(_)
+            | NodeKind::Cst(_)
+            | NodeKind::Read(_)
+            | NodeKind::Decl(_)
+            | NodeKind::Call(_)
+            | NodeKind::Rand
+            | NodeKind::Break
+            | NodeKind::Continue
+            | NodeKind::Empty
+            | NodeKind::Ref(_) => self.kind.clone(),
+        }
+    }
+
+    /// Can the expression be left out without changing what the
+    /// program does?  Assignments and calls have side effects, and a
+    /// division could fail.  The arithmetic that could overflow is
+    /// taken to wrap around, so it's pure even though it would fail
+    /// when the VM checks for overflow.
+    fn is_pure(&self) -> bool {
+        match &self.kind {
+            NodeKind::Var(_) | NodeKind::Cst(_) => true,
+            NodeKind::Add(a, b)
+            | NodeKind::Sub(a, b)
+            | NodeKind::Mul(a, b)
+            | NodeKind::Shl(a, b)
+            | NodeKind::Shr(a, b)
+            | NodeKind::Lt(a, b)
+            | NodeKind::Gt(a, b)
+            | NodeKind::Le(a, b)
+            | NodeKind::Ge(a, b)
+            | NodeKind::Eq(a, b)
+            | NodeKind::Ne(a, b)
+            | NodeKind::BitAnd(a, b)
+            | NodeKind::BitOr(a, b)
+            | NodeKind::BitXor(a, b)
+            | NodeKind::Min(a, b)
+            | NodeKind::Max(a, b)
+            | NodeKind::And(a, b)
+            | NodeKind::Or(a, b) => a.is_pure() && b.is_pure(),
+            NodeKind::BitNot(x) | NodeKind::Neg(x) | NodeKind::Abs(x) => x.is_pure(),
+            NodeKind::Ternary(a, b, c) | NodeKind::If2Expr(a, b, c) => {
+                a.is_pure() && b.is_pure() && c.is_pure()
+            }
+            _ => false,
+        }
+    }
+
+    /// Does control never reach the end of the statement?
+    fn jumps_away(&self) -> bool {
+        match &self.kind {
+            NodeKind::Break | NodeKind::Continue => true,
+            NodeKind::Seq(_, b) => b.jumps_away(),
+            NodeKind::Block(stmts) => stmts.last().is_some_and(|s| s.jumps_away()),
+            NodeKind::If2(_, then, else_) => then.jumps_away() && else_.jumps_away(),
+            _ => false,
+        }
+    }
+}
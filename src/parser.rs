@@ -7,328 +7,2059 @@
 
 #![warn(clippy::all, clippy::pedantic)]
 
-use crate::lexer::{Lexer, SourcePosition, Token};
+use crate::arena::Arena;
+use crate::lexer::{highlight, InternedStr, LexError, Lexer, SourcePosition, Span, Token};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 
-/// To create recursive types in Rust, we heap allocate the recursive
-/// subparts, via the `Box` type.  To keep the `Node` type more
-/// readable we use an alias for the boxed node.
-pub type BNode = Box<Node>;
+/// To create recursive types in Rust, the recursive subparts must be
+/// behind a pointer.  Rather than heap allocating each node with a
+/// `Box`, the nodes are allocated in an `Arena` and refer to each
+/// other by reference, the lifetime `'a` being that of the arena.  To
+/// keep the `Node` type more readable we use an alias for the
+/// reference.
+pub type BNode<'a> = &'a Node<'a>;
 
-/// The `Node` is the abstract syntax tree.  This would normally be
-/// segregated into the syntatic categories like expression,
-/// statement, etc., but for this little example we just bundle
-/// everything, forgoing a bit of type safety for brevity.
-#[derive(Debug)]
-pub enum Node {
-    /// Contains the named variable.  Note, cloning the string is a
-    /// very expensive operation.  Better would be an index into the
-    /// lexers symbol table, or a tricky option, a string slice from
-    /// the original source (left as an exercise).
-    Var(String),
+/// The arenas a tree is allocated in: one for the nodes, and one for
+/// the names of the variables and functions, which the nodes refer
+/// to rather than each having a copy of their own
+#[derive(Default)]
+pub struct NodeArena<'a> {
+    nodes: Arena<Node<'a>>,
+    names: Arena<String>,
+}
+
+impl<'a> NodeArena<'a> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move `node` into the arena
+    pub fn alloc(&self, node: Node<'a>) -> &Node<'a> {
+        self.nodes.alloc(node)
+    }
+
+    /// A copy of `name` in the arena.  The parser only makes one for
+    /// each different name, however many times it is used.
+    pub fn name(&self, name: &str) -> &str {
+        self.names.alloc(name.to_string())
+    }
+
+    /// The number of nodes allocated
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Has no node been allocated yet?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The number of chunks the nodes are in
+    #[must_use]
+    pub fn chunks(&self) -> usize {
+        self.nodes.chunks()
+    }
+}
+
+/// The `Node` is the abstract syntax tree: what kind of construct it
+/// is, and the `span` of source code it was parsed from.  Cloning a
+/// `Node` only copies it, the nodes below it being shared.
+#[derive(Clone)]
+pub struct Node<'a> {
+    pub kind: NodeKind<'a>,
+    pub span: Span,
+}
+
+/// Trees are equal if they have the same shape, wherever in the
+/// source they were parsed from, so the spans aren't compared.
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Node<'_> {}
+
+/// Likewise only the shape is hashed, so equal trees hash the same
+impl std::hash::Hash for Node<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+    }
+}
+
+/// The `Debug` output puts the span after the kind, eg. `Cst(1)@1:5-1:6`,
+/// as spelling out every `SourcePosition` would drown the tree.
+impl std::fmt::Debug for Node<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}@{:?}", self.kind, self.span)
+    }
+}
+
+/// A copy of the whole tree `node`, every node of it allocated again
+/// in `arena`.  The names are still shared, as they never change.
+/// Cloning a `Node` only copies the one node, which is all the passes
+/// need, as they make a new tree rather than change the one they are
+/// given.
+#[must_use]
+pub fn deep_copy<'a>(arena: &'a NodeArena<'a>, node: BNode<'a>) -> BNode<'a> {
+    arena.alloc(Node {
+        kind: node.map_children(|child| deep_copy(arena, child)),
+        span: node.span,
+    })
+}
+
+/// The kinds of `Node`.  This would normally be segregated into the
+/// syntatic categories like expression, statement, etc., but for
+/// this little example we just bundle everything, forgoing a bit of
+/// type safety for brevity.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NodeKind<'a> {
+    /// Contains the named variable.  The name is in the arena, so
+    /// the uses of a variable all share the one copy of it.
+    Var(&'a str),
 
     /// Contains integer constants
     Cst(isize),
 
     /// An addition expression
-    Add(BNode, BNode),
+    Add(BNode<'a>, BNode<'a>),
 
     /// A subtraction expression
-    Sub(BNode, BNode),
+    Sub(BNode<'a>, BNode<'a>),
+
+    /// A multiplication expression
+    Mul(BNode<'a>, BNode<'a>),
+
+    /// An integer division expression
+    Div(BNode<'a>, BNode<'a>),
+
+    /// An integer remainder expression
+    Mod(BNode<'a>, BNode<'a>),
+
+    /// A left shift expression
+    Shl(BNode<'a>, BNode<'a>),
+
+    /// An arithmetic (sign-extending) right shift expression
+    Shr(BNode<'a>, BNode<'a>),
 
     /// A less-than boolean expression
-    Lt(BNode, BNode),
+    Lt(BNode<'a>, BNode<'a>),
+
+    /// A greater-than boolean expression
+    Gt(BNode<'a>, BNode<'a>),
+
+    /// A less-than-or-equal boolean expression
+    Le(BNode<'a>, BNode<'a>),
+
+    /// A greater-than-or-equal boolean expression
+    Ge(BNode<'a>, BNode<'a>),
+
+    /// An equality boolean expression
+    Eq(BNode<'a>, BNode<'a>),
+
+    /// An inequality boolean expression
+    Ne(BNode<'a>, BNode<'a>),
+
+    /// A bitwise and expression
+    BitAnd(BNode<'a>, BNode<'a>),
+
+    /// A bitwise or expression
+    BitOr(BNode<'a>, BNode<'a>),
+
+    /// A bitwise exclusive or expression
+    BitXor(BNode<'a>, BNode<'a>),
+
+    /// A bitwise complement expression
+    BitNot(BNode<'a>),
+
+    /// A negation expression
+    Neg(BNode<'a>),
+
+    /// The absolute value of the expression, `isize::MAX` for
+    /// `isize::MIN` unless the VM checks for overflow
+    Abs(BNode<'a>),
+
+    /// The smaller of the two values
+    Min(BNode<'a>, BNode<'a>),
+
+    /// The larger of the two values
+    Max(BNode<'a>, BNode<'a>),
+
+    /// A pseudo-random non-negative integer
+    Rand,
+
+    /// A short-circuiting logical and
+    And(BNode<'a>, BNode<'a>),
+
+    /// A short-circuiting logical or
+    Or(BNode<'a>, BNode<'a>),
+
+    /// The conditional expression `test ? then : else`
+    Ternary(BNode<'a>, BNode<'a>, BNode<'a>),
+
+    /// The conditional expression `(if (test) then else)`, which is
+    /// the `if` statement as an expression
+    If2Expr(BNode<'a>, BNode<'a>, BNode<'a>),
 
     /// The assignment statement.  Note, the first argument must be `Var(_)`.
-    Set(BNode, BNode),
+    Set(BNode<'a>, BNode<'a>),
+
+    /// The `+=` assignment, which is sugar for `Set(var, Add(var, val))`
+    AddAssign(BNode<'a>, BNode<'a>),
 
     /// An `if` statement with no `else` part.
-    If1(BNode, BNode),
+    If1(BNode<'a>, BNode<'a>),
 
     /// An `if` statement with an `else` part.
-    If2(BNode, BNode, BNode),
+    If2(BNode<'a>, BNode<'a>, BNode<'a>),
 
     /// A `while` statement with test and body
-    While(BNode, BNode),
+    While(BNode<'a>, BNode<'a>),
 
     /// A `do-while` statement with body and test
-    Do(BNode, BNode),
+    Do(BNode<'a>, BNode<'a>),
+
+    /// A `for` statement with init, test, step, and body
+    For(BNode<'a>, BNode<'a>, BNode<'a>, BNode<'a>),
+
+    /// A `switch` statement with the value to switch on, the cases
+    /// with their values, and the optional default.  Each case ends
+    /// with an implicit `break`.
+    Switch(BNode<'a>, Vec<(isize, BNode<'a>)>, Option<BNode<'a>>),
+
+    /// A `print` statement, writing the value of the expression
+    Print(BNode<'a>),
+
+    /// A `read` statement, setting the named variable to an integer
+    /// read from the input
+    Read(&'a str),
+
+    /// A declaration of a local variable, initialized to zero and
+    /// shadowing any variable of the same name until the end of the
+    /// enclosing block
+    Decl(&'a str),
+
+    /// A `break` statement, leaving the innermost loop or `switch`
+    Break,
+
+    /// A `continue` statement, starting the next iteration of the
+    /// innermost loop
+    Continue,
 
     /// The null statement, for compiler convenience
     Empty,
 
-    /// The sequence node, which ties together two (or more) statements
-    Seq(BNode, BNode),
+    /// The sequence node, which ties together two (or more) statements.
+    /// Deprecated: the parser makes a `Block` of the statements
+    /// instead, and `Seq` is only kept for trees built by hand.
+    Seq(BNode<'a>, BNode<'a>),
+
+    /// The statements of a block, in order.  A block of a single
+    /// statement is just that statement.
+    Block(Vec<BNode<'a>>),
 
     /// The expression statement
-    Expr(BNode),
+    Expr(BNode<'a>),
+
+    /// A function definition with its name and body.  These are only
+    /// found at the top level of the program.
+    FnDef(&'a str, BNode<'a>),
 
-    /// The top-level program (there should be exactly one of these)
-    Prog(BNode),
+    /// A call of the named function.  Functions don't return values
+    /// (yet), so the value of a call is 0.
+    Call(&'a str),
+
+    /// The top-level program (there should be exactly one of these).
+    /// If there are function definitions, the body is a `Block` of
+    /// them with the main statement last.
+    Prog(BNode<'a>),
+
+    /// A subtree shared with other places in the tree, kept in the
+    /// table made by `memoize_subexpressions` under this key
+    Ref(u64),
+}
+
+/// A syntax error with the position where it was detected, and the
+/// line of the source it is on, if known.  Lexical errors are
+/// reported as syntax errors as well, with the `LexError` kept as the
+/// `cause`.
+#[derive(Debug)]
+pub struct ParseError {
+    pub pos: SourcePosition,
+    pub message: String,
+    pub source_line: String,
+    pub cause: Option<Box<LexError>>,
+}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        ParseError {
+            pos: e.pos,
+            message: e.message.clone(),
+            source_line: e.source_line.clone(),
+            cause: Some(Box::new(e)),
+        }
+    }
+}
+
+/// Shown like a `LexError`, with the line of the source under the
+/// message given the `#` flag
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "input:{}:{}", self.pos, self.message)?;
+        if f.alternate() {
+            highlight(f, self.pos, &self.source_line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_ref()
+            .map(|e| &**e as &(dyn std::error::Error + 'static))
+    }
 }
 
-/// The main entry point to the parser
+/// The main entry point to the parser, allocating the tree in `arena`
 ///
 /// ```
-/// use tinyc_in_rust::parser::{Node,parse};
-/// let ast: Node = parse("q = 42;");
+/// use tinyc_in_rust::parser::{parse, NodeArena};
+/// let arena = NodeArena::new();
+/// let ast = parse(&arena, "q = 42;").unwrap();
 /// ```
-#[must_use]
-pub fn parse(src: &str) -> Node {
-    Parser::new(src).program()
+///
+/// # Errors
+/// Returns a `ParseError` if `src` isn't a valid program
+pub fn parse<'a>(arena: &'a NodeArena<'a>, src: &str) -> Result<BNode<'a>, ParseError> {
+    let ast = Parser::new(arena, src)?.program()?;
+    Ok(arena.alloc(ast))
+}
+
+/// Parse the programs in `src` separated by lines of `---`, as in a
+/// file of test cases.  The positions in the trees and the errors are
+/// those in the whole of `src`.
+///
+/// ```
+/// use tinyc_in_rust::parser::{parse_many, NodeArena};
+/// let arena = NodeArena::new();
+/// let programs = parse_many(&arena, "a = 1;\n---\nb = 2;").unwrap();
+/// assert_eq!(programs.len(), 2);
+/// let error = parse_many(&arena, "a = 1;\n---\nb = ;").unwrap_err();
+/// assert_eq!(error.to_string(), "input:3:5:expected `(', got `;'");
+/// ```
+///
+/// # Errors
+/// Returns the `ParseError` of the first program that isn't valid
+pub fn parse_many<'a>(arena: &'a NodeArena<'a>, src: &str) -> Result<Vec<BNode<'a>>, ParseError> {
+    let mut programs = Vec::new();
+    // Each program is parsed with the lines before it left empty, so
+    // the positions come out right
+    let mut program = String::new();
+    for (i, line) in src.lines().enumerate() {
+        if line.trim() == "---" {
+            programs.push(parse(arena, &program)?);
+            program = "\n".repeat(i + 1);
+        } else {
+            program += line;
+            program.push('\n');
+        }
+    }
+    programs.push(parse(arena, &program)?);
+    Ok(programs)
 }
 
 /// The `Parser` parses a source string into a `Node` tree
-/// representation
-struct Parser<'a> {
-    lex: Lexer<'a>,
+/// representation, allocated in `arena`
+struct Parser<'a, 'src> {
+    arena: &'a NodeArena<'a>,
+    lex: Lexer<'src>,
     pos: SourcePosition,
     lookahead: Token,
+
+    /// The end of the last token consumed, ie. of the last construct
+    /// parsed
+    end: SourcePosition,
+
+    /// The names of the functions defined so far
+    functions: HashSet<&'a str>,
+
+    /// The names in the arena, by the lexer's `InternedStr` for them
+    names: HashMap<InternedStr, &'a str>,
+
+    /// The token after the lookahead, if `peek` has read it, with the
+    /// end of the lookahead and its own position
+    peeked: Option<(SourcePosition, SourcePosition, Token)>,
 }
 
-impl<'a> Parser<'a> {
+impl<'a, 'src> Parser<'a, 'src> {
     /// Prepare for parsing, given the provided source code
-    fn new(src: &'a str) -> Self {
+    fn new(arena: &'a NodeArena<'a>, src: &'src str) -> Result<Self, ParseError> {
         let mut parser = Self {
+            arena,
             lex: Lexer::new(src),
             pos: SourcePosition::default(),
             lookahead: Token::default(),
+            end: SourcePosition::default(),
+            functions: HashSet::new(),
+            names: HashMap::new(),
+            peeked: None,
         };
-        parser.next_token();
-        parser
+        parser.next_token()?;
+        Ok(parser)
     }
 
     /// Takes the next token from the lexer
-    fn next_token(&mut self) {
-        (self.pos, self.lookahead) = self.lex.get_token();
+    fn next_token(&mut self) -> Result<(), ParseError> {
+        if let Some((end, pos, token)) = self.peeked.take() {
+            (self.end, self.pos, self.lookahead) = (end, pos, token);
+            return Ok(());
+        }
+        self.end = self.lex.position();
+        (self.pos, self.lookahead) = self.lex.get_token()?;
+        Ok(())
+    }
+
+    /// The token after the lookahead, without consuming anything
+    fn peek(&mut self) -> Result<&Token, ParseError> {
+        if self.peeked.is_none() {
+            let end = self.lex.position();
+            let (pos, token) = self.lex.get_token()?;
+            self.peeked = Some((end, pos, token));
+        }
+        Ok(&self.peeked.as_ref().unwrap().2)
+    }
+
+    /// The name of the identifier `id`, copied into the arena the
+    /// first time it's seen
+    fn name(&mut self, id: InternedStr) -> &'a str {
+        let (arena, interner) = (self.arena, self.lex.interner());
+        self.names
+            .entry(id)
+            .or_insert_with(|| arena.name(interner.resolve(id)))
+    }
+
+    /// Make a `Node` spanning from `start` to the end of the last
+    /// token consumed
+    fn node(&self, start: SourcePosition, kind: NodeKind<'a>) -> Node<'a> {
+        Node {
+            kind,
+            span: Span {
+                start,
+                end: self.end,
+            },
+        }
+    }
+
+    /// Move `node` into the arena
+    fn alloc(&self, node: Node<'a>) -> BNode<'a> {
+        self.arena.alloc(node)
+    }
+
+    /// Make the `Node` for a binary operator with operands `l` and `r`
+    fn binary(
+        &self,
+        op: fn(BNode<'a>, BNode<'a>) -> NodeKind<'a>,
+        l: Node<'a>,
+        r: Node<'a>,
+    ) -> Node<'a> {
+        self.node(l.span.start, op(self.alloc(l), self.alloc(r)))
+    }
+
+    /// Report a syntax error at the current token
+    fn syntax_error<T>(&self, msg: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            pos: self.pos,
+            message: msg.to_string(),
+            source_line: self.lex.source_line(self.pos.line()).to_string(),
+            cause: None,
+        })
+    }
+
+    /// Report that the current token isn't `what` was expected
+    fn expected<T>(&self, what: &str) -> Result<T, ParseError> {
+        self.syntax_error(&format!(
+            "expected {what}, got {}",
+            self.lookahead.display_name()
+        ))
     }
 
     /// Parser for the `<term>` syntax
-    /// `<term> ::= <id> | <int> | <paren_expr>`
-    fn term(&mut self) -> Node {
-        match &mut self.lookahead {
-            // NB: "std::mem::take(name)" [thanks skeletizzle] is more
-            // efficient than the more obvious `name.to_string()`
+    /// `<term> ::= "-" <term> | "~" <term> | "++" <id> | "--" <id> | <id> | <int> | <paren_expr> |`
+    /// `           "(" "if" <paren_expr> <term> <term> ")" | <name> "(" ")"`
+    fn term(&mut self) -> Result<Node<'a>, ParseError> {
+        let start = self.pos;
+        match &self.lookahead {
+            // Pre-increment and -decrement are desugared into
+            // `<id> = <id> + 1` and `<id> = <id> - 1`, all the parts
+            // spanning the whole term
+            Token::PlusPlus | Token::MinusMinus => {
+                let incr = matches!(self.lookahead, Token::PlusPlus);
+                self.next_token()?;
+                let Token::Id(name) = self.lookahead else {
+                    return self.expected("identifier");
+                };
+                let name = self.name(name);
+                self.next_token()?;
+                let var = self.node(start, NodeKind::Var(name));
+                let one = self.node(start, NodeKind::Cst(1));
+                let val = if incr {
+                    self.binary(NodeKind::Add, var, one)
+                } else {
+                    self.binary(NodeKind::Sub, var, one)
+                };
+                let var = self.node(start, NodeKind::Var(name));
+                Ok(self.binary(NodeKind::Set, var, val))
+            }
+            Token::Minus => {
+                self.next_token()?;
+                let t = self.term()?;
+                Ok(self.node(start, NodeKind::Neg(self.alloc(t))))
+            }
+            Token::Tilde => {
+                self.next_token()?;
+                let t = self.term()?;
+                Ok(self.node(start, NodeKind::BitNot(self.alloc(t))))
+            }
+            Token::AbsSym => {
+                self.next_token()?;
+                let x = self.paren_expr()?;
+                Ok(self.node(start, NodeKind::Abs(self.alloc(x))))
+            }
+            Token::MinSym | Token::MaxSym => {
+                let op = if matches!(self.lookahead, Token::MinSym) {
+                    NodeKind::Min
+                } else {
+                    NodeKind::Max
+                };
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::Lpar) {
+                    return self.expected(Token::Lpar.display_name());
+                }
+                self.next_token()?;
+                let a = self.expr()?;
+                if !matches!(self.lookahead, Token::Comma) {
+                    return self.expected(Token::Comma.display_name());
+                }
+                self.next_token()?;
+                let b = self.expr()?;
+                self.close_paren(start, op(self.alloc(a), self.alloc(b)))
+            }
+            Token::RandSym => {
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::Lpar) {
+                    return self.expected(Token::Lpar.display_name());
+                }
+                self.next_token()?;
+                self.close_paren(start, NodeKind::Rand)
+            }
             Token::Id(name) => {
-                let name = std::mem::take(name); // Altn: name.to_string();
-                self.next_token();
-                Node::Var(name)
+                let name = self.name(*name);
+                self.next_token()?;
+                // Only `()` makes a call, as `(if (a) b (c))` has a
+                // variable followed by a parenthesized expression
+                if !matches!(self.lookahead, Token::Lpar) || !matches!(self.peek()?, Token::Rpar) {
+                    return Ok(self.node(start, NodeKind::Var(name)));
+                }
+                self.next_token()?;
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Call(name)))
+            }
+            // Only assigned to, which `check` makes sure of
+            Token::Underscore => {
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Var("_")))
             }
             Token::Int(val) => {
                 let val = *val;
-                self.next_token();
-                Node::Cst(val)
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Cst(val)))
+            }
+            // The branches of an `if` expression are terms, as in
+            // `(if (a) -1 1)`, lest it read as `(if (a) (a - 1) ...`
+            Token::Lpar => {
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::IfSym) {
+                    let x = self.expr()?;
+                    return self.close_paren(start, x.kind);
+                }
+                self.next_token()?;
+                let test = self.paren_expr()?;
+                let then = self.term()?;
+                let else_ = self.term()?;
+                self.close_paren(
+                    start,
+                    NodeKind::If2Expr(self.alloc(test), self.alloc(then), self.alloc(else_)),
+                )
             }
             _ => self.paren_expr(),
         }
     }
 
-    /* <sum> ::= <term> | <sum> "+" <term> | <sum> "-" <term> */
-    fn sum(&mut self) -> Node {
-        let mut t = self.term();
+    /* <product> ::= <term> | <product> <mulop> <term> */
+    /* <mulop> ::= "*" | "/" | "%" */
+    fn product(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.term()?;
         loop {
-            match self.lookahead {
-                Token::Plus => {
-                    self.next_token();
-                    t = Node::Add(Box::new(t), Box::new(self.term()));
-                }
-                Token::Minus => {
-                    self.next_token();
-                    t = Node::Sub(Box::new(t), Box::new(self.term()));
-                }
-                _ => return t,
-            }
+            let op = match self.lookahead {
+                Token::Star => NodeKind::Mul,
+                Token::Slash => NodeKind::Div,
+                Token::Percent => NodeKind::Mod,
+                _ => return Ok(t),
+            };
+            self.next_token()?;
+            let r = self.term()?;
+            t = self.binary(op, t, r);
         }
     }
 
-    /* <test> ::= <sum> | <sum> "<" <sum> */
-    fn cond(&mut self) -> Node {
-        let l = self.sum();
-        if matches!(self.lookahead, Token::Less) {
-            self.next_token();
-            Node::Lt(Box::new(l), Box::new(self.sum()))
-        } else {
-            l
+    /* <sum> ::= <product> | <sum> "+" <product> | <sum> "-" <product> */
+    fn sum(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.product()?;
+        loop {
+            let op = match self.lookahead {
+                Token::Plus => NodeKind::Add,
+                Token::Minus => NodeKind::Sub,
+                _ => return Ok(t),
+            };
+            self.next_token()?;
+            let r = self.product()?;
+            t = self.binary(op, t, r);
         }
     }
 
-    /* <expr> ::= <test> | <id> "=" <expr> */
-    fn expr(&mut self) -> Node {
-        if !matches!(self.lookahead, Token::Id(_)) {
-            return self.cond();
+    /* <shift> ::= <sum> | <shift> "<<" <sum> | <shift> ">>" <sum> */
+    fn shift(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.sum()?;
+        loop {
+            let op = match self.lookahead {
+                Token::Shl => NodeKind::Shl,
+                Token::Shr => NodeKind::Shr,
+                _ => return Ok(t),
+            };
+            self.next_token()?;
+            let r = self.sum()?;
+            t = self.binary(op, t, r);
         }
-        let t = self.cond(); // == Node::Var(..)
-        if matches!(self.lookahead, Token::Equal) {
-            self.next_token();
-            Node::Set(Box::new(t), Box::new(self.expr()))
-        } else {
-            t
+    }
+
+    /* <test> ::= <shift> | <shift> <relop> <shift> */
+    /* <relop> ::= "<" | ">" | "<=" | ">=" | "==" | "!=" */
+    fn cond(&mut self) -> Result<Node<'a>, ParseError> {
+        let l = self.shift()?;
+        let relop = match self.lookahead {
+            Token::Less => NodeKind::Lt,
+            Token::Greater => NodeKind::Gt,
+            Token::LessEq => NodeKind::Le,
+            Token::GreaterEq => NodeKind::Ge,
+            Token::EqEq => NodeKind::Eq,
+            Token::NotEq => NodeKind::Ne,
+            _ => return Ok(l),
+        };
+        self.next_token()?;
+        let r = self.shift()?;
+        Ok(self.binary(relop, l, r))
+    }
+
+    /* <bit_and> ::= <test> | <bit_and> "&" <test> */
+    fn bit_and(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.cond()?;
+        while matches!(self.lookahead, Token::Amp) {
+            self.next_token()?;
+            let r = self.cond()?;
+            t = self.binary(NodeKind::BitAnd, t, r);
+        }
+        Ok(t)
+    }
+
+    /* <bit_xor> ::= <bit_and> | <bit_xor> "^" <bit_and> */
+    fn bit_xor(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.bit_and()?;
+        while matches!(self.lookahead, Token::Caret) {
+            self.next_token()?;
+            let r = self.bit_and()?;
+            t = self.binary(NodeKind::BitXor, t, r);
+        }
+        Ok(t)
+    }
+
+    /* <bit_or> ::= <bit_xor> | <bit_or> "|" <bit_xor> */
+    fn bit_or(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.bit_xor()?;
+        while matches!(self.lookahead, Token::Pipe) {
+            self.next_token()?;
+            let r = self.bit_xor()?;
+            t = self.binary(NodeKind::BitOr, t, r);
+        }
+        Ok(t)
+    }
+
+    /* <and_test> ::= <bit_or> | <and_test> "&&" <bit_or> */
+    fn and_test(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.bit_or()?;
+        while matches!(self.lookahead, Token::AmpAmp) {
+            self.next_token()?;
+            let r = self.bit_or()?;
+            t = self.binary(NodeKind::And, t, r);
+        }
+        Ok(t)
+    }
+
+    /* <or_test> ::= <and_test> | <or_test> "||" <and_test> */
+    fn or_test(&mut self) -> Result<Node<'a>, ParseError> {
+        let mut t = self.and_test()?;
+        while matches!(self.lookahead, Token::PipePipe) {
+            self.next_token()?;
+            let r = self.and_test()?;
+            t = self.binary(NodeKind::Or, t, r);
+        }
+        Ok(t)
+    }
+
+    /* <ternary> ::= <or_test> | <or_test> "?" <expr> ":" <ternary> */
+    fn ternary(&mut self) -> Result<Node<'a>, ParseError> {
+        let test = self.or_test()?;
+        if !matches!(self.lookahead, Token::Question) {
+            return Ok(test);
+        }
+        self.next_token()?;
+        let then = self.expr()?;
+        if !matches!(self.lookahead, Token::Colon) {
+            return self.expected(Token::Colon.display_name());
+        }
+        self.next_token()?;
+        let else_ = self.ternary()?;
+        Ok(self.node(
+            test.span.start,
+            NodeKind::Ternary(self.alloc(test), self.alloc(then), self.alloc(else_)),
+        ))
+    }
+
+    /* <expr> ::= <ternary> | <id> "=" <expr> | <id> "+=" <expr> | "_" "=" <expr> */
+    fn expr(&mut self) -> Result<Node<'a>, ParseError> {
+        if !matches!(self.lookahead, Token::Id(_) | Token::Underscore) {
+            return self.ternary();
         }
+        let t = self.ternary()?; // == NodeKind::Var(..)
+        let op = match self.lookahead {
+            Token::Equal => NodeKind::Set,
+            Token::PlusEq => NodeKind::AddAssign,
+            _ => return Ok(t),
+        };
+        self.next_token()?;
+        let val = self.expr()?;
+        Ok(self.binary(op, t, val))
     }
 
-    fn paren_expr(&mut self) -> Node {
+    /// The span of a parenthesized expression includes the
+    /// parentheses
+    fn paren_expr(&mut self) -> Result<Node<'a>, ParseError> {
+        let start = self.pos;
         if !matches!(self.lookahead, Token::Lpar) {
-            self.lex.syntax_error(self.pos, "`(' expected");
+            return self.expected(Token::Lpar.display_name());
         }
-        self.next_token();
-        let x = self.expr();
+        self.next_token()?;
+        let x = self.expr()?;
+        self.close_paren(start, x.kind)
+    }
+
+    /// Finish a parenthesized `kind` that started at `start`
+    fn close_paren(
+        &mut self,
+        start: SourcePosition,
+        kind: NodeKind<'a>,
+    ) -> Result<Node<'a>, ParseError> {
         if !matches!(self.lookahead, Token::Rpar) {
-            self.lex.syntax_error(self.pos, "`)' expected");
+            return self.expected(Token::Rpar.display_name());
         }
-        self.next_token();
+        self.next_token()?;
 
-        x
+        Ok(self.node(start, kind))
     }
 
-    fn statement(&mut self) -> Node {
+    #[allow(clippy::too_many_lines)]
+    fn statement(&mut self) -> Result<Node<'a>, ParseError> {
+        let start = self.pos;
         match self.lookahead {
             Token::IfSym => {
                 /* "if" <paren_expr> <statement> */
-                self.next_token();
-                let cond = self.cond();
-                let then = self.statement();
+                self.next_token()?;
+                let cond = self.paren_expr()?;
+                let then = self.statement()?;
                 if matches!(self.lookahead, Token::ElseSym) {
                     /* ... "else" <statement> */
-                    self.next_token();
-                    Node::If2(Box::new(cond), Box::new(then), Box::new(self.statement()))
+                    self.next_token()?;
+                    let else_ = self.statement()?;
+                    Ok(self.node(
+                        start,
+                        NodeKind::If2(self.alloc(cond), self.alloc(then), self.alloc(else_)),
+                    ))
                 } else {
-                    Node::If1(Box::new(cond), Box::new(then))
+                    Ok(self.node(start, NodeKind::If1(self.alloc(cond), self.alloc(then))))
                 }
             }
             Token::WhileSym => {
                 /* "while" <paren_expr> <statement> */
-                self.next_token();
-                let cond = self.paren_expr();
-                Node::While(Box::new(cond), Box::new(self.statement()))
+                self.next_token()?;
+                let cond = self.paren_expr()?;
+                let body = self.statement()?;
+                Ok(self.node(start, NodeKind::While(self.alloc(cond), self.alloc(body))))
+            }
+            Token::ForSym => {
+                /* "for" "(" <expr> ";" <expr> ";" <expr> ")" <statement> */
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::Lpar) {
+                    return self.expected(Token::Lpar.display_name());
+                }
+                self.next_token()?;
+                let init = self.expr()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
+                }
+                self.next_token()?;
+                let cond = self.expr()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
+                }
+                self.next_token()?;
+                let step = self.expr()?;
+                if !matches!(self.lookahead, Token::Rpar) {
+                    return self.expected(Token::Rpar.display_name());
+                }
+                self.next_token()?;
+                let body = self.statement()?;
+                Ok(self.node(
+                    start,
+                    NodeKind::For(
+                        self.alloc(init),
+                        self.alloc(cond),
+                        self.alloc(step),
+                        self.alloc(body),
+                    ),
+                ))
             }
             Token::DoSym => {
                 /* "do" <statement> "while" <paren_expr> ";" */
-                self.next_token();
-                let body = self.statement();
+                self.next_token()?;
+                let body = self.statement()?;
                 if !matches!(self.lookahead, Token::WhileSym) {
-                    self.lex.syntax_error(self.pos, "expected `while'");
+                    return self.expected(Token::WhileSym.display_name());
+                }
+                self.next_token()?;
+                let cond = self.paren_expr()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
+                }
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Do(self.alloc(body), self.alloc(cond))))
+            }
+            Token::SwitchSym => {
+                /* "switch" <paren_expr> "{" { "case" <int> ":" <statement> }
+                [ "default" ":" <statement> ] "}" */
+                self.next_token()?;
+                let value = self.paren_expr()?;
+                if !matches!(self.lookahead, Token::Lbra) {
+                    return self.expected(Token::Lbra.display_name());
+                }
+                self.next_token()?;
+                let mut cases: Vec<(isize, BNode<'a>)> = Vec::new();
+                while matches!(self.lookahead, Token::CaseSym) {
+                    self.next_token()?;
+                    let Token::Int(n) = self.lookahead else {
+                        return self.expected("integer literal");
+                    };
+                    if cases.iter().any(|(m, _)| *m == n) {
+                        return self.syntax_error("duplicate case");
+                    }
+                    self.next_token()?;
+                    if !matches!(self.lookahead, Token::Colon) {
+                        return self.expected(Token::Colon.display_name());
+                    }
+                    self.next_token()?;
+                    let body = self.statement()?;
+                    cases.push((n, self.alloc(body)));
+                }
+                let mut default = None;
+                if matches!(self.lookahead, Token::DefaultSym) {
+                    self.next_token()?;
+                    if !matches!(self.lookahead, Token::Colon) {
+                        return self.expected(Token::Colon.display_name());
+                    }
+                    self.next_token()?;
+                    let body = self.statement()?;
+                    default = Some(self.alloc(body));
+                }
+                if !matches!(self.lookahead, Token::Rbra) {
+                    return self.expected(Token::Rbra.display_name());
+                }
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Switch(self.alloc(value), cases, default)))
+            }
+            Token::PrintSym => {
+                /* "print" <expr> ";" */
+                self.next_token()?;
+                let x = self.expr()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
+                }
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Print(self.alloc(x))))
+            }
+            Token::ReadSym => {
+                /* "read" <id> ";" */
+                self.next_token()?;
+                let Token::Id(name) = self.lookahead else {
+                    return self.expected("identifier");
+                };
+                let name = self.name(name);
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
+                }
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Read(name)))
+            }
+            Token::BreakSym => {
+                /* "break" ";" */
+                self.next_token()?;
+                if !matches!(self.lookahead, Token::Semi) {
+                    return self.expected(Token::Semi.display_name());
                 }
-                self.next_token();
-                let cond = self.paren_expr();
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Break))
+            }
+            Token::ContinueSym => {
+                /* "continue" ";" */
+                self.next_token()?;
                 if !matches!(self.lookahead, Token::Semi) {
-                    self.lex.syntax_error(self.pos, "expected `;'");
+                    return self.expected(Token::Semi.display_name());
                 }
-                self.next_token();
-                Node::Do(Box::new(body), Box::new(cond))
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Continue))
             }
             Token::Semi => {
                 /* ";" */
-                self.next_token();
-                Node::Empty
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Empty))
             }
             Token::Lbra => {
-                /* "{" { <statement> } "}" */
-                self.next_token();
-                let mut x = self.statement();
+                /* "{" { <statement> | <declaration> } "}" */
+                self.next_token()?;
+                let mut stmts = vec![self.block_item()?];
                 while !matches!(self.lookahead, Token::Rbra) {
-                    x = Node::Seq(Box::new(x), Box::new(self.statement()));
+                    stmts.push(self.block_item()?);
                 }
-                self.next_token();
-                x
+                self.next_token()?;
+                // A block of just a declaration is kept as a block so
+                // the declaration stays local to it
+                let kind = if stmts.len() == 1 && !matches!(stmts[0].kind, NodeKind::Decl(_)) {
+                    stmts.pop().unwrap().kind
+                } else {
+                    NodeKind::Block(stmts.into_iter().map(|s| self.alloc(s)).collect())
+                };
+                Ok(self.node(start, kind))
             }
             _ => {
                 /* <expr> ";" */
-                let x = self.expr();
+                let x = self.expr()?;
                 if !matches!(self.lookahead, Token::Semi) {
-                    self.lex.syntax_error(self.pos, "expected `;'");
+                    return self.expected(Token::Semi.display_name());
                 }
-                self.next_token();
-                Node::Expr(Box::new(x))
+                self.next_token()?;
+                Ok(self.node(start, NodeKind::Expr(self.alloc(x))))
             }
         }
     }
 
-    fn program(&mut self) -> Node {
-        /* <program> ::= <statement> */
-        let stmt = self.statement();
+    fn block_item(&mut self) -> Result<Node<'a>, ParseError> {
+        if !matches!(self.lookahead, Token::IntSym) {
+            return self.statement();
+        }
+        /* <declaration> ::= "int" <id> ";" */
+        let start = self.pos;
+        self.next_token()?;
+        let Token::Id(name) = self.lookahead else {
+            return self.expected("identifier");
+        };
+        let name = self.name(name);
+        self.next_token()?;
+        if !matches!(self.lookahead, Token::Semi) {
+            return self.expected(Token::Semi.display_name());
+        }
+        self.next_token()?;
+        Ok(self.node(start, NodeKind::Decl(name)))
+    }
+
+    fn function(&mut self) -> Result<Node<'a>, ParseError> {
+        /* <function> ::= "fn" <name> "(" ")" <statement> */
+        let start = self.pos;
+        self.next_token()?;
+        let Token::Id(name) = self.lookahead else {
+            return self.expected("function name");
+        };
+        let name = self.name(name);
+        if !self.functions.insert(name) {
+            return self.syntax_error(&format!("function `{name}' defined twice"));
+        }
+        self.next_token()?;
+        if !matches!(self.lookahead, Token::Lpar) {
+            return self.expected(Token::Lpar.display_name());
+        }
+        self.next_token()?;
+        if !matches!(self.lookahead, Token::Rpar) {
+            return self.expected(Token::Rpar.display_name());
+        }
+        self.next_token()?;
+        let body = self.statement()?;
+        Ok(self.node(start, NodeKind::FnDef(name, self.alloc(body))))
+    }
+
+    fn program(&mut self) -> Result<Node<'a>, ParseError> {
+        /* <program> ::= { <function> } <statement> { <function> } */
+        let start = self.pos;
+        let mut functions = Vec::new();
+        while matches!(self.lookahead, Token::FnSym) {
+            functions.push(self.function()?);
+        }
+        let stmt = self.statement()?;
+        while matches!(self.lookahead, Token::FnSym) {
+            functions.push(self.function()?);
+        }
         if !matches!(self.lookahead, Token::Eoi) {
-            self.lex.syntax_error(self.pos, "program ended here");
+            return self.syntax_error("program ended here");
+        }
+
+        // The functions go first, so the main statement is easy to
+        // tell apart from them.  The `Block` of them all spans the
+        // whole program.
+        if functions.is_empty() {
+            return Ok(self.node(start, NodeKind::Prog(self.alloc(stmt))));
         }
-        Node::Prog(Box::new(stmt))
+        let mut stmts: Vec<BNode<'a>> = functions.into_iter().map(|f| self.alloc(f)).collect();
+        stmts.push(self.alloc(stmt));
+        let body = self.node(start, NodeKind::Block(stmts));
+        Ok(self.node(start, NodeKind::Prog(self.alloc(body))))
     }
 }
 
-// *** Parser Testing ***
+/// A `Parser` that doesn't stop at the first syntax error.  After an
+/// error it skips to the next `;` or `}` and carries on with the
+/// statements and functions from there, so one run reports all the
+/// errors it can find.  Only the errors are kept, not the tree.
+pub struct ErrorRecoveryParser<'a, 'src>(Parser<'a, 'src>);
 
-#[cfg(test)]
-use insta::assert_snapshot;
+impl<'a, 'src> ErrorRecoveryParser<'a, 'src> {
+    /// Prepare for parsing `src`, allocating the tree in `arena`
+    ///
+    /// # Errors
+    /// Returns a `ParseError` if the first token isn't valid
+    pub fn new(arena: &'a NodeArena<'a>, src: &'src str) -> Result<Self, ParseError> {
+        Ok(ErrorRecoveryParser(Parser::new(arena, src)?))
+    }
 
-#[test]
-fn test_term() {
-    let mut parse = Parser::new("2 alpha");
-    let n = parse.term();
-    assert!(matches!(n, Node::Cst(2)));
-    let n = parse.term();
-    assert!(match n {
-        Node::Var(v) => v == "alpha",
-        _ => false,
-    });
-}
+    /// Parse the program, as `parse` does
+    ///
+    /// ```
+    /// use tinyc_in_rust::parser::{ErrorRecoveryParser, NodeArena};
+    /// let arena = NodeArena::new();
+    /// let errors = ErrorRecoveryParser::new(&arena, "{ a = ; b = 1 }").unwrap().program().unwrap_err();
+    /// assert_eq!(errors.len(), 2);
+    /// ```
+    ///
+    /// # Errors
+    /// Returns all the `ParseError`s found, in the order of the source.
+    /// A lexical error ends the search, as the rest can't be read.
+    pub fn program(mut self) -> Result<BNode<'a>, Vec<ParseError>> {
+        let error = match self.0.program() {
+            Ok(ast) => return Ok(self.0.alloc(ast)),
+            Err(e) => e,
+        };
+        let mut errors = Vec::new();
+        let mut error = Some(error);
+        while let Some(e) = error.take() {
+            let lexical = e.cause.is_some();
+            errors.push(e);
+            if lexical {
+                break;
+            }
+            error = self.synchronize().and_then(|()| self.rest()).err();
+        }
+        Err(errors)
+    }
 
-#[test]
-fn test_sum() {
-    assert_snapshot!(format!("{:?}", Parser::new("2+3-4").sum()));
-    assert_snapshot!(format!("{:?}", Parser::new("a-b-c").sum()));
-}
+    /// Skip to just after the next `;` or `}`, or to the end
+    fn synchronize(&mut self) -> Result<(), ParseError> {
+        loop {
+            match self.0.lookahead {
+                Token::Eoi => return Ok(()),
+                Token::Semi | Token::Rbra => return self.0.next_token(),
+                _ => self.0.next_token()?,
+            }
+        }
+    }
 
-#[test]
-fn test_cond() {
-    assert_snapshot!(format!("{:?}", Parser::new("2 < 4").cond()));
-    assert_snapshot!(format!("{:?}", Parser::new("a").cond()));
+    /// Parse the statements and functions up to the end, stopping at
+    /// the first error.  A `}` with nothing to close, as is left after
+    /// an error in a block, is skipped.
+    fn rest(&mut self) -> Result<(), ParseError> {
+        loop {
+            match self.0.lookahead {
+                Token::Eoi => return Ok(()),
+                Token::Rbra => self.0.next_token()?,
+                Token::FnSym => {
+                    self.0.function()?;
+                }
+                _ => {
+                    self.0.statement()?;
+                }
+            }
+        }
+    }
 }
 
-#[test]
-fn test_expr() {
-    assert_snapshot!(format!("{:?}", Parser::new("2 < 4").expr()));
-    assert_snapshot!(format!("{:?}", Parser::new("a = 42 - 666").expr()));
-}
+/// A walk over the tree, one method per kind of node.  The default
+/// methods visit the children with `visit_node`, so a visitor only
+/// overrides the methods for the nodes it cares about.
+pub trait AstVisitor {
+    /// Visit `node` by calling the method for its kind
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
 
-#[test]
-fn test_paren_expr() {
-    assert_snapshot!(format!("{:?}", Parser::new("(2-(3-4))").paren_expr()));
-    assert_snapshot!(format!("{:?}", Parser::new(" (x < 7) y;").paren_expr()));
-}
+    fn visit_var(&mut self, _name: &str) {}
 
-#[test]
-fn test_statement() {
-    assert_snapshot!(format!("{:?}", Parser::new(";").statement()));
-    assert_snapshot!(format!("{:?}", Parser::new("a;").statement()));
-    assert_snapshot!(format!(
-        "{:?}",
-        Parser::new("if (2 < 3) b = 42;").statement()
-    ));
-}
+    fn visit_cst(&mut self, _value: isize) {}
 
-#[test]
-fn test_statement2() {
-    assert_snapshot!(format!(
-        "{:?}",
-        Parser::new("if (2) b = 42; else b = 666;").statement()
-    ));
-}
+    fn visit_add(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
 
-#[test]
-fn test_statement3() {
-    assert_snapshot!(format!(
-        "{:?}",
-        Parser::new("{ b = 666; c = 3; d = b; }").statement()
-    ));
-}
+    fn visit_sub(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_mul(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_div(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_mod(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_shl(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_shr(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_lt(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_gt(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_le(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_ge(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_eq(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_ne(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_bit_and(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_bit_or(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_bit_xor(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_bit_not(&mut self, x: &Node) {
+        self.visit_node(x);
+    }
+
+    fn visit_neg(&mut self, x: &Node) {
+        self.visit_node(x);
+    }
+
+    fn visit_abs(&mut self, x: &Node) {
+        self.visit_node(x);
+    }
+
+    fn visit_min(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_max(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_rand(&mut self) {}
+
+    fn visit_and(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_or(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_ternary(&mut self, test: &Node, then: &Node, else_: &Node) {
+        self.visit_node(test);
+        self.visit_node(then);
+        self.visit_node(else_);
+    }
+
+    fn visit_if2_expr(&mut self, test: &Node, then: &Node, else_: &Node) {
+        self.visit_node(test);
+        self.visit_node(then);
+        self.visit_node(else_);
+    }
+
+    fn visit_set(&mut self, var: &Node, val: &Node) {
+        self.visit_node(var);
+        self.visit_node(val);
+    }
+
+    fn visit_add_assign(&mut self, var: &Node, val: &Node) {
+        self.visit_node(var);
+        self.visit_node(val);
+    }
+
+    fn visit_if1(&mut self, test: &Node, then: &Node) {
+        self.visit_node(test);
+        self.visit_node(then);
+    }
+
+    fn visit_if2(&mut self, test: &Node, then: &Node, else_: &Node) {
+        self.visit_node(test);
+        self.visit_node(then);
+        self.visit_node(else_);
+    }
+
+    fn visit_while(&mut self, test: &Node, body: &Node) {
+        self.visit_node(test);
+        self.visit_node(body);
+    }
+
+    fn visit_do(&mut self, body: &Node, test: &Node) {
+        self.visit_node(body);
+        self.visit_node(test);
+    }
+
+    fn visit_for(&mut self, init: &Node, test: &Node, step: &Node, body: &Node) {
+        self.visit_node(init);
+        self.visit_node(test);
+        self.visit_node(step);
+        self.visit_node(body);
+    }
+
+    fn visit_switch(&mut self, value: &Node, cases: &[(isize, BNode)], default: Option<&Node>) {
+        self.visit_node(value);
+        for (_, body) in cases {
+            self.visit_node(body);
+        }
+        if let Some(body) = default {
+            self.visit_node(body);
+        }
+    }
+
+    fn visit_print(&mut self, x: &Node) {
+        self.visit_node(x);
+    }
+
+    fn visit_read(&mut self, _name: &str) {}
+
+    fn visit_decl(&mut self, _name: &str) {}
+
+    fn visit_break(&mut self) {}
+
+    fn visit_continue(&mut self) {}
+
+    fn visit_empty(&mut self) {}
+
+    fn visit_seq(&mut self, a: &Node, b: &Node) {
+        self.visit_node(a);
+        self.visit_node(b);
+    }
+
+    fn visit_block(&mut self, stmts: &[BNode]) {
+        for s in stmts {
+            self.visit_node(s);
+        }
+    }
+
+    fn visit_expr(&mut self, x: &Node) {
+        self.visit_node(x);
+    }
+
+    fn visit_fn_def(&mut self, _name: &str, body: &Node) {
+        self.visit_node(body);
+    }
+
+    fn visit_call(&mut self, _name: &str) {}
+
+    fn visit_prog(&mut self, body: &Node) {
+        self.visit_node(body);
+    }
+
+    fn visit_ref(&mut self, _key: u64) {}
+}
+
+/// Call the method of `visitor` for the kind of `node`.  This is what
+/// `visit_node` does unless overridden, and an override can call it to
+/// go on with the walk.
+pub fn walk_node<V: AstVisitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match &node.kind {
+        NodeKind::Var(name) => visitor.visit_var(name),
+        NodeKind::Cst(value) => visitor.visit_cst(*value),
+        NodeKind::Add(a, b) => visitor.visit_add(a, b),
+        NodeKind::Sub(a, b) => visitor.visit_sub(a, b),
+        NodeKind::Mul(a, b) => visitor.visit_mul(a, b),
+        NodeKind::Div(a, b) => visitor.visit_div(a, b),
+        NodeKind::Mod(a, b) => visitor.visit_mod(a, b),
+        NodeKind::Shl(a, b) => visitor.visit_shl(a, b),
+        NodeKind::Shr(a, b) => visitor.visit_shr(a, b),
+        NodeKind::Lt(a, b) => visitor.visit_lt(a, b),
+        NodeKind::Gt(a, b) => visitor.visit_gt(a, b),
+        NodeKind::Le(a, b) => visitor.visit_le(a, b),
+        NodeKind::Ge(a, b) => visitor.visit_ge(a, b),
+        NodeKind::Eq(a, b) => visitor.visit_eq(a, b),
+        NodeKind::Ne(a, b) => visitor.visit_ne(a, b),
+        NodeKind::BitAnd(a, b) => visitor.visit_bit_and(a, b),
+        NodeKind::BitOr(a, b) => visitor.visit_bit_or(a, b),
+        NodeKind::BitXor(a, b) => visitor.visit_bit_xor(a, b),
+        NodeKind::BitNot(x) => visitor.visit_bit_not(x),
+        NodeKind::Neg(x) => visitor.visit_neg(x),
+        NodeKind::Abs(x) => visitor.visit_abs(x),
+        NodeKind::Min(a, b) => visitor.visit_min(a, b),
+        NodeKind::Max(a, b) => visitor.visit_max(a, b),
+        NodeKind::Rand => visitor.visit_rand(),
+        NodeKind::And(a, b) => visitor.visit_and(a, b),
+        NodeKind::Or(a, b) => visitor.visit_or(a, b),
+        NodeKind::Ternary(test, then, else_) => visitor.visit_ternary(test, then, else_),
+        NodeKind::If2Expr(test, then, else_) => visitor.visit_if2_expr(test, then, else_),
+        NodeKind::Set(var, val) => visitor.visit_set(var, val),
+        NodeKind::AddAssign(var, val) => visitor.visit_add_assign(var, val),
+        NodeKind::If1(test, then) => visitor.visit_if1(test, then),
+        NodeKind::If2(test, then, else_) => visitor.visit_if2(test, then, else_),
+        NodeKind::While(test, body) => visitor.visit_while(test, body),
+        NodeKind::Do(body, test) => visitor.visit_do(body, test),
+        NodeKind::For(init, test, step, body) => visitor.visit_for(init, test, step, body),
+        NodeKind::Switch(value, cases, default) => {
+            visitor.visit_switch(value, cases, default.as_deref());
+        }
+        NodeKind::Print(x) => visitor.visit_print(x),
+        NodeKind::Read(name) => visitor.visit_read(name),
+        NodeKind::Decl(name) => visitor.visit_decl(name),
+        NodeKind::Break => visitor.visit_break(),
+        NodeKind::Continue => visitor.visit_continue(),
+        NodeKind::Empty => visitor.visit_empty(),
+        NodeKind::Seq(a, b) => visitor.visit_seq(a, b),
+        NodeKind::Block(stmts) => visitor.visit_block(stmts),
+        NodeKind::Expr(x) => visitor.visit_expr(x),
+        NodeKind::FnDef(name, body) => visitor.visit_fn_def(name, body),
+        NodeKind::Call(name) => visitor.visit_call(name),
+        NodeKind::Prog(body) => visitor.visit_prog(body),
+        NodeKind::Ref(key) => visitor.visit_ref(*key),
+    }
+}
+
+impl Node<'_> {
+    /// The name of the kind of node, and for those with a name or a
+    /// value that too, eg. `Add`, `Var a`, or `Cst 1`
+    #[must_use]
+    pub fn label(&self) -> String {
+        match &self.kind {
+            NodeKind::Var(name) => format!("Var {name}"),
+            NodeKind::Cst(value) => format!("Cst {value}"),
+            NodeKind::Read(name) => format!("Read {name}"),
+            NodeKind::Decl(name) => format!("Decl {name}"),
+            NodeKind::FnDef(name, _) => format!("FnDef {name}"),
+            NodeKind::Call(name) => format!("Call {name}"),
+            kind => {
+                let debug = format!("{kind:?}");
+                debug[..debug.find('(').unwrap_or(debug.len())].to_string()
+            }
+        }
+    }
+}
+
+/// Collects the names of the variables a tree uses
+#[derive(Debug, Default)]
+pub struct VariableCollector {
+    pub names: HashSet<String>,
+}
+
+impl AstVisitor for VariableCollector {
+    fn visit_var(&mut self, name: &str) {
+        self.names.insert(name.to_string());
+    }
+
+    fn visit_read(&mut self, name: &str) {
+        self.names.insert(name.to_string());
+    }
+}
+
+/// Writes the tree as an outline, one node per line with its children
+/// indented below it, eg. for `a = 1;`
+///
+/// ```text
+/// Expr
+///   Set
+///     Var a
+///     Cst 1
+/// ```
+#[derive(Debug, Default)]
+pub struct AstPrinter {
+    pub output: String,
+    level: usize,
+}
+
+impl AstPrinter {
+    fn line(&mut self, text: &str) {
+        let indent = self.level * 2;
+        writeln!(self.output, "{:indent$}{text}", "").unwrap();
+    }
+}
+
+impl AstVisitor for AstPrinter {
+    fn visit_node(&mut self, node: &Node) {
+        self.line(&node.label());
+        self.level += 1;
+        walk_node(self, node);
+        self.level -= 1;
+    }
+
+    fn visit_switch(&mut self, value: &Node, cases: &[(isize, BNode)], default: Option<&Node>) {
+        self.visit_node(value);
+        for (n, body) in cases {
+            self.line(&format!("case {n}"));
+            self.level += 1;
+            self.visit_node(body);
+            self.level -= 1;
+        }
+        if let Some(body) = default {
+            self.line("default");
+            self.level += 1;
+            self.visit_node(body);
+            self.level -= 1;
+        }
+    }
+}
+
+// *** Parser Testing ***
+
+#[cfg(test)]
+use insta::assert_snapshot;
+
+/// An arena for a test, which is never freed
+#[cfg(test)]
+fn arena() -> &'static NodeArena<'static> {
+    Box::leak(Box::default())
+}
+
+/// A node for comparing with the parsed ones, which don't compare
+/// the spans
+#[cfg(test)]
+fn node(kind: NodeKind<'static>) -> BNode<'static> {
+    arena().alloc(Node {
+        kind,
+        span: Span::default(),
+    })
+}
+
+#[cfg(test)]
+fn var(name: &str) -> BNode<'static> {
+    node(NodeKind::Var(arena().name(name)))
+}
+
+#[cfg(test)]
+fn cst(n: isize) -> BNode<'static> {
+    node(NodeKind::Cst(n))
+}
+
+#[test]
+fn test_term() {
+    let mut parse = Parser::new(arena(), "2 alpha").unwrap();
+    assert_eq!(parse.term().unwrap(), *cst(2));
+    assert_eq!(parse.term().unwrap(), *var("alpha"));
+}
+
+#[test]
+fn test_negation() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "-a").unwrap().term().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a - -b").unwrap().sum().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "- -a").unwrap().term().unwrap()
+    ));
+}
+
+#[test]
+fn test_increment() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "++i").unwrap().term().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "--i").unwrap().term().unwrap()
+    ));
+}
+
+#[test]
+fn test_sum() {
+    assert_eq!(
+        Parser::new(arena(), "2+3-4").unwrap().sum().unwrap(),
+        *node(NodeKind::Sub(node(NodeKind::Add(cst(2), cst(3))), cst(4)))
+    );
+    assert_eq!(
+        Parser::new(arena(), "a-b-c").unwrap().sum().unwrap(),
+        *node(NodeKind::Sub(
+            node(NodeKind::Sub(var("a"), var("b"))),
+            var("c")
+        ))
+    );
+}
+
+#[test]
+fn test_product() {
+    assert_eq!(
+        Parser::new(arena(), "2+3*4").unwrap().sum().unwrap(),
+        *node(NodeKind::Add(cst(2), node(NodeKind::Mul(cst(3), cst(4)))))
+    );
+    assert_eq!(
+        Parser::new(arena(), "a*b*c").unwrap().product().unwrap(),
+        *node(NodeKind::Mul(
+            node(NodeKind::Mul(var("a"), var("b"))),
+            var("c")
+        ))
+    );
+    assert_eq!(
+        Parser::new(arena(), "a/b%c*d").unwrap().product().unwrap(),
+        *node(NodeKind::Mul(
+            node(NodeKind::Mod(
+                node(NodeKind::Div(var("a"), var("b"))),
+                var("c")
+            )),
+            var("d")
+        ))
+    );
+}
+
+#[test]
+fn test_cond() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "2 < 4").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a + 1 == b").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a != b - 1").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a > b").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a <= b").unwrap().cond().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a >= b").unwrap().cond().unwrap()
+    ));
+}
+
+#[test]
+fn test_shift() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a << b + 1 >> c < d")
+            .unwrap()
+            .expr()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_bitwise() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a | b ^ c & d == e")
+            .unwrap()
+            .expr()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "~a & ~-b").unwrap().expr().unwrap()
+    ));
+}
+
+#[test]
+fn test_or_test() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a < b && b < c")
+            .unwrap()
+            .or_test()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a || b && c || d")
+            .unwrap()
+            .or_test()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_ternary() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a < 3 ? 0 : 1")
+            .unwrap()
+            .ternary()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a ? b : c ? d : e")
+            .unwrap()
+            .ternary()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_if_expr() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "(if (b < 5) 0 1)")
+            .unwrap()
+            .term()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "(if (a) -b (c + 1)) * 2")
+            .unwrap()
+            .expr()
+            .unwrap()
+    ));
+    assert_eq!(
+        parse(arena(), "a = (if (b) 1 2 3);")
+            .unwrap_err()
+            .to_string(),
+        "input:1:17:expected `)', got integer literal"
+    );
+}
+
+#[test]
+fn test_expr() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "2 < 4").unwrap().expr().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a = 42 - 666")
+            .unwrap()
+            .expr()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_paren_expr() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "(2-(3-4))")
+            .unwrap()
+            .paren_expr()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), " (x < 7) y;")
+            .unwrap()
+            .paren_expr()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_statement() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), ";").unwrap().statement().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "a;").unwrap().statement().unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "if (2 < 3) b = 42;")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_statement2() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "if (2) b = 42; else b = 666;")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_statement3() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "{ b = 666; c = 3; d = b; }")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+}
 
 #[test]
 fn test_statement4() {
-    assert_snapshot!(format!("{:?}", Parser::new("while (x < 7) y;").statement()));
     assert_snapshot!(format!(
         "{:?}",
-        Parser::new("while (x < 7) { b = b - 1; c = c + b; }").statement()
+        Parser::new(arena(), "while (x < 7) y;")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "while (x < 7) { b = b - 1; c = c + b; }")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_statement5() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "for (i = 0; i < 10; i = i + 1) s = s + i;")
+            .unwrap()
+            .statement()
+            .unwrap()
+    ));
+}
+
+#[test]
+fn test_switch() {
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(
+            arena(),
+            "switch (a) { case 1: b = 10; case 2: ; default: b = 99; }"
+        )
+        .unwrap()
+        .statement()
+        .unwrap()
+    ));
+    assert_snapshot!(format!(
+        "{:?}",
+        Parser::new(arena(), "switch (a) { }")
+            .unwrap()
+            .statement()
+            .unwrap()
     ));
 }
 
 #[test]
 fn test_program() {
-    assert_snapshot!(format!("{:?}", Parser::new("a = 42;").program()));
+    assert_eq!(
+        parse(arena(), "a;").unwrap(),
+        node(NodeKind::Prog(node(NodeKind::Expr(var("a")))))
+    );
+    assert_eq!(
+        parse(arena(), "a = 42;").unwrap(),
+        node(NodeKind::Prog(node(NodeKind::Expr(node(NodeKind::Set(
+            var("a"),
+            cst(42)
+        ))))))
+    );
+}
+
+#[test]
+fn test_function() {
+    assert_snapshot!(format!(
+        "{:?}",
+        parse(arena(), "fn f() a = 1; { f(); } fn g() f();").unwrap()
+    ));
+}
+
+#[test]
+fn test_error_recovery() {
+    let errors = |src| -> Vec<String> {
+        ErrorRecoveryParser::new(arena(), src)
+            .unwrap()
+            .program()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    };
+    assert_eq!(
+        errors("{ a = 5 b = 6; c = (1 + 2; }"),
+        [
+            "input:1:9:expected `;', got identifier",
+            "input:1:26:expected `)', got `;'"
+        ]
+    );
+    assert_eq!(
+        errors("fn g ; a = ;\nfn f() ; fn f() ;"),
+        [
+            "input:1:6:expected `(', got `;'",
+            "input:1:12:expected `(', got `;'",
+            "input:2:13:function `f' defined twice"
+        ]
+    );
+    assert_eq!(
+        errors("{ a = ; while (b c = 2; } d = 1 $ 2; e = ;"),
+        [
+            "input:1:7:expected `(', got `;'",
+            "input:1:18:expected `)', got identifier",
+            "input:1:33:Illegal token"
+        ]
+    );
+    assert_eq!(
+        errors("a = 1"),
+        ["input:1:6:expected `;', got end of input"]
+    );
+    for src in [
+        "a = 1;",
+        "fn f() a = 1; { f(); b = 2; }",
+        "{ i = 0; while (i < 3) ++i; }",
+    ] {
+        let ast = ErrorRecoveryParser::new(arena(), src)
+            .unwrap()
+            .program()
+            .unwrap();
+        assert_eq!(
+            format!("{ast:?}"),
+            format!("{:?}", parse(arena(), src).unwrap())
+        );
+    }
+}
+
+#[test]
+fn test_parse_many() {
+    let src = "a = 1;\n---\nfn f() b = 2;\n{ f(); c = 3; }\n  ---  \r\nwhile (d) d = d - 1;\n";
+    let programs = parse_many(arena(), src).unwrap();
+    assert_eq!(programs.len(), 3);
+    for (program, part) in programs.iter().zip([
+        "a = 1;",
+        "fn f() b = 2;\n{ f(); c = 3; }",
+        "while (d) d = d - 1;",
+    ]) {
+        assert_eq!(*program, parse(arena(), part).unwrap());
+    }
+    assert_eq!(format!("{:?}", programs[2].span), "6:1-6:21");
+
+    assert_eq!(parse_many(arena(), "a = 1;").unwrap().len(), 1);
+    let error = |src| parse_many(arena(), src).unwrap_err().to_string();
+    assert_eq!(
+        error("a = 1;\n---\n{ b = 2\n}"),
+        "input:4:1:expected `;', got `}'"
+    );
+    assert_eq!(
+        error("a = 1;\n---\n"),
+        "input:3:1:expected `(', got end of input"
+    );
+    assert_eq!(
+        error("a = 1;\n---- \nb = 2;"),
+        "input:2:1:program ended here"
+    );
+}
+
+#[test]
+fn test_syntax_errors() {
+    let error = |src| parse(arena(), src).unwrap_err().to_string();
+    assert_eq!(error("a = (1 + 2;"), "input:1:11:expected `)', got `;'");
+    assert_eq!(
+        error("{ a = 1;\n  b = 2 }"),
+        "input:2:9:expected `;', got `}'"
+    );
+    assert_eq!(
+        error("do a; b;"),
+        "input:1:7:expected `while', got identifier"
+    );
+    assert_eq!(error("a; b;"), "input:1:4:program ended here");
+    assert_eq!(error("print a"), "input:1:8:expected `;', got end of input");
+    assert_eq!(
+        error("read 1;"),
+        "input:1:6:expected identifier, got integer literal"
+    );
+    assert_eq!(
+        error("fn (a) ;"),
+        "input:1:4:expected function name, got `('"
+    );
+    assert_eq!(error("fn f ;"), "input:1:6:expected `(', got `;'");
+    assert_eq!(error("a = f(1);"), "input:1:6:expected `;', got `('");
+    assert_eq!(
+        error("fn f() ; a; fn f() ;"),
+        "input:1:16:function `f' defined twice"
+    );
+    assert_eq!(
+        error("fn f() ; fn g() ;"),
+        "input:1:18:expected `(', got end of input"
+    );
+    assert_eq!(
+        error("switch (a) case 1: ;"),
+        "input:1:12:expected `{', got `case'"
+    );
+    assert_eq!(
+        error("switch (a) { case b: ; }"),
+        "input:1:19:expected integer literal, got identifier"
+    );
+    assert_eq!(
+        error("switch (a) { case 1 ; }"),
+        "input:1:21:expected `:', got `;'"
+    );
+    assert_eq!(
+        error("switch (a) { case 1: ; case 1: ; }"),
+        "input:1:29:duplicate case"
+    );
+    assert_eq!(
+        error("switch (a) { default: ; case 1: ; }"),
+        "input:1:25:expected `}', got `case'"
+    );
+    assert_eq!(error("a = 1 # 2;"), "input:1:7:Illegal token");
+    assert_eq!(error("a = 0x;"), "input:1:5:Illegal hexadecimal constant");
+    assert_eq!(error("a = 09;"), "input:1:5:Illegal octal constant");
+    assert_eq!(
+        error("a = 1; /* b = 2;\n"),
+        "input:1:8:unterminated comment"
+    );
+}
+
+#[test]
+fn test_variable_collector() {
+    let mut collector = VariableCollector::default();
+    collector.visit_node(
+        parse(
+            arena(),
+            "fn f() read n; { count = a + 1; while (b) print c ? d : -e; f(); }",
+        )
+        .unwrap(),
+    );
+    let mut names: Vec<_> = collector.names.into_iter().collect();
+    names.sort();
+    assert_eq!(names, ["a", "b", "c", "count", "d", "e", "n"]);
+}
+
+#[test]
+fn test_ast_printer() {
+    let mut printer = AstPrinter::default();
+    printer.visit_node(
+        parse(
+            arena(),
+            "{ a = 1; switch (a) { case 1: b = ~a; default: ; } }",
+        )
+        .unwrap(),
+    );
+    assert_snapshot!(printer.output);
+}
+
+#[test]
+fn test_deep_copy() {
+    // All the nodes of a tree, the root first
+    fn nodes<'a>(node: BNode<'a>, all: &mut Vec<BNode<'a>>) {
+        all.push(node);
+        node.map_children(|child| {
+            nodes(child, all);
+            child
+        });
+    }
+
+    let ast = parse(arena(), "{ a = 1; while (a < 10) a = a * 2; }").unwrap();
+    let copy = deep_copy(arena(), ast);
+    assert_eq!(format!("{copy:?}"), format!("{ast:?}"));
+
+    // No node is shared, down to the leaves
+    let (mut originals, mut copies) = (Vec::new(), Vec::new());
+    nodes(ast, &mut originals);
+    nodes(copy, &mut copies);
+    assert_eq!(originals.len(), 16);
+    assert_eq!(originals.len(), copies.len());
+    assert!(originals
+        .iter()
+        .zip(&copies)
+        .all(|(original, copy)| !std::ptr::eq(*original, *copy)));
 }
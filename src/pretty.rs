@@ -0,0 +1,335 @@
+//! Pretty-printing of the abstract syntax tree back to Tiny-C source
+//!
+//! The output is meant to be read by humans, but it must also parse
+//! back to the very same tree.  Expressions are therefore only
+//! parenthesized where the precedence (or associativity) of the
+//! operators requires it, and blocks are printed with the statements
+//! of the `Block` the parser builds.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::parser::{Node, NodeKind};
+use std::fmt;
+
+/// The number of spaces per indentation level
+const INDENT: usize = 4;
+
+/// The precedence of a `<term>`, which never needs parentheses
+const TERM: u8 = 12;
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_statement() {
+            self.fmt_stmt(f, 0)
+        } else {
+            self.fmt_expr(f, 0)
+        }
+    }
+}
+
+impl Node<'_> {
+    fn is_statement(&self) -> bool {
+        matches!(
+            self.kind,
+            NodeKind::If1(..)
+                | NodeKind::If2(..)
+                | NodeKind::While(..)
+                | NodeKind::For(..)
+                | NodeKind::Switch(..)
+                | NodeKind::Do(..)
+                | NodeKind::Print(..)
+                | NodeKind::Read(..)
+                | NodeKind::Decl(..)
+                | NodeKind::Break
+                | NodeKind::Continue
+                | NodeKind::Empty
+                | NodeKind::Seq(..)
+                | NodeKind::Block(..)
+                | NodeKind::Expr(..)
+                | NodeKind::FnDef(..)
+                | NodeKind::Prog(..)
+        )
+    }
+
+    /// The precedence level of an expression, higher binds tighter.
+    /// These follow the layers of the grammar, from `<expr>` down to
+    /// `<term>`.
+    fn precedence(&self) -> u8 {
+        match self.kind {
+            NodeKind::Set(..) | NodeKind::AddAssign(..) => 1,
+            NodeKind::Ternary(..) => 2,
+            NodeKind::Or(..) => 3,
+            NodeKind::And(..) => 4,
+            NodeKind::BitOr(..) => 5,
+            NodeKind::BitXor(..) => 6,
+            NodeKind::BitAnd(..) => 7,
+            NodeKind::Lt(..)
+            | NodeKind::Gt(..)
+            | NodeKind::Le(..)
+            | NodeKind::Ge(..)
+            | NodeKind::Eq(..)
+            | NodeKind::Ne(..) => 8,
+            NodeKind::Shl(..) | NodeKind::Shr(..) => 9,
+            NodeKind::Add(..) | NodeKind::Sub(..) => 10,
+            NodeKind::Mul(..) | NodeKind::Div(..) | NodeKind::Mod(..) => 11,
+            _ => TERM,
+        }
+    }
+
+    /// Writes the expression, in parentheses if it doesn't bind at
+    /// least as tight as `min`
+    fn fmt_expr(&self, f: &mut fmt::Formatter<'_>, min: u8) -> fmt::Result {
+        let prec = self.precedence();
+        if prec < min {
+            write!(f, "(")?;
+            self.fmt_expr(f, 0)?;
+            return write!(f, ")");
+        }
+
+        // The left-associative binary operators want the right operand
+        // to bind tighter, while the relational operators don't
+        // associate at all.
+        let (op, a, b, left, right) = match &self.kind {
+            NodeKind::Var(v) => return write!(f, "{v}"),
+            NodeKind::Cst(n) => return write!(f, "{n}"),
+            NodeKind::Call(name) => return write!(f, "{name}()"),
+            NodeKind::Rand => return write!(f, "rand()"),
+            // Not Tiny-C, as the shared tree isn't at hand here
+            NodeKind::Ref(key) => return write!(f, "<ref {key:x}>"),
+            NodeKind::Set(var, val) => {
+                var.fmt_expr(f, TERM)?;
+                write!(f, " = ")?;
+                return val.fmt_expr(f, 1);
+            }
+            NodeKind::AddAssign(var, val) => {
+                var.fmt_expr(f, TERM)?;
+                write!(f, " += ")?;
+                return val.fmt_expr(f, 1);
+            }
+            NodeKind::Ternary(test, then, else_) => {
+                test.fmt_expr(f, 3)?;
+                write!(f, " ? ")?;
+                then.fmt_expr(f, 0)?;
+                write!(f, " : ")?;
+                return else_.fmt_expr(f, 2);
+            }
+            NodeKind::If2Expr(test, then, else_) => {
+                write!(f, "(if (")?;
+                test.fmt_expr(f, 0)?;
+                write!(f, ") ")?;
+                then.fmt_expr(f, TERM)?;
+                write!(f, " ")?;
+                else_.fmt_expr(f, TERM)?;
+                return write!(f, ")");
+            }
+            NodeKind::BitNot(x) => {
+                write!(f, "~")?;
+                return x.fmt_expr(f, TERM);
+            }
+            // Parenthesized rather than `--`, which is a token of its own
+            NodeKind::Neg(x) if matches!(x.kind, NodeKind::Neg(_) | NodeKind::Cst(..0)) => {
+                write!(f, "-(")?;
+                x.fmt_expr(f, 0)?;
+                return write!(f, ")");
+            }
+            NodeKind::Neg(x) => {
+                write!(f, "-")?;
+                return x.fmt_expr(f, TERM);
+            }
+            NodeKind::Abs(x) => {
+                write!(f, "abs(")?;
+                x.fmt_expr(f, 0)?;
+                return write!(f, ")");
+            }
+            NodeKind::Min(a, b) | NodeKind::Max(a, b) => {
+                let name = if matches!(self.kind, NodeKind::Min(..)) {
+                    "min"
+                } else {
+                    "max"
+                };
+                write!(f, "{name}(")?;
+                a.fmt_expr(f, 0)?;
+                write!(f, ", ")?;
+                b.fmt_expr(f, 0)?;
+                return write!(f, ")");
+            }
+            NodeKind::Or(a, b) => ("||", a, b, prec, prec + 1),
+            NodeKind::And(a, b) => ("&&", a, b, prec, prec + 1),
+            NodeKind::BitOr(a, b) => ("|", a, b, prec, prec + 1),
+            NodeKind::BitXor(a, b) => ("^", a, b, prec, prec + 1),
+            NodeKind::BitAnd(a, b) => ("&", a, b, prec, prec + 1),
+            NodeKind::Lt(a, b) => ("<", a, b, prec + 1, prec + 1),
+            NodeKind::Gt(a, b) => (">", a, b, prec + 1, prec + 1),
+            NodeKind::Le(a, b) => ("<=", a, b, prec + 1, prec + 1),
+            NodeKind::Ge(a, b) => (">=", a, b, prec + 1, prec + 1),
+            NodeKind::Eq(a, b) => ("==", a, b, prec + 1, prec + 1),
+            NodeKind::Ne(a, b) => ("!=", a, b, prec + 1, prec + 1),
+            NodeKind::Shl(a, b) => ("<<", a, b, prec, prec + 1),
+            NodeKind::Shr(a, b) => (">>", a, b, prec, prec + 1),
+            NodeKind::Add(a, b) => ("+", a, b, prec, prec + 1),
+            NodeKind::Sub(a, b) => ("-", a, b, prec, prec + 1),
+            NodeKind::Mul(a, b) => ("*", a, b, prec, prec + 1),
+            NodeKind::Div(a, b) => ("/", a, b, prec, prec + 1),
+            NodeKind::Mod(a, b) => ("%", a, b, prec, prec + 1),
+            _ => return self.fmt_stmt(f, 0),
+        };
+        a.fmt_expr(f, left)?;
+        write!(f, " {op} ")?;
+        b.fmt_expr(f, right)
+    }
+
+    /// Does the statement end with an `if` that has no `else`?  If
+    /// so, it can't be the `then` part of an `if`-`else` without
+    /// braces, as the `else` would bind to the wrong `if`.
+    fn dangles(&self) -> bool {
+        match &self.kind {
+            NodeKind::If1(..) => true,
+            NodeKind::If2(_, _, s) | NodeKind::While(_, s) | NodeKind::For(_, _, _, s) => {
+                s.dangles()
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the statement, which is assumed to start at the current
+    /// indentation `level`, followed by a newline
+    #[allow(clippy::too_many_lines)]
+    fn fmt_stmt(&self, f: &mut fmt::Formatter<'_>, level: usize) -> fmt::Result {
+        match &self.kind {
+            NodeKind::Prog(body) => match &body.kind {
+                // The function definitions, one after the other, and
+                // then the main statement
+                NodeKind::Block(stmts) if matches!(stmts[0].kind, NodeKind::FnDef(..)) => {
+                    for s in stmts {
+                        s.fmt_stmt(f, level)?;
+                    }
+                    Ok(())
+                }
+                _ => body.fmt_stmt(f, level),
+            },
+            NodeKind::FnDef(name, body) => {
+                write!(f, "fn {name}()")?;
+                body.fmt_body(f, level, false)
+            }
+            NodeKind::Seq(..) => {
+                // `{ a; b; c; }` built as `Seq(Seq(a, b), c)`, so only
+                // the left spine belongs to this block
+                let mut items = vec![];
+                let mut s = self;
+                while let NodeKind::Seq(a, b) = &s.kind {
+                    items.push(b);
+                    s = a;
+                }
+                writeln!(f, "{{")?;
+                write!(f, "{:1$}", "", (level + 1) * INDENT)?;
+                s.fmt_stmt(f, level + 1)?;
+                for item in items.iter().rev() {
+                    write!(f, "{:1$}", "", (level + 1) * INDENT)?;
+                    item.fmt_stmt(f, level + 1)?;
+                }
+                writeln!(f, "{:1$}}}", "", level * INDENT)
+            }
+            NodeKind::Block(stmts) => {
+                writeln!(f, "{{")?;
+                for s in stmts {
+                    write!(f, "{:1$}", "", (level + 1) * INDENT)?;
+                    s.fmt_stmt(f, level + 1)?;
+                }
+                writeln!(f, "{:1$}}}", "", level * INDENT)
+            }
+            NodeKind::If1(test, then) => {
+                write!(f, "if (")?;
+                test.fmt_expr(f, 0)?;
+                write!(f, ")")?;
+                then.fmt_body(f, level, false)
+            }
+            NodeKind::If2(test, then, else_) => {
+                write!(f, "if (")?;
+                test.fmt_expr(f, 0)?;
+                write!(f, ")")?;
+                then.fmt_body(f, level, then.dangles())?;
+                write!(f, "{:1$}else", "", level * INDENT)?;
+                if matches!(else_.kind, NodeKind::If1(..) | NodeKind::If2(..)) {
+                    write!(f, " ")?;
+                    else_.fmt_stmt(f, level)
+                } else {
+                    else_.fmt_body(f, level, false)
+                }
+            }
+            NodeKind::While(test, body) => {
+                write!(f, "while (")?;
+                test.fmt_expr(f, 0)?;
+                write!(f, ")")?;
+                body.fmt_body(f, level, false)
+            }
+            NodeKind::For(init, test, step, body) => {
+                write!(f, "for (")?;
+                init.fmt_expr(f, 0)?;
+                write!(f, "; ")?;
+                test.fmt_expr(f, 0)?;
+                write!(f, "; ")?;
+                step.fmt_expr(f, 0)?;
+                write!(f, ")")?;
+                body.fmt_body(f, level, false)
+            }
+            NodeKind::Switch(value, cases, default) => {
+                write!(f, "switch (")?;
+                value.fmt_expr(f, 0)?;
+                writeln!(f, ") {{")?;
+                for (n, body) in cases {
+                    write!(f, "{:1$}case {n}:", "", level * INDENT)?;
+                    body.fmt_body(f, level, false)?;
+                }
+                if let Some(body) = default {
+                    write!(f, "{:1$}default:", "", level * INDENT)?;
+                    body.fmt_body(f, level, false)?;
+                }
+                writeln!(f, "{:1$}}}", "", level * INDENT)
+            }
+            NodeKind::Do(body, test) => {
+                write!(f, "do")?;
+                body.fmt_body(f, level, false)?;
+                write!(f, "{:1$}while (", "", level * INDENT)?;
+                test.fmt_expr(f, 0)?;
+                writeln!(f, ");")
+            }
+            NodeKind::Print(x) => {
+                write!(f, "print ")?;
+                x.fmt_expr(f, 0)?;
+                writeln!(f, ";")
+            }
+            NodeKind::Read(v) => writeln!(f, "read {v};"),
+            NodeKind::Decl(v) => writeln!(f, "int {v};"),
+            NodeKind::Break => writeln!(f, "break;"),
+            NodeKind::Continue => writeln!(f, "continue;"),
+            NodeKind::Empty => writeln!(f, ";"),
+            NodeKind::Expr(x) => {
+                x.fmt_expr(f, 0)?;
+                writeln!(f, ";")
+            }
+            _ => {
+                self.fmt_expr(f, 0)?;
+                writeln!(f)
+            }
+        }
+    }
+
+    /// Writes the body of a control statement, either as a block on
+    /// the same line or on its own line one level further in.  With
+    /// `braces`, even a single statement is wrapped in a block.
+    fn fmt_body(&self, f: &mut fmt::Formatter<'_>, level: usize, braces: bool) -> fmt::Result {
+        if matches!(self.kind, NodeKind::Seq(..) | NodeKind::Block(..)) {
+            write!(f, " ")?;
+            self.fmt_stmt(f, level)
+        } else if braces {
+            writeln!(f, " {{")?;
+            write!(f, "{:1$}", "", (level + 1) * INDENT)?;
+            self.fmt_stmt(f, level + 1)?;
+            writeln!(f, "{:1$}}}", "", level * INDENT)
+        } else {
+            writeln!(f)?;
+            write!(f, "{:1$}", "", (level + 1) * INDENT)?;
+            self.fmt_stmt(f, level + 1)
+        }
+    }
+}
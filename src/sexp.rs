@@ -0,0 +1,385 @@
+//! The abstract syntax tree as S-expressions, eg.
+//!
+//! ```text
+//! (Prog (Block (Expr (Set i 1)) (While (Lt i 100) (Expr (Set i (Add i i))))))
+//! ```
+//!
+//! Each node is a list of its kind and its children, except that a
+//! variable is just its name and a constant just its value.  Unlike
+//! the `Debug` output this leaves out the spans, and it's easier for
+//! other tools to read than Tiny-C.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::lexer::{SourcePosition, Span};
+use crate::parser::{walk_node, AstVisitor, BNode, Node, NodeArena, NodeKind, ParseError};
+use std::fmt::Write;
+
+/// The tree below `node` as an S-expression.  The name of a `Read`,
+/// `Decl`, `Call`, or `FnDef`, and the key of a `Ref`, follow the kind, and
+/// the cases of a `switch` are `(Case <int> <body>)` and
+/// `(Default <body>)`.
+#[must_use]
+pub fn to_sexp(node: &Node) -> String {
+    let mut writer = SexpWriter::default();
+    writer.visit_node(node);
+    writer.output
+}
+
+#[derive(Default)]
+struct SexpWriter {
+    output: String,
+}
+
+impl SexpWriter {
+    /// Start an element of the list being written
+    fn separate(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with('(') {
+            self.output.push(' ');
+        }
+    }
+}
+
+impl AstVisitor for SexpWriter {
+    fn visit_node(&mut self, node: &Node) {
+        self.separate();
+        match &node.kind {
+            NodeKind::Var(name) => self.output += name,
+            NodeKind::Cst(value) => self.output += &value.to_string(),
+            _ => {
+                self.output.push('(');
+                self.output += &node.label();
+                walk_node(self, node);
+                self.output.push(')');
+            }
+        }
+    }
+
+    fn visit_switch(&mut self, value: &Node, cases: &[(isize, BNode)], default: Option<&Node>) {
+        self.visit_node(value);
+        for (n, body) in cases {
+            self.separate();
+            write!(self.output, "(Case {n}").unwrap();
+            self.visit_node(body);
+            self.output.push(')');
+        }
+        if let Some(body) = default {
+            self.separate();
+            self.output += "(Default";
+            self.visit_node(body);
+            self.output.push(')');
+        }
+    }
+
+    fn visit_ref(&mut self, key: u64) {
+        write!(self.output, " {key}").unwrap();
+    }
+}
+
+/// Read back a tree written by `to_sexp`.  The nodes have no spans,
+/// as there's no Tiny-C source they came from.
+///
+/// ```
+/// use tinyc_in_rust::parser::{parse, NodeArena};
+/// use tinyc_in_rust::sexp::{from_sexp, to_sexp};
+/// let arena = NodeArena::new();
+/// let ast = from_sexp(&arena, "(Prog (Expr (Set a (Mul 6 7))))").unwrap();
+/// assert_eq!(ast, parse(&arena, "a = 6 * 7;").unwrap());
+/// assert_eq!(to_sexp(ast), "(Prog (Expr (Set a (Mul 6 7))))");
+/// ```
+///
+/// # Errors
+/// Returns a `ParseError` if `src` isn't the S-expression of a tree
+pub fn from_sexp<'a>(arena: &'a NodeArena<'a>, src: &str) -> Result<BNode<'a>, ParseError> {
+    let mut reader = SexpReader {
+        itr: src.chars().peekable(),
+        pos: SourcePosition::new(1, 1),
+    };
+    let sexp = reader.read()?;
+    reader.skip_space();
+    if reader.itr.peek().is_some() {
+        return reader.error("end of input expected");
+    }
+    to_node(arena, sexp)
+}
+
+/// An S-expression with the position where it starts
+enum Sexp {
+    Atom(String, SourcePosition),
+    List(Vec<Sexp>, SourcePosition),
+}
+
+impl Sexp {
+    fn pos(&self) -> SourcePosition {
+        match self {
+            Sexp::Atom(_, pos) | Sexp::List(_, pos) => *pos,
+        }
+    }
+}
+
+struct SexpReader<'a> {
+    itr: std::iter::Peekable<std::str::Chars<'a>>,
+    pos: SourcePosition,
+}
+
+impl SexpReader<'_> {
+    fn next_ch(&mut self) {
+        if let Some(c) = self.itr.next() {
+            self.pos = self.pos.advance(c);
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while self.itr.peek().is_some_and(|c| c.is_whitespace()) {
+            self.next_ch();
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            pos: self.pos,
+            message: message.to_string(),
+            source_line: String::new(),
+            cause: None,
+        })
+    }
+
+    /// Read an atom or a list
+    fn read(&mut self) -> Result<Sexp, ParseError> {
+        self.skip_space();
+        let pos = self.pos;
+        match self.itr.peek() {
+            None => self.error("unexpected end of input"),
+            Some(')') => self.error("unexpected `)'"),
+            Some('(') => {
+                self.next_ch();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_space();
+                    if self.itr.peek() == Some(&')') {
+                        self.next_ch();
+                        return Ok(Sexp::List(items, pos));
+                    }
+                    items.push(self.read()?);
+                }
+            }
+            Some(_) => {
+                let mut atom = String::new();
+                while let Some(&c) = self.itr.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    atom.push(c);
+                    self.next_ch();
+                }
+                Ok(Sexp::Atom(atom, pos))
+            }
+        }
+    }
+}
+
+fn sexp_error<T>(pos: SourcePosition, message: &str) -> Result<T, ParseError> {
+    Err(ParseError {
+        pos,
+        message: message.to_string(),
+        source_line: String::new(),
+        cause: None,
+    })
+}
+
+fn node<'a>(arena: &'a NodeArena<'a>, kind: NodeKind<'a>, pos: SourcePosition) -> BNode<'a> {
+    arena.alloc(Node {
+        kind,
+        span: Span {
+            start: pos,
+            end: pos,
+        },
+    })
+}
+
+/// The tree for `sexp`
+#[allow(clippy::too_many_lines)]
+fn to_node<'a>(arena: &'a NodeArena<'a>, sexp: Sexp) -> Result<BNode<'a>, ParseError> {
+    let (items, pos) = match sexp {
+        Sexp::Atom(atom, pos) => {
+            if let Ok(value) = atom.parse() {
+                return Ok(node(arena, NodeKind::Cst(value), pos));
+            }
+            if !atom.starts_with(|c: char| c.is_ascii_lowercase())
+                || !atom.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            {
+                return sexp_error(pos, &format!("bad atom `{atom}'"));
+            }
+            return Ok(node(arena, NodeKind::Var(arena.name(&atom)), pos));
+        }
+        Sexp::List(items, pos) => (items, pos),
+    };
+    let mut items = items.into_iter();
+    let Some(Sexp::Atom(kind, _)) = items.next() else {
+        return sexp_error(pos, "kind of node expected");
+    };
+    let rest: Vec<Sexp> = items.collect();
+
+    let binary = |op: fn(BNode<'a>, BNode<'a>) -> NodeKind<'a>, rest| -> Result<_, ParseError> {
+        let [a, b] = children(arena, rest, pos)?;
+        Ok(op(a, b))
+    };
+    let kind = match kind.as_str() {
+        "Add" => binary(NodeKind::Add, rest)?,
+        "Sub" => binary(NodeKind::Sub, rest)?,
+        "Mul" => binary(NodeKind::Mul, rest)?,
+        "Div" => binary(NodeKind::Div, rest)?,
+        "Mod" => binary(NodeKind::Mod, rest)?,
+        "Shl" => binary(NodeKind::Shl, rest)?,
+        "Shr" => binary(NodeKind::Shr, rest)?,
+        "Lt" => binary(NodeKind::Lt, rest)?,
+        "Gt" => binary(NodeKind::Gt, rest)?,
+        "Le" => binary(NodeKind::Le, rest)?,
+        "Ge" => binary(NodeKind::Ge, rest)?,
+        "Eq" => binary(NodeKind::Eq, rest)?,
+        "Ne" => binary(NodeKind::Ne, rest)?,
+        "BitAnd" => binary(NodeKind::BitAnd, rest)?,
+        "BitOr" => binary(NodeKind::BitOr, rest)?,
+        "BitXor" => binary(NodeKind::BitXor, rest)?,
+        "Min" => binary(NodeKind::Min, rest)?,
+        "Max" => binary(NodeKind::Max, rest)?,
+        "And" => binary(NodeKind::And, rest)?,
+        "Or" => binary(NodeKind::Or, rest)?,
+        "Set" => binary(NodeKind::Set, rest)?,
+        "AddAssign" => binary(NodeKind::AddAssign, rest)?,
+        "If1" => binary(NodeKind::If1, rest)?,
+        "While" => binary(NodeKind::While, rest)?,
+        "Do" => binary(NodeKind::Do, rest)?,
+        "Seq" => binary(NodeKind::Seq, rest)?,
+        "BitNot" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::BitNot(x)
+        }
+        "Neg" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::Neg(x)
+        }
+        "Abs" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::Abs(x)
+        }
+        "Print" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::Print(x)
+        }
+        "Expr" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::Expr(x)
+        }
+        "Prog" => {
+            let [x] = children(arena, rest, pos)?;
+            NodeKind::Prog(x)
+        }
+        "Ternary" => {
+            let [a, b, c] = children(arena, rest, pos)?;
+            NodeKind::Ternary(a, b, c)
+        }
+        "If2Expr" => {
+            let [a, b, c] = children(arena, rest, pos)?;
+            NodeKind::If2Expr(a, b, c)
+        }
+        "If2" => {
+            let [a, b, c] = children(arena, rest, pos)?;
+            NodeKind::If2(a, b, c)
+        }
+        "For" => {
+            let [a, b, c, d] = children(arena, rest, pos)?;
+            NodeKind::For(a, b, c, d)
+        }
+        "Block" => NodeKind::Block(
+            rest.into_iter()
+                .map(|s| to_node(arena, s))
+                .collect::<Result<_, _>>()?,
+        ),
+        "Break" | "Continue" | "Empty" | "Rand" => {
+            children::<0>(arena, rest, pos)?;
+            match kind.as_str() {
+                "Break" => NodeKind::Break,
+                "Continue" => NodeKind::Continue,
+                "Rand" => NodeKind::Rand,
+                _ => NodeKind::Empty,
+            }
+        }
+        "Read" => NodeKind::Read(name(arena, rest, pos)?),
+        "Decl" => NodeKind::Decl(name(arena, rest, pos)?),
+        "Call" => NodeKind::Call(name(arena, rest, pos)?),
+        "FnDef" => {
+            let mut rest = rest.into_iter();
+            let name = name(arena, rest.next().into_iter().collect(), pos)?;
+            let [body] = children(arena, rest.collect(), pos)?;
+            NodeKind::FnDef(name, body)
+        }
+        "Ref" => match &rest[..] {
+            [Sexp::Atom(key, key_pos)] => match key.parse() {
+                Ok(key) => NodeKind::Ref(key),
+                Err(_) => return sexp_error(*key_pos, "key expected"),
+            },
+            _ => return sexp_error(pos, "key expected"),
+        },
+        "Switch" => {
+            let mut rest = rest.into_iter();
+            let Some(value) = rest.next() else {
+                return sexp_error(pos, "value expected");
+            };
+            let value = to_node(arena, value)?;
+            let mut cases = Vec::new();
+            let mut default = None;
+            for case in rest {
+                let case_pos = case.pos();
+                let Sexp::List(items, _) = case else {
+                    return sexp_error(case_pos, "case expected");
+                };
+                match &items[..] {
+                    [Sexp::Atom(kind, _), Sexp::Atom(n, _), _] if kind == "Case" => {
+                        let Ok(n) = n.parse() else {
+                            return sexp_error(case_pos, "case value expected");
+                        };
+                        let body = items.into_iter().nth(2).unwrap();
+                        cases.push((n, to_node(arena, body)?));
+                    }
+                    [Sexp::Atom(kind, _), _] if kind == "Default" && default.is_none() => {
+                        let body = items.into_iter().nth(1).unwrap();
+                        default = Some(to_node(arena, body)?);
+                    }
+                    _ => return sexp_error(case_pos, "case expected"),
+                }
+            }
+            NodeKind::Switch(value, cases, default)
+        }
+        _ => return sexp_error(pos, &format!("unknown kind of node `{kind}'")),
+    };
+    Ok(node(arena, kind, pos))
+}
+
+/// The `N` children of the node at `pos`
+fn children<'a, const N: usize>(
+    arena: &'a NodeArena<'a>,
+    rest: Vec<Sexp>,
+    pos: SourcePosition,
+) -> Result<[BNode<'a>; N], ParseError> {
+    let nodes = rest
+        .into_iter()
+        .map(|s| to_node(arena, s))
+        .collect::<Result<Vec<_>, _>>()?;
+    match nodes.try_into() {
+        Ok(nodes) => Ok(nodes),
+        Err(_) => sexp_error(pos, &format!("{N} children expected")),
+    }
+}
+
+/// The name that is all of `rest`
+fn name<'a>(
+    arena: &'a NodeArena<'a>,
+    rest: Vec<Sexp>,
+    pos: SourcePosition,
+) -> Result<&'a str, ParseError> {
+    match <[Sexp; 1]>::try_from(rest) {
+        Ok([Sexp::Atom(name, _)]) => Ok(arena.name(&name)),
+        _ => sexp_error(pos, "name expected"),
+    }
+}
@@ -0,0 +1,84 @@
+//! The symbol table gives each global variable its slot in the VM's
+//! globals.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use std::collections::HashMap;
+
+/// Maps the names of the global variables to their slots.  The
+/// single letter names `a` to `z` always have the slots 0 to 25, so
+/// code using just those doesn't depend on the table; longer names
+/// get the next free slot the first time they are seen.
+#[derive(Clone, Debug)]
+pub struct SymbolTable {
+    table: HashMap<String, usize>,
+    next_slot: usize,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        let mut symbols = SymbolTable {
+            table: HashMap::new(),
+            next_slot: 0,
+        };
+        for c in 'a'..='z' {
+            symbols.intern(&c.to_string());
+        }
+        symbols
+    }
+}
+
+impl SymbolTable {
+    #[must_use]
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    /// The slot of `name`, giving it a fresh one if it's new
+    pub fn intern(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.table.get(name) {
+            return slot;
+        }
+        let slot = self.next_slot;
+        self.table.insert(name.to_string(), slot);
+        self.next_slot += 1;
+        slot
+    }
+
+    /// The slot of `name`, if it has one
+    #[must_use]
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.table.get(name).copied()
+    }
+
+    /// The name with the slot `slot`, if any
+    #[must_use]
+    pub fn name(&self, slot: usize) -> Option<&str> {
+        self.table
+            .iter()
+            .find_map(|(name, &s)| (s == slot).then_some(name.as_str()))
+    }
+
+    /// The number of slots given out
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.next_slot
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.next_slot == 0
+    }
+
+    /// The names with their slots, in the order of the slots
+    #[must_use]
+    pub fn names(&self) -> Vec<(&str, usize)> {
+        let mut names: Vec<_> = self
+            .table
+            .iter()
+            .map(|(name, &slot)| (name.as_str(), slot))
+            .collect();
+        names.sort_by_key(|&(_, slot)| slot);
+        names
+    }
+}
@@ -1,35 +1,599 @@
 #![warn(clippy::all, clippy::pedantic)]
-use crate::codegen::compile;
+use crate::bytecode::{decode, encode, DecodeError};
+use crate::codegen::{
+    assemble, compact, compile, compile_expr, compile_with_source_map, disassemble, peephole, Insn,
+};
+use crate::desugar::desugar;
 use crate::lexer::{Lexer, Token};
-use crate::parser::parse;
+use crate::optimizer::{algebraic_simplify, eliminate_dead_code, fold_constants};
+use crate::parser::{parse, Node, NodeArena, NodeKind};
+use crate::vm::{StepResult, TraceFormat, VmError, GLOBALS};
 use insta::assert_snapshot;
+use proptest::prelude::{
+    any, prop, prop_assert_eq, prop_oneof, proptest, BoxedStrategy, Just, ProptestConfig, Strategy,
+};
+use proptest::sample::select;
+use proptest::strategy::{Union, ValueTree};
+use proptest::test_runner::TestRunner;
+use std::fmt::Write;
+
+/// An arena for a test, which is never freed
+fn arena() -> &'static NodeArena<'static> {
+    Box::leak(Box::default())
+}
 
 // *** Lexer Testing ***
 
 #[test]
 fn test_lexer() {
-    let mut lex = Lexer::new("2 3 alpha beta ={}");
-    assert!(matches!(lex.get_token().1, Token::Int(2)));
-    assert!(matches!(lex.get_token().1, Token::Int(3)));
-    assert!(match lex.get_token().1 {
-        Token::Id(v) => v == "alpha",
+    let mut lex = Lexer::new("2 3 alpha beta ={} == != < <= > >= && || + ++ - --");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(2)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(3)));
+    assert!(match lex.get_token().unwrap().1 {
+        Token::Id(v) => lex.interner().resolve(v) == "alpha",
         _ => false,
     });
-    assert!(match lex.get_token().1 {
-        Token::Id(v) => v == "beta",
+    assert!(match lex.get_token().unwrap().1 {
+        Token::Id(v) => lex.interner().resolve(v) == "beta",
         _ => false,
     });
-    assert!(matches!(lex.get_token().1, Token::Equal));
-    assert!(matches!(lex.get_token().1, Token::Lbra));
-    assert!(matches!(lex.get_token().1, Token::Rbra));
-    assert!(matches!(lex.get_token().1, Token::Eoi));
-    assert!(matches!(lex.get_token().1, Token::Eoi));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Equal));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Lbra));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Rbra));
+    assert!(matches!(lex.get_token().unwrap().1, Token::EqEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::NotEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Less));
+    assert!(matches!(lex.get_token().unwrap().1, Token::LessEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Greater));
+    assert!(matches!(lex.get_token().unwrap().1, Token::GreaterEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::AmpAmp));
+    assert!(matches!(lex.get_token().unwrap().1, Token::PipePipe));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Plus));
+    assert!(matches!(lex.get_token().unwrap().1, Token::PlusPlus));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Minus));
+    assert!(matches!(lex.get_token().unwrap().1, Token::MinusMinus));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_error_display() {
+    use crate::lexer::SourcePosition;
+    assert_eq!(SourcePosition::new(3, 7).to_string(), "3:7");
+
+    let error = Lexer::new("a\n  $")
+        .into_tokens()
+        .find_map(Result::err)
+        .unwrap();
+    assert_eq!(error.to_string(), "input:2:3:Illegal token");
+    let error = parse(arena(), "{ a = 5 b = 6; }").unwrap_err();
+    assert_eq!(error.to_string(), "input:1:9:expected `;', got identifier");
+    let error = crate::codegen::check(parse(arena(), "{ a = 1; break; }").unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "input:1:10:`break' outside of a loop");
+    let span = parse(arena(), "a = 1;").unwrap().span;
+    assert_eq!(format!("{span:?}"), "1:1-1:7");
+}
+
+#[test]
+fn test_error_highlight() {
+    let error = parse(arena(), "{ a = 1;\n  b = 5 c = 6; }").unwrap_err();
+    assert_eq!(error.source_line, "  b = 5 c = 6; }");
+    assert_eq!(
+        format!("{error:#}"),
+        "input:2:9:expected `;', got identifier\n    b = 5 c = 6; }\n          ^"
+    );
+    // From the lexer, through the parser and the compiler
+    let error = crate::compile_to_asm("a = 1\r\n  + $;").unwrap_err();
+    assert_eq!(
+        format!("{error:#}"),
+        "input:2:5:Illegal token\n    + $;\n      ^"
+    );
+    assert_eq!(
+        format!("{:#}", parse(arena(), "a = 1 $ 2;").unwrap_err()),
+        "input:1:7:Illegal token\n  a = 1 $ 2;\n        ^"
+    );
+    // At the end, with no line to show
+    assert_eq!(
+        format!("{:#}", parse(arena(), "a = 1\n").unwrap_err()),
+        "input:2:1:expected `;', got end of input"
+    );
+}
+
+#[test]
+fn test_error_source() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::CompileError;
+    use std::error::Error;
+    // The messages of an error and those it came from
+    let chain = |e: &dyn Error| {
+        let mut messages = vec![e.to_string()];
+        let mut source = e.source();
+        while let Some(e) = source {
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        messages
+    };
+
+    // From the lexer, through the parser, to the compiler
+    let lexed = Lexer::new("a = 1 $ 2;").into_tokens().find_map(Result::err);
+    let lex_error = lexed.ok_or("no lexical error")?;
+    assert_eq!(chain(&lex_error), ["input:1:7:Illegal token"]);
+    let parse_error = parse(arena(), "a = 1 $ 2;").unwrap_err();
+    assert!(parse_error.cause.is_some());
+    assert_eq!(chain(&parse_error), ["input:1:7:Illegal token"; 2]);
+    // which the compiler reports as the lexical error it is
+    let compile_error = CompileError::from(parse_error);
+    assert!(matches!(compile_error, CompileError::Lex(_)));
+    assert_eq!(chain(&compile_error), ["input:1:7:Illegal token"; 2]);
+    let compile_error =
+        crate::compile_and_run(&mut crate::vm::VM::new(), "a = 1 $ 2;").unwrap_err();
+    assert!(matches!(compile_error, CompileError::Lex(_)));
+    assert_eq!(chain(&compile_error), ["input:1:7:Illegal token"; 2]);
+
+    // A syntax error has no cause
+    assert!(parse(arena(), "a = ;").unwrap_err().source().is_none());
+    let compile_error =
+        crate::compile_and_run(&mut crate::vm::VM::new(), "a = 1 / b;").unwrap_err();
+    assert_eq!(
+        chain(&compile_error),
+        ["runtime error: 4: division by zero", "4: division by zero"]
+    );
+
+    // And it works with `?`
+    let globals = crate::compile_and_run_returning(&mut crate::vm::VM::new(), "a = 2 + 3;")?;
+    assert_eq!(globals[0], 5);
+    Ok(())
+}
+
+#[test]
+fn test_token_display_name() {
+    let src =
+        "abs break case continue default do else fn for if int max min print rand read switch while
+               { } ( ) + ++ += - -- * / % < <= > >= << >> ; ? : , = == != && || & | ^ ~ _";
+    for (word, token) in src.split_whitespace().zip(Lexer::new(src).into_tokens()) {
+        assert_eq!(token.unwrap().1.display_name(), format!("`{word}'"));
+    }
+    assert_eq!(Token::Int(42).display_name(), "integer literal");
+    let mut lex = Lexer::new("x");
+    assert_eq!(lex.get_token().unwrap().1.display_name(), "identifier");
+    assert_eq!(lex.get_token().unwrap().1.display_name(), "end of input");
+}
+
+#[test]
+fn test_lexer_bitwise() {
+    let mut lex = Lexer::new("& && | || ^ ~a&b");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Amp));
+    assert!(matches!(lex.get_token().unwrap().1, Token::AmpAmp));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Pipe));
+    assert!(matches!(lex.get_token().unwrap().1, Token::PipePipe));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Caret));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Tilde));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Id(_)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Amp));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Id(_)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_lexer_shifts() {
+    let mut lex = Lexer::new("<< < <= >> > >= <<<");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Shl));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Less));
+    assert!(matches!(lex.get_token().unwrap().1, Token::LessEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Shr));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Greater));
+    assert!(matches!(lex.get_token().unwrap().1, Token::GreaterEq));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Shl));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Less));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_lexer_hex() {
+    let mut lex = Lexer::new("0xFF 0x0 0xDEAD 0Xbeef 0 10");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(255)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0xDEAD)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0xBEEF)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(10)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_lexer_decimal_overflow() {
+    let mut lex = Lexer::new("9223372036854775807 9223372036854775808");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(isize::MAX)));
+    assert_eq!(
+        lex.get_token().unwrap_err().to_string(),
+        "input:1:21:Illegal decimal constant"
+    );
+}
+
+#[test]
+fn test_lexer_octal() {
+    let mut lex = Lexer::new("0755 0644 07 00");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0o755)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0o644)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(7)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Int(0)));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_lexer_line_comment() {
+    let mut lex = Lexer::new("a // b c\n/ d // e\n// f\n\ng//");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Id(v) if lex.interner().resolve(v) == "a"));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Slash));
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "d"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 2, col: 3 }");
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "g"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 5, col: 1 }");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_lexer_block_comment() {
+    let mut lex = Lexer::new("a /* b\n c */ / d /* e * / **/\n/* /* f */g/***/");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Id(v) if lex.interner().resolve(v) == "a"));
+    assert!(matches!(lex.get_token().unwrap().1, Token::Slash));
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "d"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 2, col: 9 }");
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "g"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 3, col: 11 }");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Eoi));
+}
+
+#[test]
+fn test_run_line_comment() {
+    let g = run("{ a = 1; // a = 2;\n b = a / 1; /* b = 2;\n */ }");
+    assert_eq!(g[var('a')], 1);
+    assert_eq!(g[var('b')], 1);
+}
+
+#[test]
+fn test_many_comments() {
+    // Comments in a row take no more stack than one
+    for comment in ["//x\n", "/**/"] {
+        let src = comment.repeat(300_000) + "a = 1;";
+        assert_eq!(run(&src)[var('a')], 1, "{comment:?}");
+    }
+    let mut lex = Lexer::new("/**/ /* b */\n /*");
+    let e = lex.get_token().unwrap_err();
+    assert_eq!(
+        (e.pos.line(), e.pos.col(), e.message.as_str()),
+        (2, 2, "unterminated comment")
+    );
+}
+
+#[test]
+fn test_source_position_advance() {
+    use crate::lexer::SourcePosition;
+    let at = |pos: SourcePosition| (pos.line(), pos.col());
+    let start = SourcePosition::new(1, 1);
+    assert_eq!(at(start.advance('a')), (1, 2));
+    assert_eq!(at(start.advance('a').advance(' ')), (1, 3));
+    assert_eq!(at(SourcePosition::new(3, 7).advance('\n')), (4, 1));
+    assert_eq!(at(SourcePosition::new(3, 7).advance('\r')), (3, 7));
+    assert_eq!(at(start.advance('\t')), (1, 9));
+    assert_eq!(at(SourcePosition::new(2, 8).advance('\t')), (2, 9));
+    assert_eq!(at(SourcePosition::new(2, 9).advance('\t')), (2, 17));
+    assert_eq!(
+        at(SourcePosition::new(2, 3).advance_with_tab_width('\t', 4)),
+        (2, 5)
+    );
+    let end = "a\tb\r\n\tc".chars().fold(start, SourcePosition::advance);
+    assert_eq!(at(end), (2, 10));
+}
+
+#[test]
+fn test_lexer_tabs() {
+    let mut lex = Lexer::new("{\n\ta;\n  \tb;\n\t\t c\t d");
+    assert!(matches!(lex.get_token().unwrap().1, Token::Lbra));
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 2, col: 9 }");
+    lex.get_token().unwrap();
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 3, col: 9 }");
+    lex.get_token().unwrap();
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 4, col: 18 }");
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 4, col: 26 }");
+
+    let mut lex = Lexer::with_tab_width("\ta\t\tb", 4);
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 1, col: 5 }");
+    let (pos, _) = lex.get_token().unwrap();
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 1, col: 13 }");
+}
+
+#[test]
+#[should_panic(expected = "a tab width of 0")]
+fn test_lexer_zero_tab_width() {
+    let _ = Lexer::with_tab_width("\ta", 0);
+}
+
+#[test]
+fn test_lexer_crlf() {
+    let mut lex = Lexer::new("a;\r\n b; // c\r\n\r\nd");
+    lex.get_token().unwrap();
+    lex.get_token().unwrap();
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "b"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 2, col: 2 }");
+    lex.get_token().unwrap();
+    let (pos, token) = lex.get_token().unwrap();
+    assert!(matches!(token, Token::Id(v) if lex.interner().resolve(v) == "d"));
+    assert_eq!(format!("{pos:?}"), "SourcePosition { line: 4, col: 1 }");
+}
+
+#[test]
+fn test_run_tabs_and_crlf() {
+    let g = run("{\r\n\ti = 1;\r\n\twhile (i < 100)\r\n\t\ti = i + i;\r\n}\r\n");
+    assert_eq!(g[var('i')], 128);
+}
+
+#[test]
+fn test_lexer_into_tokens() {
+    let tokens = Lexer::new("{ i = 0x10; while (i) i = i - 1; }")
+        .into_tokens()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let tokens: Vec<String> = tokens.iter().map(|(_, t)| format!("{t:?}")).collect();
+    assert_eq!(
+        tokens.join(" "),
+        "Lbra Id(InternedStr(0)) Equal Int(16) Semi WhileSym Lpar Id(InternedStr(0)) Rpar \
+         Id(InternedStr(0)) Equal Id(InternedStr(0)) Minus Int(1) Semi Rbra"
+    );
+
+    // The iterator ends after the first error
+    let tokens: Vec<_> = Lexer::new("a = 1 # 2;").into_tokens().collect();
+    assert_eq!(tokens.len(), 4);
+    assert!(tokens[..3].iter().all(Result::is_ok));
+    let Err(error) = &tokens[3] else {
+        panic!("expected an error, got {:?}", tokens[3]);
+    };
+    assert_eq!(error.to_string(), "input:1:7:Illegal token");
+}
+
+#[test]
+fn test_interner() {
+    // A thousand references to a few names keep a copy of each name
+    let src = "{ total = 0; i = 0; ".to_string() + &"total = total + i; ".repeat(333) + "}";
+    let mut lex = Lexer::new(&src);
+    let mut ids = Vec::new();
+    loop {
+        match lex.get_token().unwrap().1 {
+            Token::Eoi => break,
+            Token::Id(id) => ids.push(id),
+            _ => {}
+        }
+    }
+    assert_eq!(ids.len(), 1001);
+    assert_eq!(lex.interner().len(), 2);
+    assert_eq!(lex.interner().resolve(ids[0]), "total");
+    assert_eq!(lex.interner().resolve(ids[1000]), "i");
+    assert_eq!(ids[0], ids[999]);
+    assert_eq!(run(&src)[var('i')], 0);
+}
+
+// *** Arena Testing ***
+
+#[test]
+fn test_arena() {
+    use crate::arena::Arena;
+    use std::rc::Rc;
+
+    // The values stay where they are as the arena grows, and are
+    // dropped with it
+    let counted = Rc::new(());
+    let arena = Arena::new();
+    assert!(arena.is_empty());
+    let values: Vec<&(usize, Rc<()>)> = (0..1000)
+        .map(|i| &*arena.alloc((i, counted.clone())))
+        .collect();
+    assert!(values.iter().enumerate().all(|(i, value)| value.0 == i));
+    assert_eq!(arena.len(), 1000);
+    assert_eq!(arena.chunks(), 5);
+    assert_eq!(Rc::strong_count(&counted), 1001);
+    drop(arena);
+    assert_eq!(Rc::strong_count(&counted), 1);
+}
+
+#[test]
+fn test_parse_into_arena() {
+    // The value assigned by the last statement
+    fn value<'a>(ast: &'a Node<'a>) -> &'a Node<'a> {
+        match &ast.kind {
+            NodeKind::Prog(x) | NodeKind::Expr(x) | NodeKind::Set(_, x) => value(x),
+            NodeKind::Block(stmts) => value(stmts[1]),
+            _ => ast,
+        }
+    }
+
+    // One node in the arena for each in the tree, and a pass adds the
+    // nodes it rewrites, sharing the leaves it keeps
+    let arena = NodeArena::new();
+    let ast = parse(&arena, "{ a = 1 + 2; b = a; }").unwrap();
+    assert_eq!(arena.len(), 12);
+    let folded = fold_constants(&arena, ast);
+    assert_eq!(
+        crate::sexp::to_sexp(folded),
+        "(Prog (Block (Expr (Set a 3)) (Expr (Set b a))))"
+    );
+    assert_eq!(arena.len(), 19);
+    assert!(matches!(value(folded).kind, NodeKind::Var(_)));
+    assert!(std::ptr::eq(value(ast), value(folded)));
+}
+
+#[test]
+fn test_names_in_arena() {
+    // The uses of a variable all refer to the one copy of its name
+    let arena = NodeArena::new();
+    let ast = parse(&arena, "a = a + a;").unwrap();
+    let NodeKind::Prog(Node {
+        kind: NodeKind::Expr(set),
+        ..
+    }) = &ast.kind
+    else {
+        panic!("{ast:?}");
+    };
+    let NodeKind::Set(target, sum) = &set.kind else {
+        panic!("{set:?}");
+    };
+    let NodeKind::Add(x, y) = &sum.kind else {
+        panic!("{sum:?}");
+    };
+    let names: Vec<&str> = [target, x, y]
+        .iter()
+        .map(|var| match var.kind {
+            NodeKind::Var(name) => name,
+            _ => panic!("{var:?}"),
+        })
+        .collect();
+    assert_eq!(names, ["a", "a", "a"]);
+    assert!(names.iter().all(|name| std::ptr::eq(*name, names[0])));
+}
+
+// *** Pretty-printer Testing ***
+
+const PRETTY_EXAMPLES: [&str; 17] = [
+    "{ a = 1 - (2 - 3) * 4 % (5 / 6); b = (a = 2) + -a; c = (1 < 2) == ((3 > 4) != 0); }",
+    "x = a || b && c || (d || e) && (f < g);",
+    "{ a = b ? c ? 1 : 2 : (d ? 3 : 4) ? 5 : 6; i = ++i * --j; }",
+    "if (a) if (b) x = 1; else y = 1;",
+    "{ if (a) { if (b) x = 1; } else if (c) y = 1; else { z = 1; w = 2; } }",
+    "{ do { i = i + 1; if (i == 3) continue; } while (i < 10); for (i = 0; i < 3; ++i) ; }",
+    "{ while (1) { { a = 1; b = 2; } break; } { c = 3; d = 4; } }",
+    "a = (if (b < 5) (if (c) 1 2) -(3 + 4)) + 1;",
+    "{ a += b += 2; c = (d += 1) * 3; }",
+    "switch (a + 1) { case 1: b = 10; case 2: { b = 20; break; } default: switch (b) { } }",
+    "x = ~a & (b | c) ^ ~(d ^ e) | f & g == h && ~-i;",
+    "x = a << b + 1 >> (c << d) < e & f >> 2;",
+    "{ print a; print (b = 2) + 1; if (a) print a ? b : c; }",
+    "{ read n; while (n) read n; }",
+    "fn setup() { a = 1; b = 2; } { setup(); c = (if (a) b (c)) + go(); } fn go() if (a) go();",
+    "{ x = abs(a - b) * 2; { int a; a = -abs(-3); } }",
+    "x = min(a = 1, b) + max(min(c, d), e < f ? g : h);",
+];
+
+/// The `Debug` output of `ast` without the spans, which of course
+/// differ between the original source and the pretty-printed one
+fn without_spans(ast: &Node) -> String {
+    let mut s = String::new();
+    let mut in_span = false;
+    for c in format!("{ast:?}").chars() {
+        in_span = c == '@' || in_span && (c.is_ascii_digit() || c == ':' || c == '-');
+        if !in_span {
+            s.push(c);
+        }
+    }
+    s
+}
+
+#[test]
+fn test_pretty_round_trip() {
+    for src in EXAMPLES.iter().chain(PRETTY_EXAMPLES.iter()) {
+        let ast = parse(arena(), src).unwrap();
+        let pretty = format!("{ast}");
+        assert_eq!(
+            without_spans(parse(arena(), &pretty).unwrap()),
+            without_spans(ast),
+            "{src} was printed as\n{pretty}"
+        );
+    }
+}
+
+#[test]
+fn test_pretty() {
+    for src in PRETTY_EXAMPLES {
+        assert_snapshot!(format!("{}", parse(arena(), src).unwrap()));
+    }
+}
+
+// *** Visualization Testing ***
+
+/// Counts the nodes of a tree
+#[derive(Default)]
+struct NodeCounter(usize);
+
+impl crate::parser::AstVisitor for NodeCounter {
+    fn visit_node(&mut self, node: &Node) {
+        self.0 += 1;
+        crate::parser::walk_node(self, node);
+    }
+}
+
+#[test]
+fn test_to_dot() {
+    use crate::parser::AstVisitor;
+
+    for src in PRETTY_EXAMPLES {
+        let ast = parse(arena(), src).unwrap();
+        let dot = crate::visualize::to_dot(ast);
+        assert!(dot.starts_with("digraph"), "{dot}");
+        let mut counter = NodeCounter::default();
+        counter.visit_node(ast);
+        let nodes = dot
+            .lines()
+            .filter(|l| l.contains("[label=") && !l.contains("->"));
+        assert_eq!(nodes.count(), counter.0, "{src}");
+        let edges = dot.lines().filter(|l| l.contains("->"));
+        assert_eq!(edges.count(), counter.0 - 1, "{src}");
+    }
+    assert_snapshot!(crate::visualize::to_dot(
+        parse(arena(), "switch (a) { case 1: b = 2; default: ; }").unwrap()
+    ));
+}
+
+#[test]
+fn test_sexp() {
+    use crate::sexp::{from_sexp, to_sexp};
+
+    // Every tree reads back as itself, before and after the passes
+    for src in EXAMPLES.iter().chain(PRETTY_EXAMPLES.iter()) {
+        let ast = parse(arena(), src).unwrap();
+        let sexp = to_sexp(ast);
+        assert_eq!(from_sexp(arena(), &sexp).unwrap(), ast, "{sexp}");
+        let ast = fold_constants(arena(), desugar(arena(), ast));
+        assert_eq!(from_sexp(arena(), &to_sexp(ast)).unwrap(), ast, "{src}");
+    }
+    assert_eq!(
+        to_sexp(parse(arena(), "switch (a) { case 1: b = -2; default: read c; }").unwrap()),
+        "(Prog (Switch a (Case 1 (Expr (Set b (Neg 2)))) (Default (Read c))))"
+    );
+    let (ast, _) = crate::optimizer::memoize_subexpressions(
+        arena(),
+        parse(arena(), "a = b * c + b * c;").unwrap(),
+    );
+    assert_eq!(from_sexp(arena(), &to_sexp(ast)).unwrap(), ast);
+
+    let error = |src| from_sexp(arena(), src).unwrap_err().to_string();
+    assert_eq!(
+        error("(Prog (Expr (Set a"),
+        "input:1:19:unexpected end of input"
+    );
+    assert_eq!(error("(Prog (Expr 1 2))"), "input:1:7:1 children expected");
+    assert_eq!(
+        error("(Prog\n  (Frob))"),
+        "input:2:3:unknown kind of node `Frob'"
+    );
+    assert_eq!(error("(Prog (Expr A))"), "input:1:13:bad atom `A'");
+    assert_eq!(
+        error("(Prog (Empty)) (Empty)"),
+        "input:1:16:end of input expected"
+    );
 }
 
 // *** Compiler Testing ***
 
 fn show_code(src: &str) -> String {
-    format!("{:?}", compile(parse(src)))
+    format!("{:?}", compile(parse(arena(), src).unwrap()))
 }
 
 const EXAMPLES: [&str; 7] = [
@@ -47,6 +611,163 @@ fn test_cg_assignment() {
     assert_snapshot!(show_code("a = 42;"));
 }
 
+#[test]
+fn test_cg_relational() {
+    assert_snapshot!(show_code("a = b < c;"));
+    assert_snapshot!(show_code("a = b > c;"));
+    assert_snapshot!(show_code("a = b <= c;"));
+    assert_snapshot!(show_code("a = b >= c;"));
+}
+
+#[test]
+fn test_cg_logical() {
+    assert_snapshot!(show_code("a = b && c;"));
+    assert_snapshot!(show_code("a = b || c;"));
+}
+
+#[test]
+fn test_cg_for() {
+    assert_snapshot!(show_code("for (i = 0; i < 3; i = i + 1) s = s + i;"));
+}
+
+#[test]
+fn test_cg_break() {
+    assert_snapshot!(show_code("while (1) { i = i + 1; if (i == 5) break; }"));
+}
+
+#[test]
+#[should_panic(expected = "input:1:10:`break' outside of a loop")]
+fn test_cg_break_outside_loop() {
+    show_code("{ a = 1; break; }");
+}
+
+#[test]
+fn test_cg_continue() {
+    assert_snapshot!(show_code(
+        "for (i = 0; i < 3; ++i) { if (i == 1) continue; s = s + i; }"
+    ));
+}
+
+#[test]
+#[should_panic(expected = "input:1:8:`continue' outside of a loop")]
+fn test_cg_continue_outside_loop() {
+    show_code("if (a) continue;");
+}
+
+#[test]
+fn test_cg_switch() {
+    assert_snapshot!(show_code(
+        "switch (a) { case 1: b = 10; case 2: b = 20; default: b = 99; }"
+    ));
+}
+
+#[test]
+fn test_cg_call() {
+    assert_snapshot!(show_code("fn inc() ++n; { inc(); a = inc() + 1; }"));
+}
+
+#[test]
+#[should_panic(expected = "input:1:14:undefined function `nope'")]
+fn test_cg_undefined_function() {
+    show_code("{ a = 1; b = nope(); }");
+}
+
+#[test]
+fn test_cg_expr() {
+    // The expression of an expression statement
+    let expr = |src| {
+        let NodeKind::Prog(stmt) = parse(arena(), src).unwrap().kind else {
+            unreachable!()
+        };
+        let NodeKind::Expr(expr) = stmt.kind else {
+            unreachable!()
+        };
+        expr
+    };
+    let code = compile_expr(expr("3 + 4;"));
+    assert_eq!(
+        disassemble(&code),
+        "  0: PUSH 3\n  2: PUSH 4\n  4: ADD\n  5: HALT\n"
+    );
+    let mut vm = crate::vm::VM::new();
+    vm.run(code).unwrap();
+    assert_eq!(vm.stack().last(), Some(&7));
+
+    let mut vm = crate::vm::VM::new();
+    vm.set_global('a', 5).unwrap();
+    vm.run(compile_expr(expr("a < 3 ? 1 : (b = a * a);")))
+        .unwrap();
+    assert_eq!(vm.stack(), [25]);
+    assert_eq!(vm.get_global('b'), Some(25));
+}
+
+#[test]
+fn test_cg_check() {
+    let error = |src| {
+        crate::codegen::check(parse(arena(), src).unwrap())
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(
+        error("{ a = 1; break; }"),
+        "input:1:10:`break' outside of a loop"
+    );
+    assert_eq!(
+        error("if (a) continue;"),
+        "input:1:8:`continue' outside of a loop"
+    );
+    assert_eq!(
+        error("{ a = 1; b = nope(); }"),
+        "input:1:14:undefined function `nope'"
+    );
+    assert_eq!(
+        error("switch (a) { case 1: continue; }"),
+        "input:1:22:`continue' outside of a loop"
+    );
+    assert_eq!(error("a + b = 3;"), "input:1:1:variable expected");
+    assert_eq!(error("{ a - 1 += b; }"), "input:1:3:variable expected");
+    assert!(matches!(
+        crate::compile_and_run(&mut crate::vm::VM::new(), "a + b = 3;"),
+        Err(crate::CompileError::Codegen(_))
+    ));
+    for src in EXAMPLES.iter().chain(&[
+        "switch (a) { case 1: break; }",
+        "while (1) switch (a) { case 1: continue; }",
+        "fn f() a = 1; f();",
+    ]) {
+        assert!(
+            crate::codegen::check(parse(arena(), src).unwrap()).is_ok(),
+            "{src}"
+        );
+    }
+}
+
+/// A block of many statements is a single `Block`, not as many
+/// nested nodes, so the passes over the tree don't recurse deeply
+/// and compiling it takes time in proportion to its length
+#[test]
+fn test_cg_long_block() {
+    let n = 100_000;
+    let src = format!("{{ {} }}", "a = a + 1; ".repeat(n));
+    let start = std::time::Instant::now();
+    let g = run(&src);
+    assert_eq!(g[var('a')], isize::try_from(n).unwrap());
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+}
+
+#[test]
+fn test_cg_ternary() {
+    assert_snapshot!(show_code("b = a < 3 ? 0 : 1;"));
+}
+
+#[test]
+fn test_disassemble() {
+    assert_snapshot!(disassemble(&compile(parse(arena(), "a = 42;").unwrap())));
+    assert_snapshot!(disassemble(&compile(
+        parse(arena(), "{ i = 1; while (i < 100) i = i + i; }").unwrap()
+    )));
+}
+
 #[test]
 fn test_cg_examples() {
     for ex in EXAMPLES {
@@ -54,12 +775,1763 @@ fn test_cg_examples() {
     }
 }
 
+const PEEPHOLE_EXAMPLES: [&str; 6] = [
+    "a = b - 0;",
+    "{ a = 3; b = (a + 0) * (0 + a); }",
+    "if (a) b = 1; else ;",
+    "{ a = 2; if (a) ; b = a ? 0 : 0; }",
+    "{ i = 0; while (i < 10) { ++i; if (i == 4) continue; else ; s = s + i - 0; } }",
+    "{ a = 3; b = a + a; c = b * b * b; }",
+];
+
+#[test]
+fn test_peephole() {
+    for src in PEEPHOLE_EXAMPLES {
+        let before = compile(parse(arena(), src).unwrap());
+        let after = peephole(compile(parse(arena(), src).unwrap()));
+        assert!(
+            after.len() < before.len(),
+            "{src} didn't shrink:\n{}",
+            disassemble(&after)
+        );
+    }
+    assert_snapshot!(disassemble(&peephole(compile(
+        parse(arena(), PEEPHOLE_EXAMPLES[4]).unwrap()
+    ))));
+    assert_eq!(
+        disassemble(&peephole(compile(parse(arena(), "b = a + a;").unwrap()))),
+        "  0: FETCH a\n  2: DUP\n  3: ADD\n  4: STORE b\n  6: POP\n  7: HALT\n"
+    );
+}
+
+#[test]
+fn test_peephole_keeps_results() {
+    for src in EXAMPLES.iter().chain(PEEPHOLE_EXAMPLES.iter()) {
+        let mut before = crate::vm::VM::new();
+        before.run(compile(parse(arena(), src).unwrap())).unwrap();
+        let mut after = crate::vm::VM::new();
+        after
+            .run(peephole(compile(parse(arena(), src).unwrap())))
+            .unwrap();
+        assert_eq!(after.globals, before.globals, "{src}");
+    }
+}
+
+#[test]
+fn test_compact() {
+    let src = "        push 3
+                 store i
+                 nop
+                 pop
+         loop:   nop
+                 fetch i
+                 jz done
+                 nop
+                 fetch i
+                 push -1
+                 add
+                 store i
+                 pop
+                 jmp loop
+         done:   nop
+                 halt";
+    let code = assemble(src).unwrap();
+    let compacted = compact(assemble(src).unwrap());
+    assert!(!compacted.contains(&Insn::Nop));
+    assert_eq!(compacted.len(), code.len() - 4);
+    assert_eq!(compacted[7..9], [Insn::Jz, Insn::Address(19)]);
+    assert_eq!(compacted[17..19], [Insn::Jmp, Insn::Address(5)]);
+
+    let mut before = crate::vm::VM::new();
+    before.run(code).unwrap();
+    let mut after = crate::vm::VM::new();
+    after.run(compacted).unwrap();
+    assert_eq!(after.globals, before.globals);
+}
+
+#[test]
+fn test_source_map() {
+    let (code, source_map) = compile_with_source_map(
+        parse(arena(), "{ a = 1;\n  while (a < 3)\n    a = a + 1; }").unwrap(),
+    );
+    let listing: Vec<String> = disassemble(&code)
+        .lines()
+        .map(|line| {
+            let pc = line.split(':').next().unwrap().trim().parse().unwrap();
+            format!("{line:16} {:?}", source_map.lookup(pc).unwrap())
+        })
+        .collect();
+    assert_snapshot!(listing.join("\n"));
+    assert!(source_map.lookup(code.len()).is_none());
+}
+
+#[test]
+fn test_assemble_disassembly() {
+    let examples = [
+        &EXAMPLES[..],
+        &PRETTY_EXAMPLES,
+        &FOLDING_EXAMPLES,
+        &DEAD_CODE_EXAMPLES,
+        &PEEPHOLE_EXAMPLES,
+    ];
+    for src in examples.concat() {
+        let code = compile(parse(arena(), src).unwrap());
+        let listing = disassemble(&code);
+        let assembled = assemble(&listing).unwrap();
+        assert_eq!(format!("{assembled:?}"), format!("{code:?}"), "{listing}");
+    }
+}
+
+#[test]
+fn test_assemble() {
+    // s = 1 + 2 + ... + 10, by hand
+    let code = assemble(
+        "        push 10
+                 store i     ; the counter
+                 pop
+         loop:   fetch i
+                 jz done
+                 fetch s
+                 fetch i
+                 add
+                 store s
+                 pop
+                 fetch i
+                 push -1
+                 add
+                 store i
+                 pop
+                 jmp loop
+         done:   halt",
+    )
+    .unwrap();
+    let mut vm = crate::vm::VM::new();
+    vm.run(code).unwrap();
+    assert_eq!(vm.globals[var('s')], 55);
+
+    let error = |src| assemble(src).unwrap_err().to_string();
+    assert_eq!(error("PUSH 1\nFROB"), "input:2:unknown instruction `FROB'");
+    assert_eq!(error("PUSH"), "input:1:`PUSH' needs an operand");
+    assert_eq!(error("HALT 3"), "input:1:`HALT' takes no operand");
+    assert_eq!(error("FETCH ab"), "input:1:bad variable `ab'");
+    assert_eq!(error("PUSH x"), "input:1:bad integer `x'");
+    assert_eq!(error("JMP nowhere"), "input:1:undefined label `nowhere'");
+    assert_eq!(error("a: POP\na: POP"), "input:2:label `a' defined twice");
+    assert_eq!(error("POP\n 2: HALT"), "input:2:address 2 should be 1");
+}
+
+#[test]
+fn test_bytecode_round_trip() {
+    for src in EXAMPLES
+        .iter()
+        .chain(&PRETTY_EXAMPLES)
+        .chain(&["a = -1 + 63 + -64 + 64 + 0x7fffffff * -0x80000000;"])
+    {
+        let code = compile(parse(arena(), src).unwrap());
+        let decoded = decode(&encode(&code)).unwrap();
+        assert_eq!(decoded, code, "{src}");
+    }
+    let code = [
+        Insn::Push,
+        Insn::Integer(isize::MIN),
+        Insn::Integer(isize::MAX),
+    ];
+    assert_eq!(decode(&encode(&code)).unwrap(), code);
+}
+
+#[test]
+fn test_bytecode_errors() {
+    let bytes = encode(&compile(parse(arena(), "a = 300;").unwrap()));
+    assert_eq!(decode(b"garbage").unwrap_err(), DecodeError::BadMagic);
+    assert_eq!(decode(b"TINYC\0").unwrap_err(), DecodeError::Truncated);
+    assert_eq!(
+        decode(b"TINYC\0\x07").unwrap_err(),
+        DecodeError::BadVersion(7)
+    );
+    assert_eq!(
+        decode(b"TINYC\0\x01\x12\xff").unwrap_err(),
+        DecodeError::BadOpcode {
+            offset: 8,
+            byte: 0xff
+        }
+    );
+    assert_eq!(
+        decode(&bytes[..bytes.len() - 3]).unwrap_err(),
+        DecodeError::Truncated
+    );
+    assert_eq!(
+        decode(b"TINYC\0\x01\x13\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01").unwrap_err(),
+        DecodeError::Overflow { offset: 7 }
+    );
+}
+
+// *** Analysis Testing ***
+
+#[test]
+fn test_cfg() {
+    use crate::analysis::{build_cfg, BasicBlock};
+
+    // The GCD example
+    let code = peephole(compile(parse(arena(), EXAMPLES[2]).unwrap()));
+    let cfg = build_cfg(&code);
+    let block = |start, end, successors: &[usize]| BasicBlock {
+        start,
+        end,
+        successors: successors.to_vec(),
+    };
+    assert_eq!(
+        cfg.0,
+        [
+            block(0, 9, &[1]),      // the assignments
+            block(10, 15, &[2, 6]), // the test of the `while`
+            block(17, 22, &[3, 4]), // the test of the `if`
+            block(24, 32, &[5]),    // the `then`
+            block(34, 41, &[5]),    // the `else`
+            block(42, 42, &[1]),    // back to the test
+            block(44, 44, &[]),     // the `Halt`
+        ]
+    );
+    assert_eq!(cfg.predecessors(1), [0, 5]);
+    assert_eq!(cfg.predecessors(5), [3, 4]);
+    assert!(cfg.is_reachable(0, 6));
+    assert!(cfg.is_reachable(4, 3));
+    assert!(!cfg.is_reachable(6, 0));
+
+    // The body of a function is only reached by the call
+    let code = compile(parse(arena(), "fn f() a = 1; f();").unwrap());
+    let cfg = build_cfg(&code);
+    let body = cfg
+        .0
+        .iter()
+        .position(|b| matches!(code[b.end], Insn::Ret))
+        .unwrap();
+    assert!(cfg.predecessors(body).is_empty());
+    assert!(!cfg.is_reachable(0, body));
+}
+
+#[test]
+fn test_max_stack_depth() {
+    use crate::analysis::max_stack_depth;
+
+    // All the code of the examples is run, so the depth found must be
+    // the deepest the stack gets
+    for src in EXAMPLES {
+        let code = peephole(compile(parse(arena(), src).unwrap()));
+        let depth = max_stack_depth(&code);
+        let mut vm = crate::vm::VM::new();
+        vm.load(code);
+        vm.set_max_instructions(10_000);
+        let mut deepest = 0;
+        while vm.step().unwrap() == StepResult::Running {
+            deepest = deepest.max(vm.stack().len());
+        }
+        assert_eq!(depth, Some(deepest), "{src}");
+    }
+
+    let depth = |src| max_stack_depth(&compile(parse(arena(), src).unwrap()));
+    assert_eq!(depth(";"), Some(0));
+    assert_eq!(depth("a = 1 + (2 + (3 + 4));"), Some(4));
+    // A function called with 2 on the stack, pushing 2 more
+    assert_eq!(depth("fn f() a = b + c; d = 1 + (2 + f());"), Some(4));
+    // Calling itself without more on the stack
+    assert_eq!(depth("fn f() if (n) { n = n - 1; f(); } f();"), Some(2));
+    // Calling itself with more on the stack every time
+    assert_eq!(depth("fn f() a = 1 + f(); f();"), None);
+}
+
+#[test]
+fn test_uninitialized_reads() {
+    use crate::analysis::uninitialized_reads;
+
+    let reads = |src| -> Vec<String> {
+        uninitialized_reads(parse(arena(), src).unwrap())
+            .into_iter()
+            .map(|(name, span)| format!("{name}@{span:?}"))
+            .collect()
+    };
+    for src in EXAMPLES.iter().chain(&[
+        "{ read n; while (n) --n; }",
+        "{ if (a = 1) b = 2; else b = 3; c = a + b; }",
+        "do i = 1; while (i < 0);",
+    ]) {
+        assert_eq!(reads(src), [] as [String; 0], "{src}");
+    }
+    assert_eq!(reads("a = b + 1;"), ["b@1:5-1:6"]);
+    assert_eq!(
+        reads("{ if (a) b = 1; c = b; while (c) d = 1; e = d + a; }"),
+        ["a@1:6-1:9", "b@1:21-1:22", "d@1:45-1:46", "a@1:49-1:50"]
+    );
+    assert_eq!(reads("{ x = 1 || (y = 2); z = y; }"), ["y@1:25-1:26"]);
+    assert_eq!(
+        reads("{ n = 1; fn_body(); } fn fn_body() n += 1;"),
+        ["n@1:36-1:37"]
+    );
+}
+
+#[test]
+fn test_unused_writes() {
+    use crate::analysis::unused_writes;
+
+    let unused = |src| unused_writes(parse(arena(), src).unwrap());
+    assert_eq!(unused("{ t = 5; a = 3; }"), ["t", "a"]);
+    assert_eq!(unused("{ t = 5; a = t; b = a; a = 4; }"), ["a", "b"]);
+    assert_eq!(unused("{ a = 1; b = a; a = 2; }"), ["a", "b"]);
+    assert_eq!(unused("{ read n; s += 1; x = y = 2; }"), ["n", "s", "y", "x"]);
+    assert_eq!(unused("{ t = 1; t += t; a = t; }"), ["a"]);
+    assert!(unused("{ i = 0; while (i < 10) ++i; }").is_empty());
+    assert!(unused("{ for (i = 0; i < 10; n = i) i += 1; print n; }").is_empty());
+    assert!(unused("{ n = 5; do { print n; n = n - 1; } while (n); }").is_empty());
+    assert!(unused("{ fn_body(); } fn fn_body() { n = 1; print n; }").is_empty());
+    assert!(unused("{ n = 1; f(); n = 2; f(); } fn f() print n;").is_empty());
+    assert!(unused("{ f(); print n; } fn f() n = 1;").is_empty());
+    assert_eq!(unused("fn f() n = 1; f();"), ["n"]);
+}
+
+// *** Optimizer Testing ***
+
+const FOLDING_EXAMPLES: [&str; 5] = [
+    "a = 2 + 3 * 4;",
+    "{ if (1 < 2) a = 1; if (0) b = 2; if (3 == 4) c = 3; else c = 4; }",
+    "{ x = y + 2 * 3; z = 7 % 4 && 0 || 5 != 6; }",
+    "x = 2 > 1 ? 10 : 20;",
+    "a = 1 / 0 + 6 % (3 - 3);",
+];
+
+/// The code for `src` with constants folded, after how much it shrank
+fn show_folded(src: &str) -> String {
+    let ast = parse(arena(), src).unwrap();
+    let before = compile(ast);
+    let after = compile(fold_constants(arena(), ast));
+    format!(
+        "{} -> {} insns\n{}",
+        before.len(),
+        after.len(),
+        disassemble(&after)
+    )
+}
+
+#[test]
+fn test_fold_constants() {
+    for src in FOLDING_EXAMPLES {
+        assert_snapshot!(show_folded(src));
+    }
+}
+
+#[test]
+fn test_fold_constants_keeps_results() {
+    for src in EXAMPLES.iter().chain(&FOLDING_EXAMPLES[..4]) {
+        let mut vm = crate::vm::VM::new();
+        vm.run(compile(parse(arena(), src).unwrap())).unwrap();
+        assert_eq!(run(src), vm.globals, "{src}");
+    }
+}
+
+const DEAD_CODE_EXAMPLES: [&str; 5] = [
+    "{ ; a = 1; ; b; }",
+    "{ a = 1; if (0) b = 2; while (0) c = 3; }",
+    "{ a; 42; b = a; }",
+    "while (1) { a = a + 1; if (a == 5) { b = a; break; c = 3; } continue; d = 4; }",
+    "for (i = 0; i < 3; ++i) { if (i) continue; else break; s = s + i; }",
+];
+
+#[test]
+fn test_eliminate_dead_code() {
+    for src in DEAD_CODE_EXAMPLES {
+        let ast = parse(arena(), src).unwrap();
+        let before = compile(ast);
+        let after = compile(eliminate_dead_code(arena(), ast));
+        assert!(
+            after.len() < before.len(),
+            "{src} didn't shrink:\n{}",
+            disassemble(&after)
+        );
+
+        let mut vm = crate::vm::VM::new();
+        vm.run(before).unwrap();
+        assert_eq!(run(src), vm.globals, "{src}");
+    }
+
+    // The empty statements compile to nothing, but they are gone
+    // from the tree as well
+    assert_eq!(
+        without_spans(eliminate_dead_code(
+            arena(),
+            parse(arena(), "{ ; a = 1; ; }").unwrap()
+        )),
+        "Prog(Expr(Set(Var(\"a\"), Cst(1))))"
+    );
+}
+
+/// The sources with an identity to simplify, and what they simplify to
+const SIMPLIFY_EXAMPLES: [(&str, &str); 7] = [
+    ("a = b + 0;", "a = b;"),
+    ("a = 0 + b * 1;", "a = b;"),
+    ("a = (b - 0) / 1;", "a = b;"),
+    ("a = b * 0;", "a = 0;"),
+    (
+        "while (i < n + 0) i = i + 1 * 1;",
+        "while (i < n) i = i + 1;",
+    ),
+    ("a = (b = 2) * 0;", "a = (b = 2) * 0;"),
+    ("a = b / c * 0;", "a = b / c * 0;"),
+];
+
+#[test]
+fn test_algebraic_simplify() {
+    for (src, simplified) in SIMPLIFY_EXAMPLES {
+        let ast = algebraic_simplify(arena(), parse(arena(), src).unwrap());
+        assert_eq!(ast, parse(arena(), simplified).unwrap(), "{src}");
+        let before = compile(parse(arena(), src).unwrap());
+        let after = compile(ast);
+        if src == simplified {
+            assert_eq!(after, before, "{src}");
+        } else {
+            assert!(after.len() < before.len(), "{src} didn't shrink");
+        }
+    }
+    assert_eq!(run("{ b = 7; c = 1; a = b * 1 + c * 0 - 0; }")[var('a')], 7);
+}
+
+#[test]
+fn test_algebraic_simplify_overflow() {
+    // `x * 0` is 0 even when computing `x` would overflow, which only
+    // the unoptimized program stops for with the overflow check on
+    let src = format!("{{ b = {}; a = b * b * 0; }}", isize::MAX);
+    let mut vm = crate::vm::VM::new();
+    vm.enable_overflow_check();
+    assert!(matches!(
+        vm.run(compile(parse(arena(), &src).unwrap())),
+        Err(VmError::ArithmeticOverflow { op: "*", .. })
+    ));
+    let mut vm = crate::vm::VM::new();
+    vm.enable_overflow_check();
+    let globals = crate::compile_and_run_returning(&mut vm, &src).unwrap();
+    assert_eq!(globals[vm.symbols.lookup("a").unwrap()], 0);
+}
+
+#[test]
+fn test_memoize_subexpressions() {
+    use crate::codegen::compile_with_refs;
+    use crate::optimizer::memoize_subexpressions;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    // Equal trees hash the same, wherever they were parsed from
+    let hash = |node: &Node| {
+        let mut hasher = DefaultHasher::new();
+        node.hash(&mut hasher);
+        hasher.finish()
+    };
+    assert_eq!(
+        hash(parse(arena(), "a = b + c;").unwrap()),
+        hash(parse(arena(), "  a=b +c ;").unwrap())
+    );
+    assert_ne!(
+        hash(parse(arena(), "a = b + c;").unwrap()),
+        hash(parse(arena(), "a = c + b;").unwrap())
+    );
+
+    let src = "{ a = (b + c) * (b + c) - (b + c) * (b + c); d = b + c; e = b - c; }";
+    let (ast, refs) = memoize_subexpressions(arena(), parse(arena(), src).unwrap());
+    // `b + c` and the product of two of them
+    assert_eq!(refs.len(), 2);
+    assert!(refs.values().any(|node| node.to_string() == "b + c"));
+    let pretty = format!("{ast}");
+    assert_eq!(pretty.matches("<ref").count(), 3, "{pretty}");
+    assert_eq!(
+        compile_with_refs(ast, refs),
+        compile(parse(arena(), src).unwrap())
+    );
+
+    // Nothing is repeated
+    let (ast, refs) = memoize_subexpressions(arena(), parse(arena(), EXAMPLES[1]).unwrap());
+    assert!(refs.is_empty());
+    assert_eq!(ast, parse(arena(), EXAMPLES[1]).unwrap());
+}
+
+// *** Desugaring Testing ***
+
+#[test]
+fn test_desugar() {
+    let desugared = |src| disassemble(&compile(desugar(arena(), parse(arena(), src).unwrap())));
+    let plain = |src| disassemble(&compile(parse(arena(), src).unwrap()));
+    assert_eq!(
+        desugared("for (i = 0; i < 3; ++i) s = s + i;"),
+        plain("{ i = 0; while (i < 3) { s = s + i; ++i; } }")
+    );
+    assert_eq!(
+        desugared("{ a += 2; b += a += 1; }"),
+        plain("{ a = a + 2; b = b + (a = a + 1); }")
+    );
+
+    // The `continue` would skip the step of the `while`
+    let src = "for (i = 0; i < 5; ++i) { if (i % 2) continue; s += i; }";
+    assert!(without_spans(desugar(arena(), parse(arena(), src).unwrap())).contains("For("));
+    assert_eq!(run(src)[var('s')], 6);
+
+    // But one for a nested loop doesn't
+    let src = "for (i = 0; i < 3; ++i) while (j < i) { ++j; continue; }";
+    assert!(!without_spans(desugar(arena(), parse(arena(), src).unwrap())).contains("For("));
+    assert_eq!(run(src)[var('j')], 2);
+
+    // A `+=` to something that isn't a variable is left to `check`
+    let ast = desugar(arena(), parse(arena(), "{ a + 1 += b += 2; }").unwrap());
+    assert_eq!(
+        crate::sexp::to_sexp(ast),
+        "(Prog (Expr (AddAssign (Add a 1) (Set b (Add b 2)))))"
+    );
+    assert_eq!(
+        crate::codegen::check(ast).unwrap_err().to_string(),
+        "input:1:3:variable expected"
+    );
+}
+
 // *** Execution Testing ***
 
 #[test]
 fn test_run_examples() {
     for ex in EXAMPLES {
         println!("Try {ex}:");
-        crate::vm::VM::new().run(compile(parse(ex)));
+        crate::vm::VM::new()
+            .run(compile(parse(arena(), ex).unwrap()))
+            .unwrap();
+    }
+}
+
+/// Run `src` on a fresh VM and return the resulting globals
+fn run(src: &str) -> Vec<isize> {
+    crate::compile_and_run_returning(&mut crate::vm::VM::new(), src).unwrap()
+}
+
+/// Run `src` in the interpreter with the globals of a fresh VM, and
+/// return them
+fn interpret(src: &str) -> Result<Vec<isize>, crate::CompileError> {
+    crate::interpret_returning(&mut crate::vm::VM::new(), src)
+}
+
+/// The index of the global variable `v`
+fn var(v: char) -> usize {
+    v as usize - 'a' as usize
+}
+
+#[test]
+fn test_run_mul() {
+    let g = run("{ a = 3; b = a * 4; }");
+    assert_eq!(g[var('b')], 12);
+    let g = run("{ a = 2 + 3 * 4; b = (2 + 3) * 4; }");
+    assert_eq!(g[var('a')], 14);
+    assert_eq!(g[var('b')], 20);
+}
+
+#[test]
+fn test_run_div() {
+    let g = run("{ a = 17 / 5; b = 17 % 5; c = 2 + 12 / 2 * 3; }");
+    assert_eq!(g[var('a')], 3);
+    assert_eq!(g[var('b')], 2);
+    assert_eq!(g[var('c')], 20);
+
+    // The one quotient that overflows wraps around, unless checked
+    let src = format!("{{ a = {} - 1; b = a / -1; }}", isize::MIN + 1);
+    assert_eq!(run(&src)[var('b')], isize::MIN);
+    let mut vm = crate::vm::VM::new();
+    vm.enable_overflow_check();
+    assert!(matches!(
+        crate::compile_and_run_returning(&mut vm, &src),
+        Err(crate::CompileError::Runtime(VmError::ArithmeticOverflow {
+            op: "/",
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn test_run_div_by_zero() {
+    let mut vm = crate::vm::VM::new();
+    let r = vm.run(compile(parse(arena(), "{ a = 1; b = a / 0; }").unwrap()));
+    assert_eq!(r, Err(VmError::DivisionByZero { pc: 9 }));
+    let r = vm.run(compile(parse(arena(), "b = 1 % 0;").unwrap()));
+    assert_eq!(r, Err(VmError::DivisionByZero { pc: 4 }));
+}
+
+#[test]
+fn test_run_mod() {
+    assert_eq!(run("a = 10 % 3;")[var('a')], 1);
+    assert_eq!(run("a = 9 % 3;")[var('a')], 0);
+    let r = crate::vm::VM::new().run(compile(parse(arena(), "{ b = 0; a = 7 % b; }").unwrap()));
+    assert!(matches!(r, Err(VmError::DivisionByZero { .. })));
+
+    let src = format!("{{ a = {} - 1; b = a % -1; }}", isize::MIN + 1);
+    assert_eq!(run(&src)[var('b')], 0);
+    let mut vm = crate::vm::VM::new();
+    vm.enable_overflow_check();
+    assert!(matches!(
+        crate::compile_and_run_returning(&mut vm, &src),
+        Err(crate::CompileError::Runtime(VmError::ArithmeticOverflow {
+            op: "%",
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn test_run_equality() {
+    let g = run("{ a = (3 == 3); b = (3 != 4); c = (3 == 4); d = (4 != 4); }");
+    assert_eq!(g[var('a')], 1);
+    assert_eq!(g[var('b')], 1);
+    assert_eq!(g[var('c')], 0);
+    assert_eq!(g[var('d')], 0);
+}
+
+#[test]
+fn test_run_relational() {
+    let g = run(
+        "{ i = 0; while (i <= 9) { if (i >= 3) a = a + 1; if (i > 5) b = b + 1; i = i + 1; } }",
+    );
+    assert_eq!(g[var('a')], 7);
+    assert_eq!(g[var('b')], 4);
+    let g = run("{ a = 2 < 3; b = 3 > 2; c = 3 <= 3; d = 2 >= 3; }");
+    assert_eq!(g[var('a')], 1);
+    assert_eq!(g[var('b')], 1);
+    assert_eq!(g[var('c')], 1);
+    assert_eq!(g[var('d')], 0);
+}
+
+#[test]
+fn test_run_logical() {
+    let g = run("{ a = 2 && 3; b = 2 && 0; c = 0 || 3; d = 0 || 0; }");
+    assert_eq!(g[var('a')], 1);
+    assert_eq!(g[var('b')], 0);
+    assert_eq!(g[var('c')], 1);
+    assert_eq!(g[var('d')], 0);
+
+    // The right-hand side must not run when the left decides
+    let g = run("{ a = 0 && (x = 1); b = 1 || (y = 1); c = 1 && (z = 1); }");
+    assert_eq!(g[var('x')], 0);
+    assert_eq!(g[var('y')], 0);
+    assert_eq!(g[var('z')], 1);
+}
+
+#[test]
+fn test_run_negation() {
+    assert_eq!(run("a = -5;")[var('a')], -5);
+    assert_eq!(run("a = -(3+2);")[var('a')], -5);
+    assert_eq!(run("{ a = 7; a = -a; }")[var('a')], -7);
+    assert_eq!(run("{ a = 7; b = - -a; }")[var('b')], 7);
+    assert_eq!(run("{ a = 7; c = 1 - -a; }")[var('c')], 8);
+    assert_eq!(run("{ a = 7; if (a) -a; b = 1; }")[var('b')], 1);
+}
+
+#[test]
+fn test_run_increment() {
+    let g = run("{ i=5; j = ++i; }");
+    assert_eq!(g[var('i')], 6);
+    assert_eq!(g[var('j')], 6);
+    let g = run("{ i=5; j = --i; k = 1 + ++i; }");
+    assert_eq!(g[var('i')], 5);
+    assert_eq!(g[var('j')], 4);
+    assert_eq!(g[var('k')], 6);
+}
+
+#[test]
+fn test_run_for() {
+    let g = run("{ for (i=0; i<10; i=i+1) s=s+i; }");
+    assert_eq!(g[var('s')], 45);
+    assert_eq!(g[var('i')], 10);
+    let g = run("for (i=0; i<3; ++i) for (j=0; j<4; ++j) n = n + 1;");
+    assert_eq!(g[var('n')], 12);
+}
+
+#[test]
+fn test_run_break() {
+    let g = run("{ while (1) { i = i + 1; if (i == 5) break; } }");
+    assert_eq!(g[var('i')], 5);
+    let g = run("{ do { i = i + 1; if (i == 3) break; j = j + 1; } while (i < 10); }");
+    assert_eq!(g[var('i')], 3);
+    assert_eq!(g[var('j')], 2);
+    let g =
+        run("for (i = 0; i < 5; ++i) for (j = 0; j < 5; ++j) { if (j == 2) break; n = n + 1; }");
+    assert_eq!(g[var('i')], 5);
+    assert_eq!(g[var('n')], 10);
+}
+
+#[test]
+fn test_run_continue() {
+    let g = run("{ i=0; s=0; while(i<10){i=i+1; if(i==5) continue; s=s+i;} }");
+    assert_eq!(g[var('s')], 50);
+    let g = run("for (i = 0; i < 10; ++i) { if (i % 2) continue; s = s + i; }");
+    assert_eq!(g[var('s')], 20);
+    let g = run("do { i = i + 1; if (i < 5) continue; n = n + 1; } while (i < 8);");
+    assert_eq!(g[var('i')], 8);
+    assert_eq!(g[var('n')], 4);
+}
+
+#[test]
+fn test_run_ternary() {
+    let g = run("{ a=5; b = (a < 3) ? 0 : 1; }");
+    assert_eq!(g[var('b')], 1);
+    let g = run("{ a=2; b = (a < 3) ? 0 : 1; c = a == 1 ? 10 : a == 2 ? 20 : 30; }");
+    assert_eq!(g[var('b')], 0);
+    assert_eq!(g[var('c')], 20);
+    let g = run("{ a=1; a ? (x = 1) : (y = 1); d = 2 + (a ? 3 : 4) * 5; }");
+    assert_eq!(g[var('x')], 1);
+    assert_eq!(g[var('y')], 0);
+    assert_eq!(g[var('d')], 17);
+}
+
+#[test]
+fn test_run_if_expr() {
+    let g = run("{ a = (if (b < 5) 0 1); }");
+    assert_eq!(g[var('a')], 0);
+    let g = run("{ b = 7; a = (if (b < 5) 0 1); }");
+    assert_eq!(g[var('a')], 1);
+    let g = run("{ b = 3; a = 2 * (if (b) (if (b - 3) 10 20) 30) + 1; }");
+    assert_eq!(g[var('a')], 41);
+}
+
+#[test]
+fn test_run_bitwise() {
+    let g = run("{ a = 0xFF & 0x0F; b = 0xF0 | 0x0F; c = ~0; }");
+    assert_eq!(g[var('a')], 15);
+    assert_eq!(g[var('b')], 255);
+    assert_eq!(g[var('c')], -1);
+    let g =
+        run("{ x = 12; y = 10; a = x ^ y; b = x & y == 10; c = x | y && ~x; d = 1 | 2 ^ 3 & 6; }");
+    assert_eq!(g[var('a')], 6);
+    assert_eq!(g[var('b')], 0);
+    assert_eq!(g[var('c')], 1);
+    assert_eq!(g[var('d')], 1);
+}
+
+#[test]
+fn test_run_shifts() {
+    let g = run("{ a = 1 << 4; b = 256 >> 3; }");
+    assert_eq!(g[var('a')], 16);
+    assert_eq!(g[var('b')], 32);
+    let g = run("{ a = -256 >> 4; b = 1 << 2 + 1; c = 1 << 3 > 7; d = -1 >> 99; e = 1 << 64; }");
+    assert_eq!(g[var('a')], -16);
+    assert_eq!(g[var('b')], 8);
+    assert_eq!(g[var('c')], 1);
+    assert_eq!(g[var('d')], -1);
+    assert_eq!(g[var('e')], 0);
+}
+
+#[test]
+fn test_run_functions() {
+    let g = run("fn inc() n = n + 1; { inc(); inc(); a = inc(); }");
+    assert_eq!(g[var('n')], 3);
+    assert_eq!(g[var('a')], 0);
+    let g = run("count(); fn count() if (i < 5) { ++i; count(); s += i; }");
+    assert_eq!(g[var('i')], 5);
+    assert_eq!(g[var('s')], 25);
+    let g = run("fn ping() if (n) { --n; ++p; pong(); } { n = 5; ping(); } fn pong() if (n) { --n; ping(); }");
+    assert_eq!(g[var('p')], 3);
+}
+
+#[test]
+fn test_run_switch() {
+    let src = "switch (a) { case 1: b = 10; case 2: b = 20; default: b = 99; }";
+    for (a, b) in [(1, 10), (2, 20), (3, 99)] {
+        let mut vm = crate::vm::VM::new();
+        vm.globals[var('a')] = a;
+        vm.run(compile(parse(arena(), src).unwrap())).unwrap();
+        assert_eq!(vm.globals[var('b')], b, "a = {a}");
+        assert!(vm.stack().is_empty());
+    }
+
+    // No default, `break` leaves the `switch`, and `continue` the loop
+    let g = run(
+        "for (i = 0; i < 6; ++i) switch (i % 3) { case 0: continue; \
+                 case 1: { s += 10; break; s += 100; } } ",
+    );
+    assert_eq!(g[var('s')], 20);
+    let g = run("while (i < 4) switch (++i) { case 2: n += 1; case 3: break; }");
+    assert_eq!(g[var('i')], 4);
+    assert_eq!(g[var('n')], 1);
+}
+
+#[test]
+fn test_json() {
+    let mut vm = crate::vm::VM::new();
+    assert_eq!(
+        crate::compile_and_run_json(&mut vm, "a=42;").unwrap(),
+        "{\"a\":42}"
+    );
+    crate::compile_and_run_json(&mut vm, "{ count = -1; b = 2; }").unwrap();
+    assert_eq!(
+        crate::globals_json(&vm, false),
+        "{\"a\":42,\"b\":2,\"count\":-1}"
+    );
+    let all = crate::globals_json(&vm, true);
+    assert!(all.starts_with("{\"a\":42,\"b\":2,\"c\":0,"), "{all}");
+    assert!(all.ends_with(",\"z\":0,\"count\":-1}"), "{all}");
+    assert_eq!(crate::globals_json(&crate::vm::VM::new(), false), "{}");
+}
+
+#[test]
+fn test_compile_file() {
+    let path = std::env::temp_dir().join(format!("tinyc-test-{}.tc", std::process::id()));
+    std::fs::write(&path, "{ i = 1;\n  while (i < 100) i = i + i; }\n").unwrap();
+    let mut vm = crate::vm::VM::new();
+    crate::compile_file(&path, &mut vm).unwrap();
+    assert_eq!(vm.globals[var('i')], 128);
+    std::fs::remove_file(&path).unwrap();
+
+    let e = crate::compile_file(&path, &mut vm).unwrap_err();
+    assert!(matches!(e, crate::CompileError::Io { .. }), "{e:?}");
+}
+
+#[test]
+fn test_seeded_globals() {
+    let mut vm = crate::vm::VM::from_globals(&[("n", 10), ("s", 1)]);
+    let g = crate::compile_and_run_returning(&mut vm, "while (n) { s = s * 2; --n; }").unwrap();
+    assert_eq!(g[var('s')], 1024);
+    assert_eq!(vm.get_global('n'), Some(0));
+    assert_eq!(vm.get_global('A'), None);
+
+    vm.set_global('z', -1).unwrap();
+    assert_eq!(vm.get_global('z'), Some(-1));
+    assert_eq!(
+        vm.set_global('?', 1),
+        Err(VmError::NoSuchGlobal { name: '?' })
+    );
+}
+
+#[test]
+fn test_nonzero_globals() {
+    let mut vm = crate::vm::VM::new();
+    assert!(vm.nonzero_globals().is_empty());
+    crate::compile_and_run_returning(&mut vm, "{ total = 3; b = -1; a = 2; c = 0; }").unwrap();
+    assert_eq!(vm.nonzero_globals(), [("a", 2), ("b", -1), ("total", 3)]);
+
+    let mut out = Vec::new();
+    vm.print_nonzero_globals(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "a = 2\nb = -1\ntotal = 3\n"
+    );
+}
+
+#[test]
+#[should_panic(expected = "no global named `Ab'")]
+fn test_seeded_globals_bad_name() {
+    let _ = crate::vm::VM::from_globals(&[("Ab", 1)]);
+}
+
+#[test]
+fn test_symbol_table() {
+    let mut symbols = crate::symbols::SymbolTable::new();
+    assert_eq!(symbols.intern("a"), 0);
+    assert_eq!(symbols.intern("z"), 25);
+    assert_eq!(symbols.intern("count"), 26);
+    assert_eq!(symbols.intern("total"), 27);
+    assert_eq!(symbols.intern("count"), 26);
+    assert_eq!(symbols.lookup("none"), None);
+    assert_eq!(symbols.name(27), Some("total"));
+    assert_eq!(symbols.len(), 28);
+}
+
+#[test]
+fn test_run_long_names() {
+    let mut vm = crate::vm::VM::from_globals(&[("limit", 10)]);
+    let g = crate::compile_and_run_returning(
+        &mut vm,
+        "{ count = 0; while (count < limit) { total += count; ++count; } }",
+    )
+    .unwrap();
+    assert_eq!(g[vm.symbols.lookup("total").unwrap()], 45);
+    assert_eq!(g[vm.symbols.lookup("count").unwrap()], 10);
+
+    // More than the VM has room for to begin with
+    let mut src = String::new();
+    for i in 0..100 {
+        write!(src, "v_{} = {i};", "x".repeat(i)).unwrap();
+    }
+    let mut vm = crate::vm::VM::new();
+    let g = crate::compile_and_run_returning(&mut vm, &format!("{{ {src} }}")).unwrap();
+    assert_eq!(
+        g[vm.symbols.lookup(&format!("v_{}", "x".repeat(99))).unwrap()],
+        99
+    );
+}
+
+#[test]
+fn test_call_stack() {
+    let code = assemble(
+        "       CALL twice
+                HALT
+        twice:  CALL once
+        once:   FETCH a
+                PUSH 1
+                ADD
+                STORE a
+                POP
+                RET",
+    )
+    .unwrap();
+    let mut vm = crate::vm::VM::new();
+    vm.load(code);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert_eq!(vm.pc(), 5);
+    assert_eq!(vm.call_stack(), [2, 5]);
+    vm.resume().unwrap();
+    assert_eq!(vm.globals[var('a')], 2);
+    assert!(vm.call_stack().is_empty());
+
+    assert_eq!(
+        vm.run(assemble("PUSH 1\nRET\nHALT").unwrap()),
+        Err(VmError::ReturnFromMain { pc: 2 })
+    );
+}
+
+#[test]
+fn test_run_swap() {
+    // `a = 10 - a` with the operands the other way round
+    let mut vm = crate::vm::VM::new();
+    vm.set_global('a', 3).unwrap();
+    vm.run(assemble("FETCH a\nPUSH 10\nSWAP\nSUB\nSTORE a\nPOP\nHALT").unwrap())
+        .unwrap();
+    assert_eq!(vm.get_global('a'), Some(7));
+
+    assert_eq!(
+        vm.run(assemble("PUSH 1\nSWAP\nHALT").unwrap()),
+        Err(VmError::StackUnderflow { pc: 2 })
+    );
+    let code = assemble("PUSH 1\nPUSH 2\nSWAP\nHALT").unwrap();
+    assert_eq!(decode(&encode(&code)).unwrap(), code);
+}
+
+#[test]
+fn test_run_frames() {
+    // `f` and `g` each have a local in slot 0 of their own frame
+    let code = assemble(
+        "        call f
+                 halt
+         f:      enter 1
+                 push 10
+                 storelocal 0
+                 pop
+                 call g
+                 fetchlocal 0
+                 store a
+                 pop
+                 leave 1
+                 ret
+         g:      enter 2
+                 push 20
+                 storelocal 1
+                 pop
+                 fetchlocal 1
+                 fetchlocal 0
+                 add
+                 store b
+                 pop
+                 leave 2
+                 ret",
+    )
+    .unwrap();
+    assert_eq!(crate::analysis::max_stack_depth(&code), Some(5));
+    let mut vm = crate::vm::VM::new();
+    vm.run(code).unwrap();
+    assert_eq!(
+        (vm.get_global('a'), vm.get_global('b')),
+        (Some(10), Some(20))
+    );
+    assert!(vm.stack().is_empty());
+
+    assert_eq!(
+        vm.run(assemble("ENTER 1\nFETCHLOCAL 1\nHALT").unwrap()),
+        Err(VmError::BadInstruction {
+            pc: 3,
+            got: "Address(1)".to_string()
+        })
+    );
+    assert_eq!(
+        vm.run(assemble("PUSH 1\nLEAVE 2\nHALT").unwrap()),
+        Err(VmError::StackUnderflow { pc: 2 })
+    );
+    let code = assemble("ENTER 3\nFETCHLOCAL 2\nSTORELOCAL 0\nLEAVE 3\nHALT").unwrap();
+    assert_eq!(decode(&encode(&code)).unwrap(), code);
+    assert_eq!(assemble(&disassemble(&code)).unwrap(), code);
+}
+
+#[test]
+fn test_run_locals() {
+    // The local `a` shadows the global until the end of its block
+    let g = run("{ a = 1; { int a; a = 5; b = a; } c = a; }");
+    assert_eq!((g[var('a')], g[var('b')], g[var('c')]), (1, 5, 1));
+
+    // Even once the optimizer has emptied its block, as the interpreter
+    // agrees
+    let src = "{ { int a; } a = 5; }";
+    let globals = interpret(src).unwrap();
+    assert_eq!(globals[var('a')], 5);
+    assert_eq!(run(src), globals);
+
+    // Each call has its own locals, and they start at zero
+    let g = run("fn f() {
+             int n;
+             n = a;
+             if (a > 0) { a = a - 1; f(); }
+             b = b * 10 + n;
+         }
+         { a = 3; f(); }");
+    assert_eq!(g[var('b')], 123);
+    let g = run("{ a = 2; while (a) { int x; x += 5; b += x; a = a - 1; } }");
+    assert_eq!(g[var('b')], 10);
+
+    // A local is printed without its name, as it isn't a global
+    assert_eq!(
+        output("{ x = 1; { int x; x = 7; print x; } print x; }"),
+        "7\nx = 1\n"
+    );
+
+    let ast = parse(arena(), "{ int a; read a; }").unwrap();
+    assert_eq!(
+        crate::codegen::check(ast).unwrap_err().to_string(),
+        "input:1:10:cannot read into local `a'"
+    );
+    assert!(crate::codegen::check(parse(arena(), "{ { int a; } read a; }").unwrap()).is_ok());
+}
+
+#[test]
+fn test_run_discard() {
+    // `_` takes the value, but has no slot to keep it in
+    assert_eq!(
+        crate::compile_and_run_with_globals("{ _ = 5 + 3; a = 1; }", &[]).unwrap(),
+        [("a".to_string(), 1)]
+    );
+    let g = run("{ b = _ = 4; _ = a += 2; }");
+    assert_eq!((g[var('a')], g[var('b')]), (2, 4));
+    assert!(crate::analysis::unused_writes(parse(arena(), "{ _ = 1; }").unwrap()).is_empty());
+    assert_eq!(interpret("{ b = _ = 4; }").unwrap()[var('b')], 4);
+
+    for (src, pos) in [
+        ("a = _ + 1;", "1:5"),
+        ("_ += 1;", "1:1"),
+        ("print _;", "1:7"),
+    ] {
+        let ast = parse(arena(), src).unwrap();
+        assert_eq!(
+            crate::codegen::check(ast).unwrap_err().to_string(),
+            format!("input:{pos}:cannot read `_'")
+        );
+    }
+    assert!(parse(arena(), "read _;").is_err());
+}
+
+#[test]
+fn test_run_bad_code() {
+    let mut vm = crate::vm::VM::new();
+    assert_eq!(vm.run(vec![]), Err(VmError::HaltNotReached));
+    assert_eq!(
+        vm.run(vec![Insn::Push, Insn::Integer(1)]),
+        Err(VmError::HaltNotReached)
+    );
+    assert_eq!(
+        vm.run(vec![Insn::Push, Insn::Integer(1), Insn::Add, Insn::Halt]),
+        Err(VmError::StackUnderflow { pc: 2 })
+    );
+    assert_eq!(
+        vm.run(vec![Insn::Integer(1), Insn::Halt]),
+        Err(VmError::BadInstruction {
+            pc: 0,
+            got: "Integer(1)".to_string()
+        })
+    );
+    assert_eq!(
+        vm.run(vec![Insn::Fetch, Insn::Integer(1), Insn::Halt]),
+        Err(VmError::BadInstruction {
+            pc: 1,
+            got: "Integer(1)".to_string()
+        })
+    );
+    assert_eq!(
+        vm.run(vec![Insn::Fetch, Insn::Address(GLOBALS), Insn::Halt]),
+        Err(VmError::BadInstruction {
+            pc: 1,
+            got: format!("Address({GLOBALS})")
+        })
+    );
+    assert_eq!(
+        vm.run(vec![Insn::Jmp, Insn::Address(7)]),
+        Err(VmError::HaltNotReached)
+    );
+}
+
+#[test]
+fn test_step() {
+    let mut vm = crate::vm::VM::new();
+    vm.load(compile(parse(arena(), "a = 6 * 7;").unwrap()));
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!((vm.pc(), vm.stack()), (4, &[6, 7][..]));
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert_eq!(vm.globals[var('a')], 42);
+    assert_eq!(vm.step(), Ok(StepResult::Running));
+    assert!(vm.stack().is_empty());
+    assert_eq!(vm.step(), Ok(StepResult::Halted));
+    assert_eq!(vm.step(), Ok(StepResult::Halted));
+    assert_eq!(vm.pc(), 8);
+}
+
+#[test]
+fn test_breakpoints() {
+    let mut vm = crate::vm::VM::new();
+    vm.load(compile(parse(arena(), "{ a = 1; b = 2; }").unwrap()));
+    vm.set_breakpoint(0).unwrap();
+    vm.set_breakpoint(5).unwrap();
+    assert_eq!(vm.resume(), Err(VmError::Breakpoint { pc: 0 }));
+    assert_eq!(vm.pc(), 0);
+    assert_eq!(vm.resume(), Err(VmError::Breakpoint { pc: 5 }));
+    assert_eq!((vm.globals[var('a')], vm.globals[var('b')]), (1, 0));
+
+    // Without the breakpoint, it runs to the end
+    vm.clear_breakpoint(0);
+    vm.clear_breakpoint(5);
+    assert!(vm.breakpoints().is_empty());
+    assert_eq!(vm.resume(), Ok(()));
+    assert_eq!(vm.globals[var('b')], 2);
+
+    // Operands and addresses past the end aren't instructions
+    assert_eq!(vm.set_breakpoint(1), Err(VmError::BadBreakpoint { pc: 1 }));
+    assert_eq!(
+        vm.set_breakpoint(99),
+        Err(VmError::BadBreakpoint { pc: 99 })
+    );
+    vm.set_breakpoint(7).unwrap();
+    assert_eq!(vm.breakpoints().iter().collect::<Vec<_>>(), [&7]);
+}
+
+#[test]
+fn test_watchpoints() {
+    let mut vm = crate::vm::VM::new();
+    vm.watch_global(var('i'));
+    vm.load(compile(
+        parse(
+            arena(),
+            "{ i = 0; j = 5; for (i = 1; i < 3; ++i) j = j + i; }",
+        )
+        .unwrap(),
+    ));
+    // Storing the value it already has doesn't count as a change
+    assert_eq!(
+        vm.resume(),
+        Err(VmError::Watchpoint {
+            idx: var('i'),
+            old: 0,
+            new: 1
+        })
+    );
+    assert_eq!(vm.globals[var('j')], 5);
+    assert_eq!(
+        vm.resume(),
+        Err(VmError::Watchpoint {
+            idx: var('i'),
+            old: 1,
+            new: 2
+        })
+    );
+    assert_eq!(vm.globals[var('j')], 6);
+    assert_eq!(
+        vm.resume(),
+        Err(VmError::Watchpoint {
+            idx: var('i'),
+            old: 2,
+            new: 3
+        })
+    );
+    assert_eq!(vm.resume(), Ok(()));
+    assert_eq!(vm.globals[var('j')], 8);
+}
+
+#[test]
+fn test_max_instructions() {
+    let mut vm = crate::vm::VM::new();
+    vm.set_max_instructions(1000);
+    assert_eq!(
+        vm.run(compile(parse(arena(), "while (1) i = i + 1;").unwrap())),
+        Err(VmError::CycleLimitExceeded { executed: 1000 })
+    );
+    assert_eq!(vm.instructions_executed(), 1000);
+
+    // The count starts over with each run, and the limit is inclusive
+    vm.run(compile(parse(arena(), "a = 1;").unwrap())).unwrap();
+    assert_eq!(vm.instructions_executed(), 4);
+    vm.set_max_instructions(4);
+    vm.run(compile(parse(arena(), "a = 1;").unwrap())).unwrap();
+    vm.set_max_instructions(3);
+    assert_eq!(
+        vm.run(compile(parse(arena(), "a = 1;").unwrap())),
+        Err(VmError::CycleLimitExceeded { executed: 3 })
+    );
+}
+
+#[test]
+fn test_stack_limit() {
+    // Recursing without end overflows the calls in progress
+    let mut vm = crate::vm::VM::new();
+    assert_eq!(vm.stack_limit(), crate::vm::DEFAULT_STACK_LIMIT);
+    assert_eq!(
+        vm.run(compile(parse(arena(), "fn f() f(); f();").unwrap())),
+        Err(VmError::StackOverflow { depth: 1025 })
+    );
+
+    // Pushing without end overflows the stack
+    vm.set_stack_limit(10);
+    assert_eq!(
+        vm.run(assemble("l: PUSH 1\nJMP l").unwrap()),
+        Err(VmError::StackOverflow { depth: 11 })
+    );
+    assert_eq!(vm.stack().len(), 11);
+    assert_eq!(
+        vm.run(assemble("ENTER 1000000000000\nHALT").unwrap()),
+        Err(VmError::StackOverflow {
+            depth: 1_000_000_000_000
+        })
+    );
+
+    // Up to the limit is fine
+    let mut vm = crate::vm::VM::new();
+    vm.set_stack_limit(3);
+    vm.run(assemble("PUSH 1\nPUSH 2\nPUSH 3\nHALT").unwrap())
+        .unwrap();
+    assert_eq!(vm.stack(), [1, 2, 3]);
+    vm.run(compile(
+        parse(arena(), "fn f() { a += 1; if (a < 3) f(); } f();").unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(vm.get_global('a'), Some(3));
+}
+
+#[test]
+fn test_overflow_check() {
+    let overflow = |op| assemble(&format!("PUSH {}\nPUSH 2\n{op}\nHALT", isize::MAX)).unwrap();
+
+    // Without checking, the arithmetic wraps around
+    let mut vm = crate::vm::VM::new();
+    vm.run(overflow("ADD")).unwrap();
+    assert_eq!(vm.stack(), [isize::MIN + 1]);
+    vm.run(overflow("MUL")).unwrap();
+    assert_eq!(vm.stack(), [-2]);
+
+    vm.enable_overflow_check();
+    vm.run(assemble(&format!("PUSH {}\nPUSH 1\nSUB\nHALT", isize::MAX)).unwrap())
+        .unwrap();
+    assert_eq!(
+        vm.run(assemble(&format!("PUSH {}\nPUSH 1\nADD\nHALT", isize::MAX)).unwrap()),
+        Err(VmError::ArithmeticOverflow { pc: 4, op: "+" })
+    );
+    assert_eq!(
+        vm.run(overflow("MUL")),
+        Err(VmError::ArithmeticOverflow { pc: 4, op: "*" })
+    );
+    assert_eq!(
+        vm.run(assemble(&format!("PUSH {}\nPUSH 1\nSUB\nHALT", isize::MIN)).unwrap()),
+        Err(VmError::ArithmeticOverflow { pc: 4, op: "-" })
+    );
+    assert_eq!(
+        VmError::ArithmeticOverflow { pc: 4, op: "-" }.to_string(),
+        "4: overflow in `-'"
+    );
+}
+
+#[test]
+fn test_run_abs() {
+    let g = run("{ a = abs(-5); b = abs(3); c = abs(0); d = abs(a - 8) * 2; }");
+    assert_eq!(
+        (g[var('a')], g[var('b')], g[var('c')], g[var('d')]),
+        (5, 3, 0, 6)
+    );
+
+    // The most negative number has no absolute value, and gets the
+    // nearest one rather than staying negative
+    let code = || assemble(&format!("PUSH {}\nABS\nHALT", isize::MIN)).unwrap();
+    let mut vm = crate::vm::VM::new();
+    vm.run(code()).unwrap();
+    assert_eq!(vm.stack(), [isize::MAX]);
+    let src = format!("{{ a = {} - 1; b = abs(a); }}", isize::MIN + 1);
+    assert_eq!(run(&src)[var('b')], isize::MAX);
+    let globals = crate::interpret_returning(&mut crate::vm::VM::new(), &src).unwrap();
+    assert_eq!(globals[var('b')], isize::MAX);
+    vm.enable_overflow_check();
+    assert_eq!(
+        vm.run(code()),
+        Err(VmError::ArithmeticOverflow { pc: 2, op: "abs" })
+    );
+    assert_eq!(
+        crate::sexp::to_sexp(fold_constants(
+            arena(),
+            parse(arena(), "a = abs(-7);").unwrap()
+        )),
+        "(Prog (Expr (Set a 7)))"
+    );
+}
+
+#[test]
+fn test_run_neg() {
+    let g = run("{ a = -5; b = -a; c = - -a; d = -(a - 3) * 2; }");
+    assert_eq!(
+        (g[var('a')], g[var('b')], g[var('c')], g[var('d')]),
+        (-5, 5, -5, 16)
+    );
+
+    // One instruction fewer than the `0 - a` it used to be
+    let neg = compile(parse(arena(), "b = -a;").unwrap());
+    let sub = compile(parse(arena(), "b = 0 - a;").unwrap());
+    let insns = |code: &[Insn]| {
+        code.iter()
+            .filter(|insn| !matches!(insn, Insn::Integer(_) | Insn::Address(_)))
+            .count()
+    };
+    assert_eq!(insns(&neg) + 1, insns(&sub));
+    assert!(neg.contains(&Insn::Neg));
+    assert_eq!(peephole(sub), neg);
+
+    // Negating the most negative number wraps around to itself
+    let g = run(&format!("{{ a = {} - 1; b = -a; }}", isize::MIN + 1));
+    assert_eq!((g[var('a')], g[var('b')]), (isize::MIN, isize::MIN));
+    let code = || assemble(&format!("PUSH {}\nNEG\nHALT", isize::MIN)).unwrap();
+    let mut vm = crate::vm::VM::new();
+    vm.run(code()).unwrap();
+    assert_eq!(vm.stack(), [isize::MIN]);
+    vm.enable_overflow_check();
+    assert_eq!(
+        vm.run(code()),
+        Err(VmError::ArithmeticOverflow { pc: 2, op: "-" })
+    );
+}
+
+#[test]
+fn test_run_min_max() {
+    let g = run("{ a = max(3, 5); b = min(3, 5); c = min(-a, max(b, 4) * 2); }");
+    assert_eq!((g[var('a')], g[var('b')], g[var('c')]), (5, 3, -5));
+    assert_eq!(
+        crate::sexp::to_sexp(fold_constants(
+            arena(),
+            parse(arena(), "a = max(1, min(7, 2));").unwrap()
+        )),
+        "(Prog (Expr (Set a 2)))"
+    );
+    assert_eq!(
+        parse(arena(), "a = min(1 2);").unwrap_err().to_string(),
+        "input:1:11:expected `,', got integer literal"
+    );
+}
+
+#[test]
+fn test_rand() {
+    let code = || assemble(&format!("{}HALT", "RAND\n".repeat(1000))).unwrap();
+    let mut vm = crate::vm::VM::new();
+    vm.seed_rand(42);
+    vm.run(code()).unwrap();
+    let numbers = vm.stack().to_vec();
+    assert!(numbers.iter().all(|&n| n >= 0));
+
+    // Roughly as many in each tenth, and in each of the last digits
+    let mut tenths = [0; 10];
+    let mut digits = [0; 10];
+    for n in &numbers {
+        tenths[usize::try_from(n / (isize::MAX / 10 + 1)).unwrap()] += 1;
+        digits[usize::try_from(n % 10).unwrap()] += 1;
+    }
+    for count in tenths.iter().chain(&digits) {
+        assert!((60..=140).contains(count), "{tenths:?} {digits:?}");
+    }
+
+    // The same seed gives the same numbers
+    vm.seed_rand(42);
+    vm.run(code()).unwrap();
+    assert_eq!(vm.stack(), numbers);
+
+    let g = run("{ i = 0; while (i < 100) { a += rand() >= 0; b += rand() % 2; i += 1; } }");
+    assert_eq!(g[var('a')], 100);
+    assert!((20..=80).contains(&g[var('b')]));
+}
+
+#[test]
+fn test_snapshot() {
+    let code = compile(
+        parse(
+            arena(),
+            "{ i = 0; while (i < 5) { s = s + i * rand() % 7; i += 1; } }",
+        )
+        .unwrap(),
+    );
+    let body = code.iter().position(|insn| *insn == Insn::Jz).unwrap() + 2;
+    let mut vm = crate::vm::VM::new();
+    vm.load(code);
+    vm.set_breakpoint(body).unwrap();
+
+    // Stop before the first time round the loop, and run it twice from there
+    assert_eq!(vm.resume(), Err(VmError::Breakpoint { pc: body }));
+    vm.clear_breakpoint(body);
+    let snap = vm.snapshot();
+    vm.resume().unwrap();
+    let globals = vm.globals.clone();
+    assert_eq!(globals[var('i')], 5);
+    vm.restore(snap.clone());
+    assert_eq!(vm.pc(), body);
+    assert_eq!(vm.globals[var('s')], 0);
+    vm.resume().unwrap();
+    assert_eq!(vm.globals, globals);
+    assert_ne!(vm.snapshot(), snap);
+}
+
+#[test]
+fn test_diff_globals() {
+    use crate::vm::diff_globals;
+    let mut vm = crate::vm::VM::from_globals(&[("a", 1), ("b", 2), ("c", 3)]);
+    let before = vm.snapshot();
+    crate::compile_and_run(&mut vm, "{ a = 10; b = 2; c = 0; d = 4; big = 1; }").unwrap();
+    let big = vm.symbols.lookup("big").unwrap();
+    assert_eq!(
+        diff_globals(before.globals(), &vm.globals),
+        [
+            (var('a'), 1, 10),
+            (var('c'), 3, 0),
+            (var('d'), 0, 4),
+            (big, 0, 1)
+        ]
+    );
+    assert_eq!(diff_globals(&vm.globals, &vm.globals), []);
+    assert_eq!(diff_globals(&[1, 2], &[1]), [(1, 2, 0)]);
+}
+
+#[test]
+fn test_reset() {
+    let mut vm = crate::vm::VM::new();
+    let g = crate::compile_and_run_returning(&mut vm, "{ a = 1; b = 2; }").unwrap();
+    assert_eq!((g[var('a')], g[var('b')]), (1, 2));
+
+    // Without a reset, the globals carry over to the next program
+    let g = crate::compile_and_run_returning(&mut vm, "a = a + 10;").unwrap();
+    assert_eq!((g[var('a')], g[var('b')]), (11, 2));
+
+    vm.reset_globals();
+    assert_eq!(vm.globals, [0; GLOBALS]);
+    let g = crate::compile_and_run_returning(&mut vm, "a = a + 10;").unwrap();
+    assert_eq!((g[var('a')], g[var('b')]), (10, 0));
+
+    vm.load(compile(parse(arena(), "{ a = 1; b = 2; }").unwrap()));
+    vm.step().unwrap();
+    vm.reset();
+    assert_eq!(
+        (vm.pc(), vm.stack(), &vm.globals[..]),
+        (0, &[][..], &[0; GLOBALS][..])
+    );
+    vm.resume().unwrap();
+    assert_eq!(vm.globals[var('b')], 2);
+}
+
+/// A writer whose output can still be read once the VM owns it
+#[derive(Clone, Default)]
+struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The trace of running `src` in the given `format`
+fn trace(src: &str, format: TraceFormat) -> String {
+    let buffer = SharedBuffer::default();
+    let mut vm = crate::vm::VM::new();
+    vm.trace_on();
+    vm.set_trace_writer(Box::new(buffer.clone()));
+    vm.set_trace_format(format);
+    vm.run(compile(parse(arena(), src).unwrap())).unwrap();
+    let bytes = buffer.0.borrow();
+    String::from_utf8(bytes.clone()).unwrap()
+}
+
+#[test]
+fn test_trace() {
+    assert_snapshot!(trace("a = (1 + 2) * a;", TraceFormat::Text));
+    assert_snapshot!(trace("a = (1 + 2) * a;", TraceFormat::Json));
+}
+
+/// What running `src` prints
+fn output(src: &str) -> String {
+    let buffer = SharedBuffer::default();
+    let mut vm = crate::vm::VM::new();
+    vm.set_output(Box::new(buffer.clone()));
+    crate::compile_and_run_returning(&mut vm, src).unwrap();
+    let bytes = buffer.0.borrow();
+    String::from_utf8(bytes.clone()).unwrap()
+}
+
+#[test]
+fn test_print() {
+    assert_eq!(
+        output("{ i=1; while(i<10){i=i+1; print i;} }"),
+        "i = 2\ni = 3\ni = 4\ni = 5\ni = 6\ni = 7\ni = 8\ni = 9\ni = 10\n"
+    );
+    assert_eq!(
+        output("{ a = 6; print a * 7; print -1; print (a); }"),
+        "42\n-1\na = 6\n"
+    );
+}
+
+#[test]
+fn test_read() {
+    let run_with_input = |src, input: &'static str| {
+        let mut vm = crate::vm::VM::new();
+        vm.set_input(Box::new(std::io::Cursor::new(input)));
+        crate::compile_and_run_returning(&mut vm, src)
+    };
+    let g = run_with_input("{ read a; read b; c = a * b; }", "6\n 7 \n").unwrap();
+    assert_eq!(g[var('c')], 42);
+    let g = run_with_input("{ read n; while (n) { s += n; read n; } }", "1\n2\n3\n0\n").unwrap();
+    assert_eq!(g[var('s')], 6);
+
+    for (input, pc) in [("x\n", 0), ("", 0), ("1\n", 2)] {
+        assert_eq!(
+            run_with_input("{ read a; read b; }", input)
+                .unwrap_err()
+                .to_string(),
+            format!("runtime error: {pc}: integer expected in the input")
+        );
+    }
+}
+
+/// A global, one of the first few
+fn gen_var() -> impl Strategy<Value = char> {
+    prop::char::range('a', 'e')
+}
+
+/// A constant, often one at the edges of the range
+fn gen_constant() -> BoxedStrategy<String> {
+    prop_oneof![
+        1 => Just(format!("({} - 1)", isize::MIN + 1)),
+        1 => Just(isize::MAX.to_string()),
+        1 => Just("-1".to_string()),
+        5 => (0..20isize).prop_map(|n| n.to_string()),
+    ]
+    .boxed()
+}
+
+/// An expression up to `depth` operators deep
+fn gen_expr(depth: u32) -> BoxedStrategy<String> {
+    let leaf = prop_oneof![gen_constant(), gen_var().prop_map(String::from)];
+    leaf.prop_recursive(depth, 32, 3, |x| {
+        // The divisor is as often -1, which overflows the most
+        // negative number, as an expression, which may be zero
+        let divisor = prop_oneof![
+            Just("-1".to_string()),
+            (1..10isize).prop_map(|n| n.to_string()),
+            x.clone(),
+        ];
+        let binary = select(vec!["+", "-", "*", "<", "==", "&&", "||"]);
+        prop_oneof![
+            2 => (x.clone(), select(vec!["/", "%"]), divisor)
+                .prop_map(|(a, op, d)| format!("({a} {op} {d})")),
+            1 => (x.clone(), 0..70).prop_map(|(a, n)| format!("({a} << {n})")),
+            1 => (x.clone(), 0..70).prop_map(|(a, n)| format!("({a} >> {n})")),
+            1 => (x.clone(), x.clone(), x.clone())
+                .prop_map(|(a, b, c)| format!("({a} ? {b} : {c})")),
+            1 => x.clone().prop_map(|a| format!("~{a}")),
+            1 => x.clone().prop_map(|a| format!("-({a})")),
+            1 => x.clone().prop_map(|a| format!("abs({a})")),
+            1 => (x.clone(), x.clone()).prop_map(|(a, b)| format!("min({a}, {b})")),
+            1 => (x.clone(), x.clone()).prop_map(|(a, b)| format!("max({a}, {b})")),
+            7 => (x.clone(), binary, x).prop_map(|(a, op, b)| format!("({a} {op} {b})")),
+        ]
+    })
+    .boxed()
+}
+
+/// A statement, where `counters` is how many loops it is nested in,
+/// each bounded by a counter of its own from `v` on.  It may call
+/// the functions in `fns`, each with the first counter its loops
+/// use, so that a call never resets a counter in use.
+fn gen_stmt(depth: u32, counters: u8, fns: &'static [(&'static str, u8)]) -> BoxedStrategy<String> {
+    let assign = (gen_var(), gen_expr(3))
+        .prop_map(|(v, x)| format!("{v} = {x};"))
+        .boxed();
+    if depth == 0 {
+        return assign;
+    }
+    let sub = gen_stmt(depth - 1, counters, fns);
+    let callable: Vec<_> = fns
+        .iter()
+        .filter(|(_, c)| counters <= *c)
+        .map(|(f, _)| *f)
+        .collect();
+    let call = if callable.is_empty() {
+        assign.clone()
+    } else {
+        select(callable).prop_map(|f| format!("{f}();")).boxed()
+    };
+    let mut choices = vec![
+        (2, assign.clone()),
+        (
+            1,
+            (gen_var(), gen_expr(2))
+                .prop_map(|(v, x)| format!("{v} += {x};"))
+                .boxed(),
+        ),
+        (1, gen_expr(2).prop_map(|x| format!("_ = {x};")).boxed()),
+        (
+            1,
+            (gen_expr(2), sub.clone(), sub.clone())
+                .prop_map(|(x, a, b)| format!("if ({x}) {a} else {b}"))
+                .boxed(),
+        ),
+        // A block, maybe declaring a local that shadows a global, and
+        // maybe of nothing else, though Tiny-C has no empty blocks
+        (
+            1,
+            (
+                prop::option::weighted(2.0 / 3.0, gen_var()),
+                prop::collection::vec(sub.clone(), 0..3),
+            )
+                .prop_filter("empty block", |(decl, stmts)| {
+                    decl.is_some() || !stmts.is_empty()
+                })
+                .prop_map(|(decl, stmts)| {
+                    let decl = decl.map_or(String::new(), |v| format!("int {v};"));
+                    format!("{{ {decl} {} }}", stmts.join(" "))
+                })
+                .boxed(),
+        ),
+        (
+            1,
+            (gen_expr(2), sub.clone(), sub.clone(), sub)
+                .prop_map(|(x, a, b, c)| {
+                    format!("switch ({x} % 3) {{ case 0: {a} case 1: {b} default: {c} }}")
+                })
+                .boxed(),
+        ),
+        (1, call),
+    ];
+    if counters < 4 {
+        let c = char::from(b'v' + counters);
+        let body = gen_stmt(depth - 1, counters + 1, fns);
+        let exit = select(vec!["", "if (c) break;", "if (d) continue;"]);
+        choices.push((
+            2,
+            (any::<bool>(), 0..5, exit, body.clone())
+                .prop_map(move |(is_while, n, exit, body)| {
+                    if is_while {
+                        format!("{{ {c} = {n}; while ({c} > 0) {{ {c} = {c} - 1; {exit} {body} }} }}")
+                    } else {
+                        format!(
+                            "{{ {c} = {n}; do {{ {c} = {c} - 1; {exit} {body} }} while ({c} > 0); }}"
+                        )
+                    }
+                })
+                .boxed(),
+        ));
+        choices.push((
+            2,
+            (0..5, body)
+                .prop_map(move |(n, body)| {
+                    format!("for ({c} = 0; {c} < {n}; {c} += 1) {{ {body} }}")
+                })
+                .boxed(),
+        ));
+    } else {
+        choices.push((4, assign));
+    }
+    Union::new_weighted(choices).boxed()
+}
+
+/// A program of two functions with locals, `g` calling `f`, and a
+/// main statement calling both
+fn gen_program() -> impl Strategy<Value = String> {
+    (
+        gen_stmt(3, 3, &[]),
+        gen_stmt(3, 3, &[]),
+        gen_stmt(3, 2, &[("f", 3)]),
+        gen_stmt(3, 2, &[("f", 3)]),
+        gen_stmt(4, 0, &[("f", 3), ("g", 2)]),
+        gen_stmt(4, 0, &[("f", 3), ("g", 2)]),
+    )
+        .prop_map(|(f1, f2, g1, g2, main1, main2)| {
+            format!("fn f() {{ int a; {f1} {f2} }}\nfn g() {{ int b; {g1} {g2} }}\n{{ {main1} {main2} }}")
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    /// Random programs leave the same globals, and fail or not alike,
+    /// in the interpreter and compiled (and optimized) on the VM
+    #[test]
+    fn test_interpreter_matches_vm(src in gen_program()) {
+        let mut walked = crate::vm::VM::new();
+        let interpreted = crate::interpret_returning(&mut walked, &src);
+        let mut vm = crate::vm::VM::new();
+        let compiled = crate::compile_and_run_returning(&mut vm, &src);
+        prop_assert_eq!(
+            interpreted.is_ok(),
+            compiled.is_ok(),
+            "{:?} {:?}",
+            interpreted,
+            compiled
+        );
+        prop_assert_eq!(walked.globals, vm.globals);
+    }
+}
+
+#[test]
+fn test_gen_program() {
+    // Mostly programs that run to the end
+    let mut runner = TestRunner::deterministic();
+    let programs = gen_program();
+    let failed = (0..200)
+        .filter(|_| {
+            let src = programs.new_tree(&mut runner).unwrap().current();
+            crate::compile_and_run_returning(&mut crate::vm::VM::new(), &src).is_err()
+        })
+        .count();
+    assert!(failed < 100, "{failed} failed");
+}
+
+#[test]
+fn test_interpret_examples() {
+    let expected = [
+        ('a', 1),
+        ('i', 128),
+        ('i', 25),
+        ('i', 51),
+        ('i', 51),
+        ('y', 2),
+        ('m', 89),
+    ];
+    for (src, (v, value)) in EXAMPLES.iter().zip(expected) {
+        let globals = interpret(src).unwrap();
+        assert_eq!(globals[var(v)], value, "for {src}");
+        assert_eq!(globals, run(src), "for {src}");
+    }
+}
+
+#[test]
+fn test_interpret() {
+    let g = interpret("fn f() { int a; a = 5; b = a; } { int a; a = 1; f(); c = a; }").unwrap();
+    assert_eq!((g[var('a')], g[var('b')], g[var('c')]), (0, 5, 1));
+    let g = interpret("{ i = 5; do { i = i - 1; if (i == 2) continue; s += i; } while (i); }");
+    assert_eq!(g.unwrap()[var('s')], 4 + 3 + 1);
+
+    // The globals past `z` get the slots they would on the VM
+    let src = "{ foo = 1; bar = foo + 1; foo = bar * 3; }";
+    assert_eq!(interpret(src).unwrap(), run(src));
+    assert_eq!(interpret(src).unwrap()[26..28], [6, 2]);
+
+    for (src, message) in [
+        ("{ a = 1; b = a / (a - 1); }", "1:14:division by zero"),
+        ("fn f() f(); f();", "1:8:stack overflow"),
+        ("{ g(); }", "1:3:undefined function `g'"),
+    ] {
+        assert_eq!(
+            interpret(src).unwrap_err().to_string(),
+            format!("input:{message}")
+        );
     }
 }
@@ -0,0 +1,68 @@
+//! Drawing the abstract syntax tree with Graphviz, eg.
+//!
+//! ```sh
+//! dot -Tsvg ast.dot > ast.svg
+//! ```
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use crate::parser::{walk_node, AstVisitor, BNode, Node};
+use std::fmt::Write;
+
+/// The tree below `node` as a Graphviz `digraph`, one DOT node per
+/// `Node` labelled with its kind (and name or value), and an edge from
+/// each to its children.  The edges to the cases of a `switch` are
+/// labelled with the case.
+#[must_use]
+pub fn to_dot(node: &Node) -> String {
+    let mut dot = DotWriter {
+        output: "digraph ast {\n    node [shape=box];\n".to_string(),
+        ..DotWriter::default()
+    };
+    dot.visit_node(node);
+    dot.output += "}\n";
+    dot.output
+}
+
+#[derive(Default)]
+struct DotWriter {
+    output: String,
+
+    /// The number of nodes written so far, which numbers the next
+    next_id: usize,
+
+    /// The number of the parent of the node being visited, if any,
+    /// and the label for the edge from it
+    parent: Option<usize>,
+    edge_label: Option<String>,
+}
+
+impl AstVisitor for DotWriter {
+    fn visit_node(&mut self, node: &Node) {
+        let id = self.next_id;
+        self.next_id += 1;
+        writeln!(self.output, "    n{id} [label=\"{}\"];", node.label()).unwrap();
+        if let Some(parent) = self.parent {
+            match self.edge_label.take() {
+                Some(label) => writeln!(self.output, "    n{parent} -> n{id} [label=\"{label}\"];"),
+                None => writeln!(self.output, "    n{parent} -> n{id};"),
+            }
+            .unwrap();
+        }
+        let parent = self.parent.replace(id);
+        walk_node(self, node);
+        self.parent = parent;
+    }
+
+    fn visit_switch(&mut self, value: &Node, cases: &[(isize, BNode)], default: Option<&Node>) {
+        self.visit_node(value);
+        for (n, body) in cases {
+            self.edge_label = Some(format!("case {n}"));
+            self.visit_node(body);
+        }
+        if let Some(body) = default {
+            self.edge_label = Some("default".to_string());
+            self.visit_node(body);
+        }
+    }
+}
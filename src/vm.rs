@@ -1,111 +1,890 @@
 /* Virtual machine. */
 
+use crate::analysis::max_stack_depth;
 use crate::codegen::Insn;
+use crate::symbols::SymbolTable;
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+/// The runtime errors the virtual machine can report.  The `pc` is
+/// the address of the offending instruction.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// Something that isn't a valid instruction (or operand) for
+    /// this position was found in the code
+    BadInstruction { pc: usize, got: String },
+
+    /// An instruction needed more operands than the stack had
+    StackUnderflow { pc: usize },
+
+    /// A `Div` or `Mod` with a zero divisor
+    DivisionByZero { pc: usize },
+
+    /// A `Read` got a line that isn't an integer, or no line at all
+    InvalidInput { pc: usize },
+
+    /// Execution ran off the end of the code without a `Halt`
+    HaltNotReached,
+
+    /// A `Ret` with no call to return from
+    ReturnFromMain { pc: usize },
+
+    /// There is no global by that name, only `a` to `z`
+    NoSuchGlobal { name: char },
+
+    /// Execution paused at a breakpoint, before the instruction at
+    /// `pc`.  Going on from here executes that instruction.
+    Breakpoint { pc: usize },
+
+    /// A breakpoint was asked for where there's no instruction
+    BadBreakpoint { pc: usize },
+
+    /// Execution paused after a `Store` changed the watched global
+    /// `idx` from `old` to `new`
+    Watchpoint { idx: usize, old: isize, new: isize },
+
+    /// The limit on the number of instructions to execute was
+    /// reached, after `executed` instructions
+    CycleLimitExceeded { executed: u64 },
+
+    /// The stack, or the calls in progress, grew to `depth`, past the
+    /// limit set by `set_stack_limit`
+    StackOverflow { depth: usize },
+
+    /// An `Add`, `Sub`, `Mul`, `Div`, `Mod`, `Neg`, or `Abs` overflowed,
+    /// which is only an error once `enable_overflow_check` has been
+    /// called.  The `op` is the operator, eg. `+`.
+    ArithmeticOverflow { pc: usize, op: &'static str },
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::BadInstruction { pc, got } => write!(f, "{pc}: bad instruction {got}"),
+            VmError::StackUnderflow { pc } => write!(f, "{pc}: stack underflow"),
+            VmError::DivisionByZero { pc } => write!(f, "{pc}: division by zero"),
+            VmError::InvalidInput { pc } => write!(f, "{pc}: integer expected in the input"),
+            VmError::HaltNotReached => write!(f, "ran past the end of the code"),
+            VmError::ReturnFromMain { pc } => write!(f, "{pc}: return without a call"),
+            VmError::NoSuchGlobal { name } => write!(f, "no global named `{name}'"),
+            VmError::Breakpoint { pc } => write!(f, "{pc}: breakpoint"),
+            VmError::BadBreakpoint { pc } => write!(f, "{pc}: no instruction for a breakpoint"),
+            VmError::Watchpoint { idx, old, new } => {
+                write!(f, "global {idx} changed from {old} to {new}")
+            }
+            VmError::CycleLimitExceeded { executed } => {
+                write!(f, "gave up after {executed} instructions")
+            }
+            VmError::StackOverflow { depth } => write!(f, "stack overflow at depth {depth}"),
+            VmError::ArithmeticOverflow { pc, op } => write!(f, "{pc}: overflow in `{op}'"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// What became of the VM after a `step()`
+#[derive(Debug, PartialEq, Eq)]
+pub enum StepResult {
+    /// There are more instructions to execute
+    Running,
+
+    /// The `Halt` instruction was reached
+    Halted,
+}
+
+/// How the trace shows each instruction executed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// For humans, eg. `   4: Add  (stack: [1, 2])`
+    #[default]
+    Text,
+
+    /// For tools, one JSON object per line, eg.
+    /// `{"pc":4,"insn":"Add","stack":[1,2]}`
+    Json,
+}
+
+/// The number of globals the VM has room for at least: `a` to `z`
+/// and as many more named ones.  There are more if the symbol table
+/// needs them.
+pub const GLOBALS: usize = 64;
+
+/// The number of values the stack, and the number of calls in
+/// progress, are limited to unless `set_stack_limit` says otherwise
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+/// The state of a `VM` at some point of its run, to go back to with
+/// `VM::restore`.  The code isn't part of it, as it doesn't change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmSnapshot {
+    globals: Vec<isize>,
+    pc: usize,
+    stack: Vec<isize>,
+    call_stack: Vec<usize>,
+    fp: usize,
+    frames: Vec<usize>,
+    rand_state: u64,
+}
+
+impl VmSnapshot {
+    /// The values of the globals, by slot
+    #[must_use]
+    pub fn globals(&self) -> &[isize] {
+        &self.globals
+    }
+}
+
+/// The slots of the globals that differ between `before` and `after`,
+/// with their values in each, eg. from a `VmSnapshot` taken before a
+/// run and the `globals` after it.  A global missing from one is zero.
+#[must_use]
+pub fn diff_globals(before: &[isize], after: &[isize]) -> Vec<(usize, isize, isize)> {
+    let value = |globals: &[isize], slot| globals.get(slot).copied().unwrap_or_default();
+    (0..before.len().max(after.len()))
+        .map(|slot| (slot, value(before, slot), value(after, slot)))
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
 
 /// The virtual machine executes the `Insn` and holds the `code`, the
 /// `pc`, the `stack`, and the `globals`.
 #[derive(Default)]
 pub struct VM {
-    pub globals: [isize; 26],
+    pub globals: Vec<isize>,
+
+    /// The state of the generator `Rand` draws from
+    rand_state: u64,
+
+    /// The names of the globals, for the code compiled for this VM
+    pub symbols: SymbolTable,
+
     code: Vec<Insn>,
     pc: usize,
     stack: Vec<isize>,
+
+    /// The return addresses of the calls in progress
+    call_stack: Vec<usize>,
+
+    /// Where the innermost frame of locals starts on the `stack`, and
+    /// where each of the frames around it did
+    fp: usize,
+    frames: Vec<usize>,
+
     tracing: bool,
+    trace_format: TraceFormat,
+
+    /// Where the trace goes, standard output if `None`
+    trace_writer: Option<Box<dyn Write>>,
+
+    /// Where `Print` and `PrintVar` go, standard output if `None`
+    output: Option<Box<dyn Write>>,
+
+    /// Where `Read` reads from, standard input if `None`
+    input: Option<Box<dyn BufRead>>,
+
+    breakpoints: BTreeSet<usize>,
+    watched: BTreeSet<usize>,
+
+    /// The number of instructions executed since the code was loaded
+    executed: u64,
+    max_instructions: Option<u64>,
+
+    /// The limit on the depth of the stack, `DEFAULT_STACK_LIMIT` if
+    /// `None`
+    stack_limit: Option<usize>,
+
+    /// Whether arithmetic that overflows is an error rather than
+    /// wrapping around
+    overflow_check: bool,
+
+    /// Whether `compile_and_run` and the like keep quiet about the
+    /// variables a program assigns but never reads
+    quiet_unused: bool,
+
+    /// The breakpoint we are paused at, if any, so that going on
+    /// doesn't stop at it again right away
+    paused_at: Option<usize>,
 }
 
 impl VM {
     #[must_use]
     pub fn new() -> Self {
-        VM { ..VM::default() }
+        let mut vm = VM { ..VM::default() };
+        vm.reset_globals();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        vm.seed_rand(now.map_or(0, |d| d.as_secs() ^ u64::from(d.subsec_nanos())));
+        vm
+    }
+
+    /// Start the numbers `Rand` gives over from `seed`, so that a run
+    /// can be repeated.  A new VM is seeded from the time.
+    pub fn seed_rand(&mut self, seed: u64) {
+        self.rand_state = seed;
+    }
+
+    /// The next number from the linear congruential generator, with
+    /// the poorly distributed low bits mixed with the high ones
+    fn rand(&mut self) -> isize {
+        self.rand_state = self
+            .rand_state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let x = self.rand_state ^ (self.rand_state >> 31);
+        isize::try_from(x >> 1).unwrap_or(isize::MAX)
+    }
+
+    /// A VM with some of the globals given initial values, eg.
+    /// `VM::from_globals(&[("n", 10)])`
+    ///
+    /// # Panics
+    /// Panics if a name isn't a variable name, lowercase letters and
+    /// underscores
+    #[must_use]
+    pub fn from_globals(init: &[(&str, isize)]) -> Self {
+        let mut vm = VM::new();
+        for &(name, value) in init {
+            assert!(
+                name.starts_with(|c: char| c.is_ascii_lowercase())
+                    && name.chars().all(|c| c.is_ascii_lowercase() || c == '_'),
+                "no global named `{name}'"
+            );
+            let idx = vm.symbols.intern(name);
+            vm.grow_globals();
+            vm.globals[idx] = value;
+        }
+        vm
+    }
+
+    /// Set the global `name`, one of `a` to `z`, to `value`
+    ///
+    /// # Errors
+    /// Returns `VmError::NoSuchGlobal` for any other name
+    pub fn set_global(&mut self, name: char, value: isize) -> Result<(), VmError> {
+        let idx = Self::global_index(name).ok_or(VmError::NoSuchGlobal { name })?;
+        self.globals[idx] = value;
+        Ok(())
+    }
+
+    /// The value of the global `name`, if it's one of `a` to `z`
+    #[must_use]
+    pub fn get_global(&self, name: char) -> Option<isize> {
+        Self::global_index(name).map(|idx| self.globals[idx])
+    }
+
+    fn global_index(name: char) -> Option<usize> {
+        ('a'..='z').position(|c| c == name)
+    }
+
+    /// The names and values of the globals that aren't zero, in the
+    /// order of their slots
+    #[must_use]
+    pub fn nonzero_globals(&self) -> Vec<(&str, isize)> {
+        self.symbols
+            .names()
+            .into_iter()
+            .filter(|&(_, slot)| self.globals[slot] != 0)
+            .map(|(name, slot)| (name, self.globals[slot]))
+            .collect()
+    }
+
+    /// Write the globals that aren't zero to `writer`, one `name = value`
+    /// per line, as printed at the end of a run
+    ///
+    /// # Errors
+    /// Returns the error of writing to `writer`, if any
+    pub fn print_nonzero_globals(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        for (name, value) in self.nonzero_globals() {
+            writeln!(writer, "{name} = {value}")?;
+        }
+        Ok(())
     }
 
     pub fn trace_on(&mut self) {
         self.tracing = true;
     }
 
-    fn get_const(&mut self) -> isize {
-        let Insn::Integer(n) = self.code[self.pc] else {
-            panic!("Bad code, expected integer constant, got {:?}", self.code[self.pc]);
+    /// Send the trace to `writer` rather than standard output
+    pub fn set_trace_writer(&mut self, writer: Box<dyn Write>) {
+        self.trace_writer = Some(writer);
+    }
+
+    pub fn set_trace_format(&mut self, format: TraceFormat) {
+        self.trace_format = format;
+    }
+
+    /// Send what the program prints to `writer` rather than standard
+    /// output
+    pub fn set_output(&mut self, writer: Box<dyn Write>) {
+        self.output = Some(writer);
+    }
+
+    /// Read the program's input from `reader` rather than standard
+    /// input
+    pub fn set_input(&mut self, reader: Box<dyn BufRead>) {
+        self.input = Some(reader);
+    }
+
+    /// Read a line of the program's input as an integer
+    fn read(&mut self, pc: usize) -> Result<isize, VmError> {
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(reader) => reader.read_line(&mut line),
+            None => std::io::stdin().lock().read_line(&mut line),
         };
-        self.pc += 1;
-        n
+        match read {
+            Ok(n) if n > 0 => line
+                .trim()
+                .parse()
+                .map_err(|_| VmError::InvalidInput { pc }),
+            _ => Err(VmError::InvalidInput { pc }),
+        }
     }
 
-    fn get_address(&mut self) -> usize {
-        let Insn::Address(n) = self.code[self.pc] else {
-            panic!("Bad code, expected address constant, got {:?}", self.code[self.pc]);
+    /// Write a `line` of the program's output.  As with the trace,
+    /// errors are ignored.
+    fn print(&mut self, line: &str) {
+        let _ = match &mut self.output {
+            Some(writer) => writeln!(writer, "{line}"),
+            None => writeln!(std::io::stdout(), "{line}"),
         };
-        self.pc += 1;
-        n
     }
 
-    fn top(&mut self) -> isize {
-        self.stack[self.stack.len() - 1]
+    /// Trace the instruction at `pc`, which is about to be executed.
+    /// Failing to write the trace is no reason to stop the program,
+    /// so errors are ignored.
+    fn trace(&mut self, pc: usize) {
+        let insn = &self.code[pc];
+        let line = match self.trace_format {
+            TraceFormat::Text => format!("{pc:4}: {insn:?}  (stack: {:?})", self.stack),
+            TraceFormat::Json => {
+                let stack: Vec<String> = self.stack.iter().map(ToString::to_string).collect();
+                format!(
+                    "{{\"pc\":{pc},\"insn\":\"{insn:?}\",\"stack\":[{}]}}",
+                    stack.join(",")
+                )
+            }
+        };
+        let _ = match &mut self.trace_writer {
+            Some(writer) => writeln!(writer, "{line}"),
+            None => writeln!(std::io::stdout(), "{line}"),
+        };
     }
 
-    /// # Panics
-    /// Panics on illegal code
-    pub fn run(&mut self, code: Vec<Insn>) {
+    /// Set all the globals back to zero
+    pub fn reset_globals(&mut self) {
+        self.globals.clear();
+        self.grow_globals();
+    }
+
+    /// Make room for all the globals in the symbol table
+    fn grow_globals(&mut self) {
+        let len = self.symbols.len().max(GLOBALS);
+        if self.globals.len() < len {
+            self.globals.resize(len, 0);
+        }
+    }
+
+    /// Set the globals back to zero, clear the stack, and start over
+    /// at the first instruction
+    pub fn reset(&mut self) {
+        self.reset_globals();
+        self.stack.clear();
+        self.call_stack.clear();
+        self.fp = 0;
+        self.frames.clear();
+        self.pc = 0;
+    }
+
+    /// The state of the run so far
+    #[must_use]
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            globals: self.globals.clone(),
+            pc: self.pc,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            fp: self.fp,
+            frames: self.frames.clone(),
+            rand_state: self.rand_state,
+        }
+    }
+
+    /// Go back to the state in `snap`, taken while running the code
+    /// that is loaded now
+    pub fn restore(&mut self, snap: VmSnapshot) {
+        self.globals = snap.globals;
+        self.pc = snap.pc;
+        self.stack = snap.stack;
+        self.call_stack = snap.call_stack;
+        self.fp = snap.fp;
+        self.frames = snap.frames;
+        self.rand_state = snap.rand_state;
+        self.paused_at = None;
+    }
+
+    /// The address of the next instruction to execute
+    #[must_use]
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The evaluation stack, with the top at the end
+    #[must_use]
+    pub fn stack(&self) -> &[isize] {
+        &self.stack
+    }
+
+    /// The return addresses of the calls in progress, the innermost
+    /// last
+    #[must_use]
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    /// Prepare to execute `code` from the beginning, one `step()` at
+    /// a time.  The globals are kept.  Room is made on the stack for
+    /// as much as the code can push, up to the stack limit, so running
+    /// it doesn't reallocate.
+    pub fn load(&mut self, code: Vec<Insn>) {
         self.code = code;
+        self.grow_globals();
         self.pc = 0;
-        loop {
-            let insn = &self.code[self.pc];
+        self.stack.clear();
+        let depth = max_stack_depth(&self.code).unwrap_or_default();
+        self.stack.reserve(depth.min(self.stack_limit()));
+        self.call_stack.clear();
+        self.fp = 0;
+        self.frames.clear();
+        self.paused_at = None;
+        self.executed = 0;
+    }
 
-            if self.tracing {
-                println!("{:4}: {:?}  (stack: {:?})", self.pc, insn, self.stack);
+    /// Stop with `VmError::CycleLimitExceeded` rather than execute
+    /// more than `n` instructions
+    pub fn set_max_instructions(&mut self, n: u64) {
+        self.max_instructions = Some(n);
+    }
+
+    /// Stop with `VmError::StackOverflow` rather than let the stack,
+    /// or the calls in progress, grow deeper than `n`
+    pub fn set_stack_limit(&mut self, n: usize) {
+        self.stack_limit = Some(n);
+    }
+
+    /// The limit on the depth of the stack
+    #[must_use]
+    pub fn stack_limit(&self) -> usize {
+        self.stack_limit.unwrap_or(DEFAULT_STACK_LIMIT)
+    }
+
+    /// Stop with `VmError::ArithmeticOverflow` when an `Add`, `Sub`,
+    /// `Mul`, `Div`, `Mod`, `Neg`, or `Abs` overflows, rather than wrap
+    /// around (or for `Abs`, give `isize::MAX`).  This makes them
+    /// slower, so it's off unless asked for.
+    pub fn enable_overflow_check(&mut self) {
+        self.overflow_check = true;
+    }
+
+    /// Have `compile_and_run` and the like not warn about the variables
+    /// a program assigns but never reads, eg. when its standard error
+    /// is shown to someone who didn't write the program
+    ///
+    /// ```
+    /// use tinyc_in_rust::{compile_and_run, vm::VM};
+    /// let mut vm = VM::new();
+    /// assert!(vm.warns_unused());
+    /// vm.disable_unused_warnings();
+    /// compile_and_run(&mut vm, "{ t = 1; a = t; }").unwrap(); // warns of nothing
+    /// assert!(!vm.warns_unused());
+    /// ```
+    pub fn disable_unused_warnings(&mut self) {
+        self.quiet_unused = true;
+    }
+
+    /// Do `compile_and_run` and the like warn about the variables
+    /// assigned but never read?
+    #[must_use]
+    pub fn warns_unused(&self) -> bool {
+        !self.quiet_unused
+    }
+
+    /// The number of instructions executed since the code was loaded
+    #[must_use]
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Pause execution before the instruction at `pc` is executed.
+    /// The breakpoints are kept when new code is loaded.
+    ///
+    /// # Errors
+    /// Returns `VmError::BadBreakpoint` if there's no instruction at
+    /// `pc` in the loaded code
+    pub fn set_breakpoint(&mut self, pc: usize) -> Result<(), VmError> {
+        let mut insn_pc = 0;
+        while insn_pc < pc && insn_pc < self.code.len() {
+            insn_pc += self.code[insn_pc].size();
+        }
+        if insn_pc != pc || pc >= self.code.len() {
+            return Err(VmError::BadBreakpoint { pc });
+        }
+        self.breakpoints.insert(pc);
+        Ok(())
+    }
+
+    /// Remove the breakpoint at `pc`, if there is one
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Pause execution whenever a `Store` changes the value of the
+    /// global `idx`
+    pub fn watch_global(&mut self, idx: usize) {
+        self.watched.insert(idx);
+    }
+
+    /// The addresses of the breakpoints
+    #[must_use]
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    /// The operand slot at `pc` which must be an `Integer(_)`
+    fn get_const(&mut self) -> Result<isize, VmError> {
+        match self.code.get(self.pc) {
+            Some(Insn::Integer(n)) => {
+                self.pc += 1;
+                Ok(*n)
             }
+            Some(insn) => Err(VmError::BadInstruction {
+                pc: self.pc,
+                got: format!("{insn:?}"),
+            }),
+            None => Err(VmError::HaltNotReached),
+        }
+    }
 
-            self.pc += 1;
-            match *insn {
-                Insn::Integer(_) | Insn::Address(_) => {
-                    panic!("Can't execute middle of instructions")
-                }
-                Insn::Halt => {
-                    break;
+    /// The operand slot at `pc` which must be an `Address(_)`
+    fn get_address(&mut self) -> Result<usize, VmError> {
+        match self.code.get(self.pc) {
+            Some(Insn::Address(n)) => {
+                self.pc += 1;
+                Ok(*n)
+            }
+            Some(insn) => Err(VmError::BadInstruction {
+                pc: self.pc,
+                got: format!("{insn:?}"),
+            }),
+            None => Err(VmError::HaltNotReached),
+        }
+    }
+
+    /// Like `get_address`, but the address must also name a global
+    fn get_global_address(&mut self) -> Result<usize, VmError> {
+        let a = self.get_address()?;
+        if a >= self.globals.len() {
+            return Err(VmError::BadInstruction {
+                pc: self.pc - 1,
+                got: format!("{:?}", Insn::Address(a)),
+            });
+        }
+        Ok(a)
+    }
+
+    /// Like `get_address`, but for the slot of a local in the
+    /// innermost frame, which must be on the stack
+    fn get_local_address(&mut self) -> Result<usize, VmError> {
+        let a = self.fp + self.get_address()?;
+        if a >= self.stack.len() {
+            return Err(VmError::BadInstruction {
+                pc: self.pc - 1,
+                got: format!("{:?}", Insn::Address(a - self.fp)),
+            });
+        }
+        Ok(a)
+    }
+
+    fn pop(&mut self, pc: usize) -> Result<isize, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { pc })
+    }
+
+    fn top(&mut self, pc: usize) -> Result<isize, VmError> {
+        self.stack
+            .last()
+            .copied()
+            .ok_or(VmError::StackUnderflow { pc })
+    }
+
+    /// Pops the two operands of a binary operator and pushes the
+    /// result of applying `op` to them
+    fn binary(&mut self, pc: usize, op: impl FnOnce(isize, isize) -> isize) -> Result<(), VmError> {
+        let b = self.pop(pc)?;
+        let a = self.pop(pc)?;
+        self.stack.push(op(a, b));
+        Ok(())
+    }
+
+    /// Like `binary`, for the operator `op` which wraps around on
+    /// overflow, unless overflow is checked
+    fn arithmetic(
+        &mut self,
+        pc: usize,
+        op: &'static str,
+        checked: fn(isize, isize) -> Option<isize>,
+        wrapping: fn(isize, isize) -> isize,
+    ) -> Result<(), VmError> {
+        let b = self.pop(pc)?;
+        let a = self.pop(pc)?;
+        let v = if self.overflow_check {
+            checked(a, b).ok_or(VmError::ArithmeticOverflow { pc, op })?
+        } else {
+            wrapping(a, b)
+        };
+        self.stack.push(v);
+        Ok(())
+    }
+
+    /// Like `arithmetic`, but failing if the divisor is zero.  The only
+    /// overflow is the most negative number divided by -1.
+    fn divide(
+        &mut self,
+        pc: usize,
+        op: &'static str,
+        checked: fn(isize, isize) -> Option<isize>,
+        wrapping: fn(isize, isize) -> isize,
+    ) -> Result<(), VmError> {
+        if self.stack.last() == Some(&0) {
+            return Err(VmError::DivisionByZero { pc });
+        }
+        self.arithmetic(pc, op, checked, wrapping)
+    }
+
+    /// Run `code` from the beginning until it halts
+    ///
+    /// # Errors
+    /// Returns a `VmError` if the program fails at runtime, including
+    /// when the code itself is malformed
+    pub fn run(&mut self, code: Vec<Insn>) -> Result<(), VmError> {
+        self.load(code);
+        self.resume()
+    }
+
+    /// Go on executing the loaded code from `pc` until it halts
+    ///
+    /// # Errors
+    /// Returns a `VmError` if the program fails at runtime or
+    /// reaches a breakpoint
+    pub fn resume(&mut self) -> Result<(), VmError> {
+        while self.step()? == StepResult::Running {}
+        Ok(())
+    }
+
+    /// Execute the single instruction at `pc`.  Once halted, the VM
+    /// stays at the `Halt` instruction.
+    ///
+    /// # Errors
+    /// Returns a `VmError` if the instruction fails, including when
+    /// the code itself is malformed
+    #[allow(clippy::too_many_lines)]
+    pub fn step(&mut self) -> Result<StepResult, VmError> {
+        let pc = self.pc;
+        if self.breakpoints.contains(&pc) && self.paused_at != Some(pc) {
+            self.paused_at = Some(pc);
+            return Err(VmError::Breakpoint { pc });
+        }
+        self.paused_at = None;
+
+        if pc >= self.code.len() {
+            return Err(VmError::HaltNotReached);
+        }
+        if self.max_instructions == Some(self.executed) {
+            return Err(VmError::CycleLimitExceeded {
+                executed: self.executed,
+            });
+        }
+        self.executed += 1;
+
+        if self.tracing {
+            self.trace(pc);
+        }
+        let insn = &self.code[pc];
+
+        self.pc += 1;
+        match *insn {
+            Insn::Integer(_) | Insn::Address(_) => {
+                return Err(VmError::BadInstruction {
+                    pc,
+                    got: format!("{insn:?}"),
+                });
+            }
+            Insn::Halt => {
+                self.pc = pc;
+                return Ok(StepResult::Halted);
+            }
+            Insn::Fetch => {
+                let a = self.get_global_address()?;
+                self.stack.push(self.globals[a]);
+            }
+            Insn::Store => {
+                let a = self.get_global_address()?;
+                let (old, new) = (self.globals[a], self.top(pc)?);
+                self.globals[a] = new;
+                if old != new && self.watched.contains(&a) {
+                    return Err(VmError::Watchpoint { idx: a, old, new });
                 }
-                Insn::Fetch => {
-                    let a = self.get_address();
-                    self.stack.push(self.globals[a]);
+            }
+            Insn::Read => {
+                let a = self.get_global_address()?;
+                let (old, new) = (self.globals[a], self.read(pc)?);
+                self.globals[a] = new;
+                if old != new && self.watched.contains(&a) {
+                    return Err(VmError::Watchpoint { idx: a, old, new });
                 }
-                Insn::Store => self.globals[self.get_address()] = self.top(),
-                Insn::Push => {
-                    let v = self.get_const();
-                    self.stack.push(v);
+            }
+            Insn::Push => {
+                let v = self.get_const()?;
+                self.stack.push(v);
+            }
+            Insn::Pop => {
+                self.pop(pc)?;
+            }
+            Insn::Dup => {
+                let v = self.top(pc)?;
+                self.stack.push(v);
+            }
+            Insn::Nop => {}
+            Insn::Swap => {
+                let n = self.stack.len();
+                if n < 2 {
+                    return Err(VmError::StackUnderflow { pc });
                 }
-                Insn::Pop => {
-                    self.stack.pop().unwrap();
+                self.stack.swap(n - 1, n - 2);
+            }
+            Insn::Add => self.arithmetic(pc, "+", isize::checked_add, isize::wrapping_add)?,
+            Insn::Sub => self.arithmetic(pc, "-", isize::checked_sub, isize::wrapping_sub)?,
+            Insn::Mul => self.arithmetic(pc, "*", isize::checked_mul, isize::wrapping_mul)?,
+            Insn::Div => self.divide(pc, "/", isize::checked_div, isize::wrapping_div)?,
+            Insn::Mod => self.divide(pc, "%", isize::checked_rem, isize::wrapping_rem)?,
+            // Shifting by the width or more (or by a negative amount)
+            // shifts out all the bits, leaving only the sign for `Shr`
+            Insn::Shl => self.binary(pc, |a, b| {
+                u32::try_from(b)
+                    .ok()
+                    .and_then(|b| a.checked_shl(b))
+                    .unwrap_or(0)
+            })?,
+            Insn::Shr => self.binary(pc, |a, b| {
+                a >> u32::try_from(b).map_or(isize::BITS - 1, |b| b.min(isize::BITS - 1))
+            })?,
+            Insn::Lt => self.binary(pc, |a, b| isize::from(a < b))?,
+            Insn::Gt => self.binary(pc, |a, b| isize::from(a > b))?,
+            Insn::Le => self.binary(pc, |a, b| isize::from(a <= b))?,
+            Insn::Ge => self.binary(pc, |a, b| isize::from(a >= b))?,
+            Insn::Eq => self.binary(pc, |a, b| isize::from(a == b))?,
+            Insn::Ne => self.binary(pc, |a, b| isize::from(a != b))?,
+            Insn::BitAnd => self.binary(pc, |a, b| a & b)?,
+            Insn::BitOr => self.binary(pc, |a, b| a | b)?,
+            Insn::BitXor => self.binary(pc, |a, b| a ^ b)?,
+            Insn::Min => self.binary(pc, std::cmp::min)?,
+            Insn::Max => self.binary(pc, std::cmp::max)?,
+            Insn::Rand => {
+                let v = self.rand();
+                self.stack.push(v);
+            }
+            Insn::BitNot => {
+                let v = self.pop(pc)?;
+                self.stack.push(!v);
+            }
+            Insn::Neg => {
+                let v = self.pop(pc)?;
+                if v == isize::MIN && self.overflow_check {
+                    return Err(VmError::ArithmeticOverflow { pc, op: "-" });
                 }
-                Insn::Add => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a + b);
+                self.stack.push(v.wrapping_neg());
+            }
+            Insn::Abs => {
+                let v = self.pop(pc)?;
+                if v == isize::MIN && self.overflow_check {
+                    return Err(VmError::ArithmeticOverflow { pc, op: "abs" });
                 }
-                Insn::Sub => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a - b);
+                // There's no positive `-isize::MIN`, and a negative
+                // absolute value would be worse than the nearest one
+                self.stack.push(v.saturating_abs());
+            }
+            Insn::FetchLocal => {
+                let a = self.get_local_address()?;
+                self.stack.push(self.stack[a]);
+            }
+            Insn::StoreLocal => {
+                let a = self.get_local_address()?;
+                self.stack[a] = self.top(pc)?;
+            }
+            Insn::Enter => {
+                let n = self.get_address()?;
+                // Checked before the stack grows, which could be by a lot
+                if self.stack.len().saturating_add(n) > self.stack_limit() {
+                    return Err(VmError::StackOverflow {
+                        depth: self.stack.len().saturating_add(n),
+                    });
                 }
-                Insn::Lt => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(isize::from(a < b));
+                self.frames.push(self.fp);
+                self.fp = self.stack.len();
+                self.stack.resize(self.fp + n, 0);
+            }
+            Insn::Leave => {
+                let n = self.get_address()?;
+                let Some(len) = self.stack.len().checked_sub(n) else {
+                    return Err(VmError::StackUnderflow { pc });
+                };
+                self.stack.truncate(len);
+                self.fp = self.frames.pop().unwrap_or_default();
+            }
+            Insn::Call => {
+                let n = self.get_address()?;
+                if self.call_stack.len() == self.stack_limit() {
+                    return Err(VmError::StackOverflow {
+                        depth: self.call_stack.len() + 1,
+                    });
                 }
-                Insn::Jmp => self.pc = self.get_address(),
-                Insn::Jz => {
-                    let n = self.get_address();
-                    let v = self.stack.pop().unwrap();
-                    if v == 0 {
-                        self.pc = n;
-                    }
+                self.call_stack.push(self.pc);
+                self.pc = n;
+            }
+            Insn::Ret => {
+                self.pc = self
+                    .call_stack
+                    .pop()
+                    .ok_or(VmError::ReturnFromMain { pc })?;
+            }
+            Insn::Print => {
+                let v = self.pop(pc)?;
+                self.print(&v.to_string());
+            }
+            Insn::PrintVar => {
+                let a = self.get_global_address()?;
+                let v = self.pop(pc)?;
+                let name = self.symbols.name(a).unwrap_or("?").to_string();
+                self.print(&format!("{name} = {v}"));
+            }
+            Insn::Jmp => self.pc = self.get_address()?,
+            Insn::Jz => {
+                let n = self.get_address()?;
+                if self.pop(pc)? == 0 {
+                    self.pc = n;
                 }
-                Insn::Jnz => {
-                    let n = self.get_address();
-                    let v = self.stack.pop().unwrap();
-                    if v != 0 {
-                        self.pc = n;
-                    }
+            }
+            Insn::Jnz => {
+                let n = self.get_address()?;
+                if self.pop(pc)? != 0 {
+                    self.pc = n;
                 }
             }
         }
+        if self.stack.len() > self.stack_limit() {
+            return Err(VmError::StackOverflow {
+                depth: self.stack.len(),
+            });
+        }
+        Ok(StepResult::Running)
     }
 }
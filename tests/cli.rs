@@ -0,0 +1,224 @@
+//! Tests of the command-line programs, run as a user would
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the `main` binary with `args`, feeding it `input`
+fn main_with(args: &[&str], input: &str) -> Output {
+    main_with_bytes(args, input.as_bytes())
+}
+
+/// Like `main_with`, but the input needn't be UTF-8
+fn main_with_bytes(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_main"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit before reading it all, eg. on a bad option
+    let _ = child.stdin.take().unwrap().write_all(input);
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_output_asm() {
+    let out = main_with(&["--output-asm"], "{ i=1; while (i<100) i=i+i; }\n");
+    assert!(out.status.success());
+    let asm = String::from_utf8(out.stdout).unwrap();
+    for mnemonic in ["PUSH 1", "STORE i", "FETCH i", "LT", "JZ", "JMP", "HALT"] {
+        assert!(asm.contains(mnemonic), "no {mnemonic} in\n{asm}");
+    }
+    // Not run, so no variables printed
+    assert!(!asm.contains("i = 128"));
+}
+
+#[test]
+fn test_max_stack() {
+    let src = "fn f() { n += 1; f(); } f();\n";
+    let out = main_with(&[], src);
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("stack overflow at depth 1025"));
+    let out = main_with(&["--max-stack", "10"], src);
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("stack overflow at depth 11"));
+    assert!(!main_with(&["--max-stack"], src).status.success());
+}
+
+#[test]
+fn test_dump_ast() {
+    let out = main_with(&["--dump-ast"], "{ i=1; while (i<100) i=i+i; }\n");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "(Prog (Block (Expr (Set i 1)) (While (Lt i 100) (Expr (Set i (Add i i))))))\n"
+    );
+}
+
+#[test]
+fn test_all_syntax_errors() {
+    let out = main_with(&[], "{ a = 5 b = 6; c = (1 + 2; }\n");
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "input:1:9:expected `;', got identifier
+  { a = 5 b = 6; c = (1 + 2; }
+          ^
+input:1:26:expected `)', got `;'
+  { a = 5 b = 6; c = (1 + 2; }
+                           ^
+"
+    );
+}
+
+/// Run the `repl` binary, feeding it `input`
+fn repl_with(input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_repl"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap()
+}
+
+#[test]
+fn test_repl() {
+    let out = repl_with("a=5;\nb=a+1;\nc=1/0;\n:reset\nd=a+2;\n:vars\n:quit\ne=1;\n");
+    let lines: Vec<_> = out.split("tinyc> ").collect();
+    assert_eq!(lines[1], "a = 5\n");
+    assert_eq!(lines[2], "a = 5\nb = 6\n");
+    assert!(lines[3].starts_with("runtime error: "), "{}", lines[3]);
+    assert_eq!(lines[5], "d = 2\n");
+    assert!(lines[6].starts_with("a = 0\nb = 0\nc = 0\nd = 2\n"));
+    assert_eq!(lines.len(), 8, "didn't quit at :quit");
+}
+
+#[test]
+fn test_source_files() {
+    let dir = std::env::temp_dir().join(format!("tinyc-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let first = dir.join("first.tc");
+    let second = dir.join("second.tc");
+    std::fs::write(
+        &first,
+        "{\n    i = 1;\n    while (i < 100)\n        i = i + i;\n}\n",
+    )
+    .unwrap();
+    std::fs::write(&second, "j = 3;\n").unwrap();
+
+    let out = main_with(&[first.to_str().unwrap(), second.to_str().unwrap()], "");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "i = 128\nj = 3\n");
+
+    let missing = dir.join("missing.tc");
+    let out = main_with(&[missing.to_str().unwrap()], "");
+    assert!(!out.status.success());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.starts_with(&format!("cannot read {}: ", missing.display())),
+        "{err}"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_bad_input() {
+    // The programs before the line that isn't UTF-8 are run
+    let out = main_with_bytes(&["--no-warn-unused"], b"a = 1;\nb = 2; \xff\n");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "a = 1\n");
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.starts_with("cannot read standard input: "), "{err}");
+}
+
+#[test]
+fn test_json() {
+    let out = main_with(&["--json"], "a=42;\n{ b = 1; c = 0; }\n");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "{\"a\":42}\n{\"b\":1}\n"
+    );
+}
+
+#[test]
+fn test_diff() {
+    let out = main_with(
+        &["--diff", "--persist"],
+        "{ a = 1; b = 2; }\n{ a = 5; b = 2; total = a; }\n",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "a = 1 (was 0)\nb = 2 (was 0)\na = 5 (was 1)\ntotal = 5 (was 0)\n"
+    );
+}
+
+#[test]
+fn test_warn_unused() {
+    let out = main_with(&[], "{ t = 5; a = 3; }\n");
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "warning: `t' is assigned but never read\nwarning: `a' is assigned but never read\n"
+    );
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "a = 3\nt = 5\n");
+
+    let out = main_with(&["--no-warn-unused"], "{ t = 5; a = 3; }\n");
+    assert!(out.stderr.is_empty());
+
+    // Once for each program, however it's run
+    let out = main_with(&["--interpret"], "{ a = 1; b = a; a = 2; }\n");
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "warning: `a' is assigned but never read\nwarning: `b' is assigned but never read\n"
+    );
+}
+
+#[test]
+fn test_interpret() {
+    // The examples of the README, one program per line
+    let examples = "\
+a=b=c=2<3;
+{ i=1; while (i<100) i=i+i; }
+{ i=125; j=100; while (i-j) if (i<j) j=j-i; else i=i-j; }
+{ i=1; do i=i+10; while (i<50); }
+{ i=1; while ((i=i+10)<50) ; }
+{ i=7; if (i<5) x=1; if (i<10) y=2; }
+{ m=n=1;k=10; while (0 < k) { t = m; m = n; n = t + n; k = k - 1; }}
+{ count = 3; total = count * 14; }
+";
+    let compiled = main_with(&["--json-all"], examples);
+    let interpreted = main_with(&["--json-all", "--interpret"], examples);
+    assert!(interpreted.status.success());
+    assert_eq!(
+        String::from_utf8(interpreted.stdout).unwrap(),
+        String::from_utf8(compiled.stdout).unwrap()
+    );
+
+    let out = main_with(&["--interpret", "--no-warn-unused"], "count = 3;\n");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "count = 3\n");
+
+    let out = main_with(
+        &["--interpret", "--no-warn-unused"],
+        "{ a = 1; b = a / (a - 1); }\n",
+    );
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "input:1:14:division by zero\n"
+    );
+}
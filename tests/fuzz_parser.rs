@@ -0,0 +1,109 @@
+//! Feed the parser arbitrary input and check that it always returns,
+//! with a tree or a `ParseError`, rather than panic.  The trees it
+//! returns go on to `check`, and those that pass to `compile`, which
+//! mustn't panic either.
+
+#![warn(clippy::all, clippy::pedantic)]
+
+use proptest::prelude::*;
+use proptest::sample::{select, Index};
+use tinyc_in_rust::codegen::{check, compile};
+use tinyc_in_rust::parser::{parse, NodeArena};
+
+/// The pieces the programs are changed with: the tokens of Tiny-C,
+/// things that almost are, and some that aren't at all
+const PIECES: [&str; 48] = [
+    "if",
+    "else",
+    "while",
+    "do",
+    "for",
+    "switch",
+    "case",
+    "default",
+    "break",
+    "continue",
+    "fn",
+    "print",
+    "read",
+    "int",
+    "abs",
+    "min",
+    "max",
+    "rand",
+    "a",
+    "xyz",
+    "0",
+    "42",
+    "0x1f",
+    "077",
+    "99999999999999999999",
+    "{",
+    "}",
+    "(",
+    ")",
+    ";",
+    ":",
+    ",",
+    "?",
+    "=",
+    "+=",
+    "++",
+    "--",
+    "-",
+    "*",
+    "/",
+    "<<",
+    "&&",
+    "||",
+    "~",
+    "!",
+    "/*",
+    "//",
+    "\n",
+];
+
+/// Programs to make a small change to, so that many of the inputs
+/// still parse and get as far as `check` and `compile`
+const PROGRAMS: [&str; 4] = [
+    "{ i = 1; while (i < 100) i = i + i; print i; }",
+    "{ int a; for (a = 0; a < 3; ++a) { if (a == 1) continue; print a; } }",
+    "{ switch (x) { case 1: f(); default: break; } } fn f() { x += 1; }",
+    "{ do { read n; s = n > 0 ? s + n : -s; } while (n); print s; }",
+];
+
+/// One of the `PROGRAMS` with up to four of its bytes replaced by one
+/// of the `PIECES` or up to two arbitrary characters
+fn mutated_program() -> impl Strategy<Value = String> {
+    let piece = prop_oneof![select(&PIECES[..]).prop_map(str::to_string), ".{0,2}"];
+    (select(&PROGRAMS[..]), any::<Index>(), 0..=4usize, piece).prop_map(
+        |(program, at, len, piece)| {
+            let start = at.index(program.len());
+            let end = (start + len).min(program.len());
+            format!("{}{piece}{}", &program[..start], &program[end..])
+        },
+    )
+}
+
+/// Parse `src`, and check and compile the tree if it parses
+fn parse_check_compile(src: &str) {
+    if let Ok(ast) = parse(&NodeArena::new(), src) {
+        if check(ast).is_ok() {
+            let _ = compile(ast);
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(5000))]
+
+    #[test]
+    fn test_parse_never_panics(src in any::<String>()) {
+        parse_check_compile(&src);
+    }
+
+    #[test]
+    fn test_parse_mutated_never_panics(src in mutated_program()) {
+        parse_check_compile(&src);
+    }
+}